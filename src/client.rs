@@ -0,0 +1,271 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::errors::Error;
+use crate::service_key;
+use crate::{
+    check_authorization, fetch_artifact, get_all_packages, http, list_available_artifacts,
+};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Integration package from `IntegrationPackages`. New fields can be added in minor versions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct Package {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Version")]
+    pub version: Option<String>,
+    #[serde(rename = "Mode")]
+    pub mode: Option<String>,
+    #[serde(rename = "Vendor")]
+    pub vendor: Option<String>,
+    #[serde(rename = "Description")]
+    pub description: Option<String>,
+    #[serde(rename = "ShortText")]
+    pub short_text: Option<String>,
+    #[serde(rename = "Keywords")]
+    pub keywords: Option<String>,
+    #[serde(rename = "SupportedPlatform")]
+    pub supported_platform: Option<String>,
+    #[serde(rename = "Products")]
+    pub products: Option<String>,
+    #[serde(rename = "Industries")]
+    pub industries: Option<String>,
+    #[serde(rename = "LineOfBusiness")]
+    pub line_of_business: Option<String>,
+    #[serde(rename = "Countries")]
+    pub countries: Option<String>,
+    #[serde(rename = "CreatedBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "CreationDate")]
+    pub creation_date: Option<String>,
+    #[serde(rename = "ModifiedBy")]
+    pub modified_by: Option<String>,
+    #[serde(rename = "ModifiedDate", alias = "ModifiedAt")]
+    pub modified_date: Option<String>,
+}
+
+/// Designtime artifact of a package, e.g. from `IntegrationDesigntimeArtifacts`.
+/// New fields can be added in minor versions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
+pub struct Artifact {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Version")]
+    pub version: Option<String>,
+    #[serde(rename = "Description")]
+    pub description: Option<String>,
+    #[serde(rename = "ModifiedDate", alias = "ModifiedAt")]
+    pub modified_date: Option<String>,
+}
+
+// OData collection response
+#[derive(Deserialize, Debug)]
+pub(crate) struct ODataResults<T> {
+    pub results: Vec<T>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct ODataResponse<T> {
+    pub d: ODataResults<T>,
+}
+
+/// Authenticated connection to the API of a single tenant.
+pub struct CpiClient {
+    config: Config,
+    client: reqwest::Client,
+    authorization: Authorization,
+}
+
+impl CpiClient {
+    /// Connects with the default `http` settings and checks API access.
+    /// `secret` is the password, client secret or certificate passphrase of the tenant credential,
+    /// a `service_key_file` credential uses the secret of the service key.
+    pub async fn new(tenant: Tenant, secret: &str) -> Result<CpiClient, Error> {
+        CpiClient::with_http(tenant, Http::default(), secret).await
+    }
+
+    /// Like `new`, with retry, proxy and timeout settings.
+    pub async fn with_http(tenant: Tenant, http: Http, secret: &str) -> Result<CpiClient, Error> {
+        let tenant = service_key::resolve_tenant(&tenant)?;
+        let secret = match &tenant.credential {
+            CredentialInside::OauthClientCredentials(c) => c
+                .client_secret
+                .clone()
+                .unwrap_or_else(|| secret.to_string()),
+            _ => secret.to_string(),
+        };
+        let config = Config {
+            cpisync: CONFIG_VERSION.to_string(),
+            tenant: Some(tenant),
+            tenants: BTreeMap::new(),
+            packages: Packages::default(),
+            git: None,
+            http,
+            tenant_content: Vec::new(),
+            output: Output::default(),
+        };
+        let client = http::client_builder(&config)?.build()?;
+        let authorization = check_authorization(&config, &client, &secret).await?;
+        Ok(CpiClient {
+            config,
+            client,
+            authorization,
+        })
+    }
+
+    pub fn tenant(&self) -> Result<&Tenant, Error> {
+        self.config.tenant()
+    }
+
+    /// All integration packages of the tenant.
+    pub async fn list_packages(&self) -> Result<Vec<Package>, Error> {
+        get_all_packages(&self.config, &self.client, &self.authorization).await
+    }
+
+    /// Artifacts of the given type in the package, empty if the type is not available on the tenant.
+    /// `Error::Api` with status 404 if the package doesn't exist.
+    pub async fn list_artifacts(
+        &self,
+        package_id: &str,
+        artifact_type: ArtifactType,
+    ) -> Result<Vec<Artifact>, Error> {
+        let artifacts = list_available_artifacts(
+            package_id,
+            artifact_type,
+            &self.config,
+            &self.client,
+            &self.authorization,
+        )
+        .await?;
+        Ok(artifacts.unwrap_or_default())
+    }
+
+    /// Artifact ZIP of the active version.
+    pub async fn download_artifact_bytes(
+        &self,
+        artifact_id: &str,
+        artifact_type: ArtifactType,
+    ) -> Result<Vec<u8>, Error> {
+        let content = fetch_artifact(
+            artifact_id,
+            artifact_type,
+            &self.config,
+            &self.client,
+            &self.authorization,
+        )
+        .await?;
+        Ok(content.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn tenant(server: &MockServer) -> Tenant {
+        serde_json::from_value(test_util::mock_tenant(server, test_util::s_user())).unwrap()
+    }
+
+    async fn checked_tenant() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn rejected_check_is_an_api_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(403).set_body_string("Forbidden"))
+            .mount(&server)
+            .await;
+
+        match CpiClient::new(tenant(&server), "secret").await {
+            Err(Error::Api { url, status, body }) => {
+                assert_eq!(url, format!("{}/api/v1/", server.uri()));
+                assert_eq!(status, 403);
+                assert_eq!(body, "Forbidden");
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("check passed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_artifact_type_is_empty() {
+        let server = checked_tenant().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/IntegrationPackages('Pkg1')"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/api/v1/IntegrationPackages('Pkg1')/ScriptCollectionDesigntimeArtifacts",
+            ))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/IntegrationPackages"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html/>"))
+            .mount(&server)
+            .await;
+
+        let client = CpiClient::new(tenant(&server), "secret").await.unwrap();
+        assert!(client
+            .list_artifacts("Pkg1", ArtifactType::ScriptCollection)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            client.list_packages().await,
+            Err(Error::UnexpectedResponse { url, .. }) if url.ends_with("/api/v1/IntegrationPackages")
+        ));
+    }
+
+    #[tokio::test]
+    async fn missing_package_is_an_api_error() {
+        let server = checked_tenant().await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/api/v1/IntegrationPackages('Missing')/IntegrationDesigntimeArtifacts",
+            ))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/IntegrationPackages('Missing')"))
+            .respond_with(ResponseTemplate::new(404).set_body_string("Not Found"))
+            .mount(&server)
+            .await;
+
+        let client = CpiClient::new(tenant(&server), "secret").await.unwrap();
+        match client
+            .list_artifacts("Missing", ArtifactType::IntegrationFlow)
+            .await
+        {
+            Err(Error::Api { url, status, .. }) => {
+                assert!(url.ends_with("/api/v1/IntegrationPackages('Missing')"));
+                assert_eq!(status, 404);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
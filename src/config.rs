@@ -1,4 +1,10 @@
+use crate::errors::Error;
+use crate::service_key;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+/// Config file version, the `cpisync` value of the schema.
+pub const CONFIG_VERSION: &str = "0.4.0";
 
 fn default_package_rule_operation() -> OperationEnum {
     OperationEnum::Include
@@ -11,6 +17,10 @@ fn default_prop_comment_removal() -> PropCommentRemoval {
     PropCommentRemoval::Disabled
 }
 
+fn default_certificate_format() -> CertificateFormat {
+    CertificateFormat::Pem
+}
+
 fn default_packages_local_dir() -> String {
     "".to_string()
 }
@@ -19,6 +29,70 @@ fn default_download_worker_count() -> usize {
     5
 }
 
+fn default_artifact_types() -> Vec<ArtifactType> {
+    ArtifactType::ALL.to_vec()
+}
+
+fn default_artifact_type_folders() -> ArtifactTypeFolders {
+    ArtifactTypeFolders::Enabled
+}
+
+fn default_incremental_sync() -> IncrementalSync {
+    IncrementalSync::Disabled
+}
+
+fn default_runtime_status() -> RuntimeStatus {
+    RuntimeStatus::Disabled
+}
+
+fn default_configuration_export() -> ConfigurationExport {
+    ConfigurationExport::Disabled
+}
+
+fn default_package_export() -> PackageExport {
+    PackageExport::Disabled
+}
+
+fn default_package_export_timestamp() -> PackageExportTimestamp {
+    PackageExportTimestamp::Disabled
+}
+
+fn default_xml_sort_attributes() -> XmlSortAttributes {
+    XmlSortAttributes::Enabled
+}
+
+fn default_output_format() -> OutputFormat {
+    OutputFormat::Directory
+}
+
+fn default_http_max_attempts() -> u32 {
+    3
+}
+
+fn default_http_backoff_initial_ms() -> u64 {
+    500
+}
+
+fn default_http_backoff_max_ms() -> u64 {
+    30000
+}
+
+fn default_http_user_agent() -> String {
+    format!("cpi-sync/{}", env!("CARGO_PKG_VERSION"))
+}
+
+fn default_git_commit() -> GitCommit {
+    GitCommit::Disabled
+}
+
+fn default_git_commit_per() -> GitCommitPer {
+    GitCommitPer::Run
+}
+
+fn default_git_message() -> String {
+    "Sync {tenant} at {timestamp}: {packages}".to_string()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum OperationEnum {
     #[serde(rename = "include")]
@@ -65,23 +139,354 @@ pub enum PropCommentRemoval {
     Enabled,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum XmlSortAttributes {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NormalizationStripLines {
+    pub pattern: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NormalizationXml {
+    #[serde(default = "default_xml_sort_attributes")]
+    pub sort_attributes: XmlSortAttributes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum NormalizationStep {
+    #[serde(rename = "strip_lines")]
+    StripLines(NormalizationStripLines),
+    // CRLF and CR to LF
+    #[serde(rename = "line_endings")]
+    LineEndings,
+    #[serde(rename = "xml")]
+    Xml(NormalizationXml),
+    #[serde(rename = "sort_properties")]
+    SortProperties,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NormalizationRule {
+    // path inside the artifact, without a `/` the file name in any folder
+    pub glob: String,
+    pub steps: Vec<NormalizationStep>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArtifactType {
+    #[serde(rename = "IntegrationDesigntimeArtifacts")]
+    IntegrationFlow,
+    #[serde(rename = "ValueMappingDesigntimeArtifacts")]
+    ValueMapping,
+    #[serde(rename = "MessageMappingDesigntimeArtifacts")]
+    MessageMapping,
+    #[serde(rename = "ScriptCollectionDesigntimeArtifacts")]
+    ScriptCollection,
+}
+
+impl ArtifactType {
+    // function libraries and REST/SOAP API artifacts are not supported, they are only
+    // part of the package export
+    pub const ALL: [ArtifactType; 4] = [
+        ArtifactType::IntegrationFlow,
+        ArtifactType::ValueMapping,
+        ArtifactType::MessageMapping,
+        ArtifactType::ScriptCollection,
+    ];
+
+    /// Entity set name in the OData API
+    pub fn api_name(&self) -> &'static str {
+        match self {
+            ArtifactType::IntegrationFlow => "IntegrationDesigntimeArtifacts",
+            ArtifactType::ValueMapping => "ValueMappingDesigntimeArtifacts",
+            ArtifactType::MessageMapping => "MessageMappingDesigntimeArtifacts",
+            ArtifactType::ScriptCollection => "ScriptCollectionDesigntimeArtifacts",
+        }
+    }
+
+    /// Local subfolder inside the package directory
+    pub fn folder_name(&self) -> &'static str {
+        match self {
+            ArtifactType::IntegrationFlow => "IntegrationFlows",
+            ArtifactType::ValueMapping => "ValueMappings",
+            ArtifactType::MessageMapping => "MessageMappings",
+            ArtifactType::ScriptCollection => "ScriptCollections",
+        }
+    }
+}
+
+/// Tenant content outside of packages, written into the `tenant` folder.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TenantContentType {
+    #[serde(rename = "keystore")]
+    Keystore,
+    #[serde(rename = "user_credentials")]
+    UserCredentials,
+    #[serde(rename = "oauth2_credentials")]
+    OAuth2Credentials,
+    #[serde(rename = "number_ranges")]
+    NumberRanges,
+    #[serde(rename = "jms_queues")]
+    JmsQueues,
+    #[serde(rename = "variables")]
+    Variables,
+}
+
+impl TenantContentType {
+    /// Entity set name in the OData API
+    pub fn api_name(&self) -> &'static str {
+        match self {
+            TenantContentType::Keystore => "KeystoreEntries",
+            TenantContentType::UserCredentials => "UserCredentials",
+            TenantContentType::OAuth2Credentials => "OAuth2ClientCredentials",
+            TenantContentType::NumberRanges => "NumberRanges",
+            TenantContentType::JmsQueues => "Queues",
+            TenantContentType::Variables => "Variables",
+        }
+    }
+
+    /// Local file name inside the `tenant` folder, without extension
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            TenantContentType::Keystore => "keystore",
+            TenantContentType::UserCredentials => "user-credentials",
+            TenantContentType::OAuth2Credentials => "oauth2-credentials",
+            TenantContentType::NumberRanges => "number-ranges",
+            TenantContentType::JmsQueues => "jms-queues",
+            TenantContentType::Variables => "variables",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ArtifactTypeFolders {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum IncrementalSync {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RuntimeStatus {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigurationExport {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "properties")]
+    Properties,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PackageExport {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PackageExportTimestamp {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Packages {
     #[serde(default = "default_extract_zip")]
     pub zip_extraction: ZipExtraction,
     #[serde(default = "default_prop_comment_removal")]
     pub prop_comment_removal: PropCommentRemoval,
+    // applied to extracted files after `prop_comment_removal`
+    #[serde(default)]
+    pub normalization: Vec<NormalizationRule>,
     #[serde(default = "default_download_worker_count")]
     pub download_worker_count: usize,
     #[serde(default = "default_packages_local_dir")]
     pub local_dir: String,
+    #[serde(default = "default_artifact_types")]
+    pub artifact_types: Vec<ArtifactType>,
+    #[serde(default = "default_artifact_type_folders")]
+    pub artifact_type_folders: ArtifactTypeFolders,
+    #[serde(default = "default_incremental_sync")]
+    pub incremental_sync: IncrementalSync,
+    #[serde(default = "default_runtime_status")]
+    pub runtime_status: RuntimeStatus,
+    #[serde(default = "default_configuration_export")]
+    pub configuration_export: ConfigurationExport,
+    // regex patterns, values of matching parameter keys are masked
+    #[serde(default)]
+    pub configuration_mask_patterns: Vec<String>,
+    #[serde(default = "default_package_export")]
+    pub package_export: PackageExport,
+    #[serde(default = "default_package_export_timestamp")]
+    pub package_export_timestamp: PackageExportTimestamp,
+    // number of timestamped exports kept per package, all if not set
+    pub package_export_retention: Option<usize>,
     pub filter_rules: Vec<PackageRuleEnum>,
 }
 
+// no package is selected without filter rules
+impl Default for Packages {
+    fn default() -> Self {
+        Packages {
+            zip_extraction: default_extract_zip(),
+            prop_comment_removal: default_prop_comment_removal(),
+            normalization: Vec::new(),
+            download_worker_count: default_download_worker_count(),
+            local_dir: default_packages_local_dir(),
+            artifact_types: default_artifact_types(),
+            artifact_type_folders: default_artifact_type_folders(),
+            incremental_sync: default_incremental_sync(),
+            runtime_status: default_runtime_status(),
+            configuration_export: default_configuration_export(),
+            configuration_mask_patterns: Vec::new(),
+            package_export: default_package_export(),
+            package_export_timestamp: default_package_export_timestamp(),
+            package_export_retention: None,
+            filter_rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GitCommit {
+    #[serde(rename = "disabled")]
+    Disabled,
+    #[serde(rename = "enabled")]
+    Enabled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GitCommitPer {
+    #[serde(rename = "run")]
+    Run,
+    #[serde(rename = "package")]
+    Package,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Git {
+    #[serde(default = "default_git_commit")]
+    pub commit: GitCommit,
+    #[serde(default = "default_git_commit_per")]
+    pub commit_per: GitCommitPer,
+    pub author_name: Option<String>,
+    pub author_email: Option<String>,
+    #[serde(default = "default_git_message")]
+    pub message: String,
+    pub push_remote: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[serde(rename = "directory")]
+    Directory,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl OutputFormat {
+    /// File extension of the snapshot archive, `None` for directory output.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Directory => None,
+            OutputFormat::TarGz => Some("tar.gz"),
+            OutputFormat::Zip => Some("zip"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Output {
+    #[serde(default = "default_output_format")]
+    pub format: OutputFormat,
+    // number of snapshot archives kept per tenant, default: all
+    pub retention: Option<usize>,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            format: default_output_format(),
+            retention: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Http {
+    #[serde(default = "default_http_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_http_backoff_initial_ms")]
+    pub backoff_initial_ms: u64,
+    #[serde(default = "default_http_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    // default: proxy from the HTTPS_PROXY/HTTP_PROXY environment variables
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    pub proxy_username_environment_variable: Option<String>,
+    pub proxy_password_environment_variable: Option<String>,
+    // PEM files, trusted in addition to the system certificates
+    #[serde(default)]
+    pub ca_files: Vec<String>,
+    pub connect_timeout_ms: Option<u64>,
+    // whole request including the response body, not a read timeout
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default = "default_http_user_agent")]
+    pub user_agent: String,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            max_attempts: default_http_max_attempts(),
+            backoff_initial_ms: default_http_backoff_initial_ms(),
+            backoff_max_ms: default_http_backoff_max_ms(),
+            proxy: None,
+            no_proxy: Vec::new(),
+            proxy_username_environment_variable: None,
+            proxy_password_environment_variable: None,
+            ca_files: Vec::new(),
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            user_agent: default_http_user_agent(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CredentialSUser {
     pub username: String,
     pub password_environment_variable: Option<String>,
+    pub password_file: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -89,26 +494,311 @@ pub struct CredentialOauthClientCredentials {
     pub client_id: String,
     pub token_endpoint_url: String,
     pub client_secret_environment_variable: Option<String>,
+    pub client_secret_file: Option<String>,
+    pub token_cache_file: Option<String>,
+    // secret from a service key, never read from the config
+    #[serde(skip)]
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CertificateFormat {
+    #[serde(rename = "pem")]
+    Pem,
+    #[serde(rename = "pkcs12")]
+    Pkcs12,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CredentialOauthClientCertificate {
+    pub client_id: String,
+    pub token_endpoint_url: String,
+    #[serde(default = "default_certificate_format")]
+    pub certificate_format: CertificateFormat,
+    pub certificate_file: String,
+    // PEM only, defaults to the key in `certificate_file`
+    pub key_file: Option<String>,
+    // PKCS#12 archive or encrypted PEM key
+    pub passphrase_environment_variable: Option<String>,
+    pub token_cache_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CredentialServiceKeyFile {
+    pub path: String,
+    pub token_cache_file: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum CredentialInside {
     #[serde(rename = "oauth_client_credentials")]
     OauthClientCredentials(CredentialOauthClientCredentials),
+    #[serde(rename = "oauth_client_certificate")]
+    OauthClientCertificate(CredentialOauthClientCertificate),
     #[serde(rename = "s_user")]
     SUser(CredentialSUser),
+    // replaced with `OauthClientCredentials` by `tenant_configs`
+    #[serde(rename = "service_key_file")]
+    ServiceKeyFile(CredentialServiceKeyFile),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tenant {
+    // derived from the service key if empty
+    #[serde(default)]
     pub management_host: String,
+    // default: `https://<management_host>/api/v1`, e.g. for a local mock server
+    pub api_base_url: Option<String>,
     pub credential: CredentialInside,
     // credential: CredentialInside,
+    // key in the `tenants` map, set by `tenant_configs`
+    #[serde(skip)]
+    pub name: Option<String>,
+}
+
+impl Tenant {
+    /// Base URL of the OData API, without a trailing `/`.
+    pub fn api_url(&self) -> String {
+        match &self.api_base_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("https://{host}/api/v1", host = self.management_host),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TenantEntry {
+    #[serde(flatten)]
+    pub tenant: Tenant,
+    pub packages: Option<Packages>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub cpisync: String,
-    pub tenant: Tenant,
+    pub tenant: Option<Tenant>,
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantEntry>,
     pub packages: Packages,
+    pub git: Option<Git>,
+    #[serde(default)]
+    pub http: Http,
+    #[serde(default)]
+    pub tenant_content: Vec<TenantContentType>,
+    #[serde(default)]
+    pub output: Output,
+}
+
+#[derive(Debug, Clone)]
+pub enum TenantSelection {
+    // the single `tenant` of the config
+    Default,
+    Named(Vec<String>),
+    All,
+}
+
+/// Tenant names are folder names below `local_dir`, `.` and `..` would leave the tenant's own folder.
+fn check_tenant_name(name: &str) -> Result<(), Error> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if name.is_empty() || name == "." || name == ".." || !valid_chars {
+        return Err(Error::Config(format!(
+            "Invalid tenant name: {:?}, use letters, digits, `_`, `.` and `-`, not `.` or `..`",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn tenant(&self) -> Result<&Tenant, Error> {
+        self.tenant
+            .as_ref()
+            .ok_or_else(|| Error::Config("No tenant selected".to_string()))
+    }
+
+    /// Resolves the selected tenants to single-tenant configs, service key credentials are loaded.
+    /// Tenants from the `tenants` map use their own `packages` if given,
+    /// and write into a subfolder of `local_dir` named after the tenant.
+    pub fn tenant_configs(
+        &self,
+        selection: &TenantSelection,
+    ) -> Result<Vec<(Option<String>, Config)>, Error> {
+        let names: Vec<String> = match selection {
+            TenantSelection::Default => {
+                if let Some(tenant) = &self.tenant {
+                    let mut tenant_config = self.clone();
+                    tenant_config.tenant = Some(service_key::resolve_tenant(tenant)?);
+                    return Ok(vec![(None, tenant_config)]);
+                }
+                if self.tenants.is_empty() {
+                    return Err(Error::Config(
+                        "No tenant configured, set `tenant` or `tenants`".to_string(),
+                    ));
+                }
+                return Err(Error::Config(format!(
+                    "Config has multiple tenants, use --tenant <name> or --all-tenants. Tenants: {:?}",
+                    self.tenants.keys().collect::<Vec<_>>()
+                )));
+            }
+            //a repeated name would sync into the same folder twice at once
+            TenantSelection::Named(names) => {
+                let mut unique_names: Vec<String> = Vec::new();
+                for name in names {
+                    if !unique_names.contains(name) {
+                        unique_names.push(name.clone());
+                    }
+                }
+                unique_names
+            }
+            TenantSelection::All => self.tenants.keys().cloned().collect(),
+        };
+
+        let mut configs = Vec::new();
+        for name in names {
+            check_tenant_name(&name)?;
+            let entry = self.tenants.get(&name).ok_or_else(|| {
+                Error::Config(format!(
+                    "Tenant not found in config: {}. Tenants: {:?}",
+                    &name,
+                    self.tenants.keys().collect::<Vec<_>>()
+                ))
+            })?;
+
+            let mut packages = entry
+                .packages
+                .clone()
+                .unwrap_or_else(|| self.packages.clone());
+            packages.local_dir = Path::new(&packages.local_dir)
+                .join(&name)
+                .to_string_lossy()
+                .to_string();
+
+            let mut tenant = service_key::resolve_tenant(&entry.tenant)?;
+            tenant.name = Some(name.clone());
+
+            let tenant_config = Config {
+                cpisync: self.cpisync.clone(),
+                tenant: Some(tenant),
+                tenants: BTreeMap::new(),
+                packages,
+                git: self.git.clone(),
+                http: self.http.clone(),
+                tenant_content: self.tenant_content.clone(),
+                output: self.output.clone(),
+            };
+            configs.push((Some(name), tenant_config));
+        }
+        Ok(configs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+
+    fn tenants_config() -> Config {
+        test_util::config(json!({
+            "tenants": {
+                "DEV": test_util::tenant(),
+                "PRD": {
+                    "management_host": "prd.example.com",
+                    "credential": test_util::s_user(),
+                    "packages": { "filter_rules": [], "local_dir": "prd-data" }
+                }
+            },
+            "packages": { "filter_rules": [], "local_dir": "data" }
+        }))
+    }
+
+    fn names(configs: &[(Option<String>, Config)]) -> Vec<Option<&str>> {
+        configs.iter().map(|(name, _)| name.as_deref()).collect()
+    }
+
+    #[test]
+    fn default_selection_uses_the_single_tenant() {
+        let config = test_util::config(json!({}));
+        let configs = config.tenant_configs(&TenantSelection::Default).unwrap();
+        assert_eq!(names(&configs), vec![None]);
+        assert_eq!(configs[0].1.tenant().unwrap().name, None);
+
+        assert!(matches!(
+            tenants_config().tenant_configs(&TenantSelection::Default),
+            Err(Error::Config(message)) if message.contains("DEV")
+        ));
+
+        let mut config = test_util::config(json!({}));
+        config.tenant = None;
+        assert!(matches!(
+            config.tenant_configs(&TenantSelection::Default),
+            Err(Error::Config(message)) if message.starts_with("No tenant configured")
+        ));
+    }
+
+    #[test]
+    fn named_and_all_tenants_write_into_subfolders() {
+        let config = tenants_config();
+        let configs = config
+            .tenant_configs(&TenantSelection::Named(vec!["PRD".to_string()]))
+            .unwrap();
+        assert_eq!(names(&configs), vec![Some("PRD")]);
+        let (_, prd) = &configs[0];
+        assert_eq!(prd.tenant().unwrap().name.as_deref(), Some("PRD"));
+        assert_eq!(prd.tenant().unwrap().management_host, "prd.example.com");
+        assert!(prd.tenants.is_empty());
+        // the tenant's own packages replace the shared ones
+        assert_eq!(
+            Path::new(&prd.packages.local_dir),
+            Path::new("prd-data").join("PRD")
+        );
+
+        let configs = config.tenant_configs(&TenantSelection::All).unwrap();
+        assert_eq!(names(&configs), vec![Some("DEV"), Some("PRD")]);
+        assert_eq!(
+            Path::new(&configs[0].1.packages.local_dir),
+            Path::new("data").join("DEV")
+        );
+    }
+
+    #[test]
+    fn repeated_tenant_names_are_selected_once() {
+        let selection = TenantSelection::Named(
+            ["PRD", "DEV", "PRD", "DEV"]
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        );
+        let configs = tenants_config().tenant_configs(&selection).unwrap();
+        assert_eq!(names(&configs), vec![Some("PRD"), Some("DEV")]);
+    }
+
+    #[test]
+    fn dot_tenant_names_are_rejected() {
+        for name in [".", "..", "a/b", ""] {
+            let config = test_util::config(json!({
+                "tenants": { name: test_util::tenant() }
+            }));
+            for selection in [
+                TenantSelection::All,
+                TenantSelection::Named(vec![name.to_string()]),
+            ] {
+                assert!(matches!(
+                    config.tenant_configs(&selection),
+                    Err(Error::Config(message)) if message.contains("Invalid tenant name")
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_tenant_is_a_config_error() {
+        let selection = TenantSelection::Named(vec!["DEV".to_string(), "QAS".to_string()]);
+        assert!(matches!(
+            tenants_config().tenant_configs(&selection),
+            Err(Error::Config(message)) if message.contains("QAS")
+        ));
+    }
 }
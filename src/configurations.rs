@@ -0,0 +1,405 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::errors::Error;
+use crate::state::content_hash;
+use crate::storage::Storage;
+use crate::{artifact_type_path, http, unexpected_response};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+const MASKED_VALUE: &str = "********";
+
+#[derive(Deserialize, Debug)]
+struct ConfigurationResult {
+    #[serde(rename = "ParameterKey")]
+    parameter_key: String,
+    #[serde(rename = "ParameterValue")]
+    parameter_value: Option<String>,
+    #[serde(rename = "DataType")]
+    data_type: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConfigurationD {
+    results: Vec<ConfigurationResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConfigurationRoot {
+    d: ConfigurationD,
+}
+
+#[derive(Serialize, Debug)]
+struct Parameter {
+    key: String,
+    value: Option<String>,
+    data_type: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ArtifactConfigurations {
+    artifact_id: String,
+    // sorted by key
+    parameters: Vec<Parameter>,
+}
+
+/// File next to the artifact directory or zip: `<artifact>.configurations.<json|properties>`.
+pub fn configurations_path(
+    package_id: &str,
+    artifact_id: &str,
+    export: &ConfigurationExport,
+    config: &Config,
+) -> Option<String> {
+    let extension = match export {
+        ConfigurationExport::Disabled => return None,
+        ConfigurationExport::Json => "json",
+        ConfigurationExport::Properties => "properties",
+    };
+    Some(format!(
+        "{}/{}.configurations.{}",
+        artifact_type_path(package_id, ArtifactType::IntegrationFlow, config),
+        artifact_id,
+        extension
+    ))
+}
+
+/// Configuration file paths of an iFlow in any format.
+pub fn configurations_paths(package_id: &str, artifact_id: &str, config: &Config) -> Vec<String> {
+    [ConfigurationExport::Json, ConfigurationExport::Properties]
+        .iter()
+        .filter_map(|export| configurations_path(package_id, artifact_id, export, config))
+        .collect()
+}
+
+/// Removes the configuration files of a deleted artifact, in any format.
+pub fn remove_configurations(
+    package_id: &str,
+    artifact_id: &str,
+    config: &Config,
+    storage: &dyn Storage,
+) -> Result<(), Error> {
+    for path in configurations_paths(package_id, artifact_id, config) {
+        storage.remove_tree(&path)?;
+    }
+    Ok(())
+}
+
+/// Escapes a `.properties` key or value, keys also escape the separators.
+fn escape_property(text: &str, is_key: bool) -> String {
+    let mut escaped = String::new();
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ' ' if is_key || i == 0 => escaped.push_str("\\ "),
+            '=' | ':' if is_key => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | '!' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn to_properties(configurations: &ArtifactConfigurations) -> String {
+    let mut content = String::new();
+    for parameter in configurations.parameters.iter() {
+        content.push_str(&escape_property(&parameter.key, true));
+        content.push('=');
+        content.push_str(&escape_property(
+            parameter.value.as_deref().unwrap_or_default(),
+            false,
+        ));
+        content.push('\n');
+    }
+    content
+}
+
+async fn get_configurations(
+    artifact_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<ConfigurationResult>, Error> {
+    let api_configurations_url = format!(
+        "{api}/IntegrationDesigntimeArtifacts(Id='{artifact_id}',Version='Active')/Configurations",
+        api = config.tenant()?.api_url(),
+        artifact_id = artifact_id
+    );
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(&api_configurations_url)
+            .header("Accept", "application/json"),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    let body_text = resp.text().await?;
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_configurations_url,
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
+    }
+    let resp_obj: ConfigurationRoot = serde_json::from_str(&body_text)
+        .map_err(|e| unexpected_response(&api_configurations_url, e))?;
+    Ok(resp_obj.d.results)
+}
+
+/// Writes the externalized parameters of an iFlow, sorted by key, returns the hash of the file.
+/// Values of keys matching `configuration_mask_patterns` are masked.
+/// The file is not written again if the tenant and the file on disk both still have the
+/// `previous_hash` of the last sync, a locally changed file is restored.
+pub async fn write_configurations(
+    package_id: &str,
+    artifact_id: &str,
+    config: &Config,
+    storage: &dyn Storage,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    previous_hash: Option<&str>,
+) -> Result<Option<String>, Error> {
+    let export = &config.packages.configuration_export;
+    let path = match configurations_path(package_id, artifact_id, export, config) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mask_patterns = config
+        .packages
+        .configuration_mask_patterns
+        .iter()
+        .map(|p| Regex::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut parameters: Vec<Parameter> =
+        get_configurations(artifact_id, config, client, authorization)
+            .await?
+            .into_iter()
+            .map(|c| {
+                let masked = mask_patterns.iter().any(|p| p.is_match(&c.parameter_key));
+                Parameter {
+                    value: match masked {
+                        true => Some(MASKED_VALUE.to_string()),
+                        false => c.parameter_value,
+                    },
+                    key: c.parameter_key,
+                    data_type: c.data_type,
+                }
+            })
+            .collect();
+    parameters.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let configurations = ArtifactConfigurations {
+        artifact_id: artifact_id.to_string(),
+        parameters,
+    };
+    let content = match export {
+        ConfigurationExport::Properties => to_properties(&configurations),
+        _ => serde_json::to_string_pretty(&configurations)? + "\n",
+    };
+    let hash = content_hash(content.as_bytes());
+    let local_hash = storage.get_file(&path)?.map(|c| content_hash(&c));
+    if previous_hash == Some(hash.as_str()) && local_hash.as_deref() == previous_hash {
+        return Ok(Some(hash));
+    }
+    //drops the file of a previously configured format
+    remove_configurations(package_id, artifact_id, config, storage)?;
+    storage.put_file(&path, content.as_bytes())?;
+    Ok(Some(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test_util;
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn tenant() -> MockServer {
+        let server = MockServer::start().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationDesigntimeArtifacts(Id='Flow1',Version='Active')/Configurations",
+            json!([
+                { "ParameterKey": "url", "ParameterValue": "https://example.com/a b", "DataType": "xsd:string" },
+                { "ParameterKey": "api password", "ParameterValue": "secret", "DataType": "xsd:string" },
+                { "ParameterKey": "#timeout", "ParameterValue": " 30", "DataType": "xsd:integer" }
+            ]),
+        )
+        .await;
+        server
+    }
+
+    async fn try_write(
+        server: &MockServer,
+        export: &str,
+        storage: &MemoryStorage,
+        previous_hash: Option<&str>,
+    ) -> Result<Option<String>, Error> {
+        let config = test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "packages": {
+                "filter_rules": [],
+                "configuration_export": export,
+                "configuration_mask_patterns": ["(?i)password"]
+            },
+            "http": { "max_attempts": 1 }
+        }));
+        let client = http::client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+        write_configurations(
+            "Pkg1",
+            "Flow1",
+            &config,
+            storage,
+            &client,
+            &authorization,
+            previous_hash,
+        )
+        .await
+    }
+
+    async fn write(
+        server: &MockServer,
+        export: &str,
+        storage: &MemoryStorage,
+        previous_hash: Option<&str>,
+    ) -> Option<String> {
+        try_write(server, export, storage, previous_hash)
+            .await
+            .unwrap()
+    }
+
+    fn file(storage: &MemoryStorage, path: &str) -> String {
+        String::from_utf8(storage.get_file(path).unwrap().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_is_sorted_and_masked() {
+        let server = tenant().await;
+        let storage = MemoryStorage::new();
+        write(&server, "json", &storage, None).await.unwrap();
+
+        let content: serde_json::Value = serde_json::from_str(&file(
+            &storage,
+            "Pkg1/IntegrationFlows/Flow1.configurations.json",
+        ))
+        .unwrap();
+        assert_eq!(
+            content,
+            json!({
+                "artifact_id": "Flow1",
+                "parameters": [
+                    { "key": "#timeout", "value": " 30", "data_type": "xsd:integer" },
+                    { "key": "api password", "value": "********", "data_type": "xsd:string" },
+                    { "key": "url", "value": "https://example.com/a b", "data_type": "xsd:string" }
+                ]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn properties_are_escaped_and_replace_the_json_file() {
+        let server = tenant().await;
+        let storage = MemoryStorage::new();
+        write(&server, "json", &storage, None).await.unwrap();
+        write(&server, "properties", &storage, None).await.unwrap();
+
+        assert_eq!(
+            storage.list("Pkg1").unwrap(),
+            vec!["Pkg1/IntegrationFlows/Flow1.configurations.properties"]
+        );
+        assert_eq!(
+            file(
+                &storage,
+                "Pkg1/IntegrationFlows/Flow1.configurations.properties"
+            ),
+            "\\#timeout=\\ 30\napi\\ password=********\nurl=https://example.com/a b\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_configurations_are_not_written_again() {
+        let server = tenant().await;
+        let storage = MemoryStorage::new();
+        let hash = write(&server, "json", &storage, None).await.unwrap();
+        let path = "Pkg1/IntegrationFlows/Flow1.configurations.json";
+        let tenant_content = file(&storage, path);
+
+        //a skipped write keeps the file of the other format, a write removes it
+        storage
+            .put_file("Pkg1/IntegrationFlows/Flow1.configurations.properties", b"")
+            .unwrap();
+        assert_eq!(
+            write(&server, "json", &storage, Some(&hash)).await,
+            Some(hash.clone())
+        );
+        assert!(storage
+            .get_file("Pkg1/IntegrationFlows/Flow1.configurations.properties")
+            .unwrap()
+            .is_some());
+
+        storage.put_file(path, b"local").unwrap();
+        assert_eq!(
+            write(&server, "json", &storage, Some(&hash)).await,
+            Some(hash.clone())
+        );
+        assert_eq!(file(&storage, path), tenant_content);
+
+        storage.remove_tree(path).unwrap();
+        assert_eq!(
+            write(&server, "json", &storage, Some(&hash)).await,
+            Some(hash)
+        );
+        assert_eq!(file(&storage, path), tenant_content);
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_api_errors_html_is_unexpected() {
+        let server = MockServer::start().await;
+        let configurations_path =
+            "/api/v1/IntegrationDesigntimeArtifacts(Id='Flow1',Version='Active')/Configurations";
+        Mock::given(method("GET"))
+            .and(path(configurations_path))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(configurations_path))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html>Login</html>"))
+            .mount(&server)
+            .await;
+
+        let storage = MemoryStorage::new();
+        assert!(matches!(
+            try_write(&server, "json", &storage, None).await,
+            Err(Error::Api { status: 500, .. })
+        ));
+        assert!(matches!(
+            try_write(&server, "json", &storage, None).await,
+            Err(Error::UnexpectedResponse { url, .. }) if url.ends_with("/Configurations")
+        ));
+        assert!(storage.list("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn property_escaping() {
+        assert_eq!(escape_property("a=b:c d", true), "a\\=b\\:c\\ d");
+        assert_eq!(escape_property("!x", false), "\\!x");
+        assert_eq!(escape_property("a b\tc\\", false), "a b\\tc\\\\");
+    }
+}
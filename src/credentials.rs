@@ -0,0 +1,391 @@
+use crate::auth;
+use crate::config::*;
+use crate::errors::Error;
+use crate::service_key;
+use crate::vault::{self, Vault};
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub const VAULT_PASSPHRASE_ENVIRONMENT_VARIABLE: &str = "CPISYNC_VAULT_PASSPHRASE";
+
+/// Secret sources given on the command line, shared by all tenants of a run.
+pub struct CredentialInput {
+    pub no_input: bool,
+    // secret read from stdin, used for every tenant without another source
+    pub secret_stdin: Option<String>,
+    pub vault_file: PathBuf,
+    // opened on first use, so the passphrase is asked once per run
+    vault: Mutex<Option<Vault>>,
+}
+
+impl CredentialInput {
+    /// Without `vault_file` the vault in the user config directory is used.
+    pub fn new(
+        no_input: bool,
+        secret_stdin: Option<String>,
+        vault_file: Option<PathBuf>,
+    ) -> CredentialInput {
+        CredentialInput {
+            no_input,
+            secret_stdin,
+            vault_file: vault_file.unwrap_or_else(default_vault_file),
+            vault: Mutex::new(None),
+        }
+    }
+
+    /// Runs `f` with the opened vault, creating it if it doesn't exist yet.
+    fn with_vault<T>(&self, f: impl FnOnce(&mut Vault) -> Result<T, Error>) -> Result<T, Error> {
+        let mut vault = self
+            .vault
+            .lock()
+            .map_err(|_| Error::Vault("Vault lock poisoned".to_string()))?;
+        if vault.is_none() {
+            let passphrase = vault_passphrase(&self.vault_file, self.no_input)?;
+            *vault = Some(Vault::open(&self.vault_file, &passphrase)?);
+        }
+        match vault.as_mut() {
+            Some(v) => f(v),
+            None => Err(Error::Vault("Vault not opened".to_string())),
+        }
+    }
+}
+
+fn default_vault_file() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("cpisync")
+        .join("credentials.vault.json")
+}
+
+/// Master passphrase from the environment variable or a prompt, a new vault asks twice.
+fn vault_passphrase(vault_file: &Path, no_input: bool) -> Result<String, Error> {
+    if let Ok(passphrase) = env::var(VAULT_PASSPHRASE_ENVIRONMENT_VARIABLE) {
+        return Ok(passphrase);
+    }
+    if no_input {
+        return Err(Error::Vault(format!(
+            "Vault passphrase required, set environment variable: {}",
+            VAULT_PASSPHRASE_ENVIRONMENT_VARIABLE
+        )));
+    }
+
+    println!("Vault: {}", vault_file.display());
+    let passphrase = rpassword::prompt_password_stdout("Vault passphrase: ")?;
+    if !vault_file.is_file() {
+        let confirmation = rpassword::prompt_password_stdout("Repeat vault passphrase: ")?;
+        if confirmation != passphrase {
+            return Err(Error::Vault("Passphrases do not match".to_string()));
+        }
+    }
+    if passphrase.is_empty() {
+        return Err(Error::Vault("Empty vault passphrase".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// S-user name or OAuth client id of the tenant credential.
+fn credential_user(tenant: &Tenant) -> Result<&str, Error> {
+    match &tenant.credential {
+        CredentialInside::OauthClientCredentials(c) => Ok(&c.client_id),
+        CredentialInside::OauthClientCertificate(c) => Ok(&c.client_id),
+        CredentialInside::SUser(c) => Ok(&c.username),
+        CredentialInside::ServiceKeyFile(c) => Err(service_key::not_loaded(c)),
+    }
+}
+
+/// Vault entry name of the tenant credential: `<management_host>/<user or client id>`.
+fn vault_entry_name(config: &Config) -> Result<String, Error> {
+    let tenant = config.tenant()?;
+    Ok(format!(
+        "{}/{}",
+        tenant.management_host,
+        credential_user(tenant)?
+    ))
+}
+
+/// Reads a secret file, a trailing line break is not part of the secret.
+fn read_secret_file(secret_file: &str) -> Result<String, Error> {
+    let secret = fs::read_to_string(secret_file)
+        .map_err(|e| Error::Config(format!("Can not read secret file: {}: {}", secret_file, e)))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Resolves the tenant secret, in order: environment variable, secret file, vault entry,
+/// `--secret-stdin` and, if allowed, an interactive prompt.
+/// Without any secret fails with `Error::Config` naming the tenant and the sources tried.
+pub fn get_password(config: &Config, input: &CredentialInput) -> Result<String, Error> {
+    let mut password: Option<String> = None;
+    let mut tried_sources: Vec<String> = Vec::new();
+
+    //get secret from environment variable or file
+    match &config.tenant()?.credential {
+        CredentialInside::SUser(c) => {
+            if let Some(varkey) = &c.password_environment_variable {
+                tried_sources.push(format!("environment variable {}", varkey));
+                match env::var(varkey) {
+                    Ok(val) => {
+                        password = Some(val);
+                    }
+                    Err(e) => {
+                        println!(
+                            "Can not find S-user Pass in environment variable: {}: {}",
+                            &varkey, e
+                        );
+                    }
+                };
+            };
+            if let (None, Some(password_file)) = (&password, &c.password_file) {
+                password = Some(read_secret_file(password_file)?);
+            }
+        }
+        CredentialInside::OauthClientCredentials(c) => {
+            //secret from the service key
+            password = c.client_secret.clone();
+            if let (None, Some(varkey)) = (&password, &c.client_secret_environment_variable) {
+                tried_sources.push(format!("environment variable {}", varkey));
+                match env::var(varkey) {
+                    Ok(val) => {
+                        password = Some(val);
+                    }
+                    Err(e) => {
+                        println!(
+                            "Can not find Client Secret environment variable: {}: {}",
+                            &varkey, e
+                        );
+                    }
+                };
+            };
+            if let (None, Some(client_secret_file)) = (&password, &c.client_secret_file) {
+                password = Some(read_secret_file(client_secret_file)?);
+            }
+        }
+        CredentialInside::OauthClientCertificate(c) => {
+            //an unencrypted PEM key has no secret
+            if let CertificateFormat::Pem = c.certificate_format {
+                if !auth::pem_key_encrypted(c)? {
+                    return Ok(String::new());
+                }
+            }
+            if let Some(varkey) = &c.passphrase_environment_variable {
+                tried_sources.push(format!("environment variable {}", varkey));
+                match env::var(varkey) {
+                    Ok(val) => {
+                        password = Some(val);
+                    }
+                    Err(e) => {
+                        println!(
+                            "Can not find certificate passphrase environment variable: {}: {}",
+                            &varkey, e
+                        );
+                    }
+                };
+            };
+        }
+        CredentialInside::ServiceKeyFile(c) => return Err(service_key::not_loaded(c)),
+    }
+
+    //vault is only opened if it exists, a missing entry falls through
+    let entry_name = vault_entry_name(config)?;
+    tried_sources.push(format!(
+        "vault entry {} in {}",
+        entry_name,
+        input.vault_file.display()
+    ));
+    if password.is_none() && vault::entry_names(&input.vault_file)?.contains(&entry_name) {
+        password = input.with_vault(|v| v.get(&entry_name))?;
+    }
+
+    tried_sources.push("--secret-stdin".to_string());
+    if password.is_none() {
+        password = input.secret_stdin.clone();
+    }
+
+    //try to get password from command line
+    if !input.no_input && password.is_none() {
+        let username = credential_user(config.tenant()?)?;
+        let message = format!(
+            "Would you like to enter a password for user: {user} to connect host: {host}?",
+            user = username,
+            host = config.tenant()?.management_host
+        );
+
+        println!("{}", message);
+
+        let pass = rpassword::prompt_password_stdout("Password: ")?;
+        password = Some(pass);
+    }
+
+    match password {
+        Some(p) => Ok(p),
+        None => Err(Error::Config(format!(
+            "No secret for tenant {}, tried: {}, prompt disabled by --no-input",
+            config.tenant()?.management_host,
+            tried_sources.join(", ")
+        ))),
+    }
+}
+
+/// Stores the secret of each selected tenant in the vault,
+/// from `--secret-stdin` or an interactive prompt.
+pub fn credentials_add(
+    config: &Config,
+    selection: &TenantSelection,
+    input: &CredentialInput,
+) -> Result<(), Error> {
+    let tenant_configs = config.tenant_configs(selection)?;
+    input.with_vault(|vault| {
+        for (_, tenant_config) in tenant_configs.iter() {
+            let entry_name = vault_entry_name(tenant_config)?;
+            let secret = match &input.secret_stdin {
+                Some(secret) => secret.clone(),
+                None if input.no_input => {
+                    return Err(Error::Vault(
+                        "No secret given, use --secret-stdin".to_string(),
+                    ))
+                }
+                None => {
+                    println!("Secret for: {}", entry_name);
+                    rpassword::prompt_password_stdout("Password: ")?
+                }
+            };
+            vault.insert(&entry_name, &secret)?;
+            println!("Vault entry added: {}", entry_name);
+        }
+        vault.save(&input.vault_file)
+    })
+}
+
+/// Removes the vault entries of the selected tenants.
+pub fn credentials_remove(
+    config: &Config,
+    selection: &TenantSelection,
+    input: &CredentialInput,
+) -> Result<(), Error> {
+    let tenant_configs = config.tenant_configs(selection)?;
+    if !input.vault_file.is_file() {
+        println!("Vault not found: {}", input.vault_file.display());
+        return Ok(());
+    }
+    input.with_vault(|vault| {
+        for (_, tenant_config) in tenant_configs.iter() {
+            let entry_name = vault_entry_name(tenant_config)?;
+            if vault.remove(&entry_name) {
+                println!("Vault entry removed: {}", entry_name);
+            } else {
+                println!("Vault entry not found: {}", entry_name);
+            }
+        }
+        vault.save(&input.vault_file)
+    })
+}
+
+/// Prints the vault entry names, secrets stay encrypted.
+pub fn credentials_list(input: &CredentialInput) -> Result<(), Error> {
+    println!("Vault: {}", input.vault_file.display());
+    for entry_name in vault::entry_names(&input.vault_file)? {
+        println!("{}", entry_name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+
+    const PASSPHRASE: &str = "passphrase";
+
+    /// S-user tenant with the secret sources, environment variables are unique per test.
+    fn s_user_config(password_environment_variable: &str, password_file: Option<&Path>) -> Config {
+        test_util::config(json!({
+            "tenant": {
+                "management_host": "tenant.example.com",
+                "credential": { "s_user": {
+                    "username": "S1",
+                    "password_environment_variable": password_environment_variable,
+                    "password_file": password_file.map(|f| f.to_string_lossy())
+                }}
+            }
+        }))
+    }
+
+    /// Input with an opened vault, optionally with the secret of the test tenant.
+    fn input(
+        vault_file: &Path,
+        vault_secret: Option<&str>,
+        secret_stdin: Option<&str>,
+    ) -> CredentialInput {
+        let mut vault = Vault::open(vault_file, PASSPHRASE).unwrap();
+        if let Some(secret) = vault_secret {
+            vault.insert("tenant.example.com/S1", secret).unwrap();
+            vault.save(vault_file).unwrap();
+        }
+        let input = CredentialInput::new(
+            true,
+            secret_stdin.map(|s| s.to_string()),
+            Some(vault_file.to_path_buf()),
+        );
+        *input.vault.lock().unwrap() = Some(vault);
+        input
+    }
+
+    #[test]
+    fn environment_variable_comes_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("secret.txt");
+        fs::write(&secret_file, "file").unwrap();
+        env::set_var("CPISYNC_TEST_SECRET_FIRST", "env");
+
+        let config = s_user_config("CPISYNC_TEST_SECRET_FIRST", Some(&secret_file));
+        let input = input(&dir.path().join("vault.json"), Some("vault"), Some("stdin"));
+        assert_eq!(get_password(&config, &input).unwrap(), "env");
+    }
+
+    #[test]
+    fn missing_environment_variable_falls_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("secret.txt");
+        fs::write(&secret_file, "file\n").unwrap();
+        let vault_file = dir.path().join("vault.json");
+        let input = input(&vault_file, Some("vault"), Some("stdin"));
+
+        //to the secret file, without its line break
+        let config = s_user_config("CPISYNC_TEST_SECRET_MISSING", Some(&secret_file));
+        assert_eq!(get_password(&config, &input).unwrap(), "file");
+
+        //to the vault
+        let config = s_user_config("CPISYNC_TEST_SECRET_MISSING", None);
+        assert_eq!(get_password(&config, &input).unwrap(), "vault");
+
+        //to stdin, without a vault entry
+        let other_vault_file = dir.path().join("other.vault.json");
+        let input = self::input(&other_vault_file, None, Some("stdin"));
+        assert_eq!(get_password(&config, &input).unwrap(), "stdin");
+    }
+
+    #[test]
+    fn no_input_without_a_secret_is_a_config_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_file = dir.path().join("vault.json");
+        let config = s_user_config("CPISYNC_TEST_SECRET_NONE", None);
+
+        match get_password(&config, &input(&vault_file, None, None)) {
+            Err(Error::Config(message)) => {
+                assert!(message.contains("tenant.example.com"), "{}", message);
+                assert!(message.contains("CPISYNC_TEST_SECRET_NONE"), "{}", message);
+                assert!(
+                    message.contains("vault entry tenant.example.com/S1"),
+                    "{}",
+                    message
+                );
+                assert!(message.contains("--secret-stdin"), "{}", message);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use crate::auth::Authorization;
+use crate::client::{Artifact, Package};
+use crate::config::*;
+use crate::credentials::{get_password, CredentialInput};
+use crate::errors::Error;
+use crate::http;
+use crate::{
+    extract_entries, fetch_artifact, get_all_packages, get_authorization, list_package_artifacts,
+    run_pooled, select_packages, try_run_pooled,
+};
+
+use futures::Future;
+use similar::TextDiff;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::Cursor,
+    ops::Deref,
+};
+
+// one tenant of the comparison
+struct DiffSide {
+    name: String,
+    config: Config,
+    client: reqwest::Client,
+    authorization: Authorization,
+    // all packages on the tenant
+    package_list: Vec<Package>,
+    package_set: BTreeSet<String>,
+}
+
+async fn connect_side(name: String, config: Config, password: String) -> Result<DiffSide, Error> {
+    println!("Connecting Tenant: {}", &name);
+
+    let client = http::client_builder(&config)?.build()?;
+    let authorization = get_authorization(&config, &client, &password).await?;
+
+    let package_list = get_all_packages(&config, &client, &authorization).await?;
+    let package_set = package_list.iter().map(|p| p.id.clone()).collect();
+
+    Ok(DiffSide {
+        name,
+        config,
+        client,
+        authorization,
+        package_list,
+        package_set,
+    })
+}
+
+/// Applies the filter_rules of each side to the packages of both tenants,
+/// so a `single` rule only fails if the package is missing on both.
+fn select_diff_packages(left: &DiffSide, right: &DiffSide) -> Result<BTreeSet<String>, Error> {
+    let mut known_ids = BTreeSet::new();
+    let package_list: Vec<Package> = left
+        .package_list
+        .iter()
+        .chain(right.package_list.iter())
+        .filter(|p| known_ids.insert(p.id.clone()))
+        .cloned()
+        .collect();
+
+    let mut selected_packages = BTreeSet::new();
+    for side in [left, right] {
+        selected_packages.extend(select_packages(&side.config, &package_list)?);
+    }
+    Ok(selected_packages)
+}
+
+/// Artifact types configured on either tenant, in the order of the left tenant.
+fn diff_artifact_types(left: &DiffSide, right: &DiffSide) -> Vec<ArtifactType> {
+    let mut artifact_types: Vec<ArtifactType> = Vec::new();
+    for artifact_type in left
+        .config
+        .packages
+        .artifact_types
+        .iter()
+        .chain(right.config.packages.artifact_types.iter())
+    {
+        if !artifact_types.contains(artifact_type) {
+            artifact_types.push(*artifact_type);
+        }
+    }
+    artifact_types
+}
+
+async fn fetch_entries(
+    side: &DiffSide,
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+    let respbytes = fetch_artifact(
+        artifact_id,
+        artifact_type,
+        &side.config,
+        &side.client,
+        &side.authorization,
+    )
+    .await?;
+
+    Ok(
+        extract_entries(&side.config, Cursor::new(respbytes.deref()))?
+            .into_iter()
+            .collect(),
+    )
+}
+
+/// Compares the extracted files of an artifact on both tenants, returns the report lines.
+async fn compare_artifact(
+    package_id: &str,
+    artifact_label: &str,
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+    left: &DiffSide,
+    right: &DiffSide,
+) -> Result<Vec<String>, Error> {
+    let (left_entries, right_entries) = futures::try_join!(
+        fetch_entries(left, artifact_id, artifact_type),
+        fetch_entries(right, artifact_id, artifact_type)
+    )?;
+
+    let mut lines = Vec::new();
+    let paths: BTreeSet<&String> = left_entries.keys().chain(right_entries.keys()).collect();
+    for path in paths {
+        let file_label = format!("{}/{}", artifact_label, path);
+        match (left_entries.get(path), right_entries.get(path)) {
+            (Some(_), None) => lines.push(format!("Only on {}: {}", left.name, file_label)),
+            (None, Some(_)) => lines.push(format!("Only on {}: {}", right.name, file_label)),
+            (Some(left_content), Some(right_content)) => {
+                if left_content == right_content {
+                    continue;
+                }
+                lines.push(format!("Changed: {}", file_label));
+                match (
+                    std::str::from_utf8(left_content),
+                    std::str::from_utf8(right_content),
+                ) {
+                    (Ok(left_text), Ok(right_text)) => {
+                        let unified_diff = TextDiff::from_lines(left_text, right_text)
+                            .unified_diff()
+                            .header(
+                                &format!("{}/{}/{}", left.name, package_id, file_label),
+                                &format!("{}/{}/{}", right.name, package_id, file_label),
+                            )
+                            .to_string();
+                        lines.push(unified_diff.trim_end().to_string());
+                    }
+                    _ => lines.push("Binary content differs".to_string()),
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    Ok(lines)
+}
+
+fn artifact_map(list: Vec<Artifact>) -> BTreeMap<String, Artifact> {
+    list.into_iter().map(|a| (a.id.clone(), a)).collect()
+}
+
+// report lines of a package, keyed by artifact label for a stable order
+type PackageReport = BTreeMap<String, Vec<String>>;
+
+// result of an artifact comparison task
+struct ArtifactDiff {
+    package_id: String,
+    artifact_label: String,
+    lines: Vec<String>,
+    // the artifact could not be downloaded or extracted on one of the tenants
+    failed: bool,
+}
+
+/// Lists the artifacts of a package on both tenants, returns the report lines
+/// and the comparison tasks for artifacts that exist on both tenants.
+async fn process_package_diff<'a>(
+    package_id: &'a str,
+    artifact_types: &'a [ArtifactType],
+    left: &'a DiffSide,
+    right: &'a DiffSide,
+) -> Result<
+    (
+        String,
+        PackageReport,
+        Vec<impl Future<Output = ArtifactDiff> + 'a>,
+    ),
+    Error,
+> {
+    let mut lines = PackageReport::new();
+    let mut tasks = Vec::new();
+
+    for (only, other) in [(left, right), (right, left)] {
+        if !other.package_set.contains(package_id) {
+            lines.insert(
+                String::new(),
+                vec![format!("Package only on {}", only.name)],
+            );
+            return Ok((package_id.to_string(), lines, tasks));
+        }
+    }
+
+    println!("Processing Package: {:?}", package_id);
+
+    for artifact_type in artifact_types.iter() {
+        let left_artifacts = artifact_map(
+            list_package_artifacts(
+                package_id,
+                *artifact_type,
+                &left.config,
+                &left.client,
+                &left.authorization,
+            )
+            .await?,
+        );
+        let right_artifacts = artifact_map(
+            list_package_artifacts(
+                package_id,
+                *artifact_type,
+                &right.config,
+                &right.client,
+                &right.authorization,
+            )
+            .await?,
+        );
+
+        let artifact_ids: BTreeSet<String> = left_artifacts
+            .keys()
+            .chain(right_artifacts.keys())
+            .cloned()
+            .collect();
+        for artifact_id in artifact_ids {
+            let artifact_label = format!("{}/{}", artifact_type.folder_name(), artifact_id);
+            let (left_artifact, right_artifact) = match (
+                left_artifacts.get(&artifact_id),
+                right_artifacts.get(&artifact_id),
+            ) {
+                (Some(l), Some(r)) => (l, r),
+                (Some(_), None) => {
+                    lines.insert(
+                        artifact_label.clone(),
+                        vec![format!("Only on {}: {}", left.name, artifact_label)],
+                    );
+                    continue;
+                }
+                _ => {
+                    lines.insert(
+                        artifact_label.clone(),
+                        vec![format!("Only on {}: {}", right.name, artifact_label)],
+                    );
+                    continue;
+                }
+            };
+
+            if left_artifact.version != right_artifact.version {
+                lines
+                    .entry(artifact_label.clone())
+                    .or_default()
+                    .push(format!(
+                        "Version mismatch: {} ({}: {}, {}: {})",
+                        artifact_label,
+                        left.name,
+                        left_artifact.version.as_deref().unwrap_or("-"),
+                        right.name,
+                        right_artifact.version.as_deref().unwrap_or("-"),
+                    ));
+            }
+
+            let artifact_type = *artifact_type;
+            tasks.push(async move {
+                let result = compare_artifact(
+                    package_id,
+                    &artifact_label,
+                    &artifact_id,
+                    artifact_type,
+                    left,
+                    right,
+                )
+                .await;
+                let failed = result.is_err();
+                let lines = result.unwrap_or_else(|err| {
+                    vec![format!("Could not compare {}: {}", artifact_label, err)]
+                });
+                ArtifactDiff {
+                    package_id: package_id.to_string(),
+                    artifact_label,
+                    lines,
+                    failed,
+                }
+            });
+        }
+    }
+
+    Ok((package_id.to_string(), lines, tasks))
+}
+
+/// Compares the selected packages of two tenants from the `tenants` config.
+/// Packages selected on either tenant are compared, packages that exist on one tenant only
+/// are reported as such. Artifact types configured on either tenant are compared.
+/// Artifacts that could not be compared fail with `Error::DiffIncomplete` after the report is written.
+pub async fn diff_with_tenants(
+    config: &Config,
+    credentials: &CredentialInput,
+    left_tenant: &str,
+    right_tenant: &str,
+    output: Option<&str>,
+) -> Result<(), Error> {
+    let now = tokio::time::Instant::now();
+
+    let mut tenant_logins = Vec::new();
+    for (tenant_name, tenant_config) in config.tenant_configs(&TenantSelection::Named(vec![
+        left_tenant.to_string(),
+        right_tenant.to_string(),
+    ]))? {
+        let password = get_password(&tenant_config, credentials)?;
+        let name = tenant_name.ok_or(Error::Config("Tenant name missing".to_string()))?;
+        tenant_logins.push((name, tenant_config, password));
+    }
+    let (right_name, right_config, right_password) = tenant_logins
+        .pop()
+        .ok_or(Error::Config("Tenant missing".to_string()))?;
+    let (left_name, left_config, left_password) = tenant_logins
+        .pop()
+        .ok_or(Error::Config("Tenant missing".to_string()))?;
+
+    let (left, right) = futures::try_join!(
+        connect_side(left_name, left_config, left_password),
+        connect_side(right_name, right_config, right_password)
+    )?;
+
+    let package_list = select_diff_packages(&left, &right)?;
+    let artifact_types = diff_artifact_types(&left, &right);
+
+    println!("Comparing These Packages:");
+    println!("{:?}", &package_list);
+
+    let worker_count = left.config.packages.download_worker_count;
+    let outputs = try_run_pooled(
+        package_list
+            .iter()
+            .map(|package_id| process_package_diff(package_id, &artifact_types, &left, &right)),
+        worker_count,
+    )
+    .await?;
+
+    let mut package_reports: BTreeMap<String, PackageReport> = BTreeMap::new();
+    let mut tasks = Vec::new();
+    for (package_id, lines, mut package_tasks) in outputs {
+        package_reports.insert(package_id, lines);
+        tasks.append(&mut package_tasks);
+    }
+    let mut failed_count = 0;
+    for artifact_diff in run_pooled(tasks, worker_count).await {
+        if artifact_diff.failed {
+            failed_count += 1;
+        }
+        package_reports
+            .entry(artifact_diff.package_id)
+            .or_default()
+            .entry(artifact_diff.artifact_label)
+            .or_default()
+            .extend(artifact_diff.lines);
+    }
+
+    let mut report = format!("Diff Report: {} <-> {}\n", &left.name, &right.name);
+    let mut changed_package_count = 0;
+    for (package_id, package_report) in package_reports.iter() {
+        if package_report.values().all(|lines| lines.is_empty()) {
+            continue;
+        }
+        changed_package_count += 1;
+        report.push_str(&format!("\n=== Package: {}\n", package_id));
+        for line in package_report.values().flatten() {
+            report.push_str(line);
+            report.push('\n');
+        }
+    }
+    report.push_str(&format!(
+        "\nCompared packages: {}, packages with differences: {}\n",
+        package_reports.len(),
+        changed_package_count
+    ));
+    if failed_count > 0 {
+        report.push_str(&format!(
+            "Artifacts that could not be compared: {}\n",
+            failed_count
+        ));
+    }
+
+    match output {
+        Some(output_path) => {
+            fs::write(output_path, &report)?;
+            println!("Diff report written to: {}", output_path);
+        }
+        None => println!("{}", report),
+    }
+
+    println!("Diff time elapsed in seconds: {}", now.elapsed().as_secs());
+
+    if failed_count > 0 {
+        return Err(Error::DiffIncomplete(failed_count));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+    use wiremock::{MockServer, ResponseTemplate};
+
+    const FLOWS: &str = "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts";
+
+    async fn mount_flow(server: &MockServer, artifact_id: &str, response: ResponseTemplate) {
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .and(wiremock::matchers::path(format!(
+                "/api/v1/IntegrationDesigntimeArtifacts(Id='{}',Version='Active')/$value",
+                artifact_id
+            )))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    fn flow(content: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_bytes(test_util::zip(&[("flow.iflw", content)]))
+    }
+
+    /// Tenant with the given packages, `Pkg1` has the given flows.
+    async fn tenant(packages: serde_json::Value, flows: &[(&str, ResponseTemplate)]) -> MockServer {
+        let server = test_util::mock_server().await;
+        test_util::mount_results(&server, "/IntegrationPackages", packages).await;
+        let flow_list: Vec<serde_json::Value> = flows
+            .iter()
+            .map(|(id, _)| json!({ "Id": id, "Name": id, "Version": "1.0.0" }))
+            .collect();
+        test_util::mount_results(&server, FLOWS, json!(flow_list)).await;
+        for (artifact_id, response) in flows {
+            mount_flow(&server, artifact_id, response.clone()).await;
+        }
+        server
+    }
+
+    fn tenants_config(left: &MockServer, right: &MockServer) -> Config {
+        test_util::config(json!({
+            "tenants": {
+                "DEV": test_util::mock_tenant(left, test_util::s_user()),
+                "PRD": test_util::mock_tenant(right, test_util::s_user())
+            },
+            "packages": {
+                "filter_rules": [{ "type": "regex", "pattern": "^Pkg" }],
+                "artifact_types": ["IntegrationDesigntimeArtifacts"]
+            },
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    async fn run_diff(left: &MockServer, right: &MockServer) -> (Result<(), Error>, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("diff.txt");
+        let result = diff_with_tenants(
+            &tenants_config(left, right),
+            &CredentialInput::new(true, Some("secret".to_string()), None),
+            "DEV",
+            "PRD",
+            Some(&output.to_string_lossy()),
+        )
+        .await;
+        (result, fs::read_to_string(&output).unwrap())
+    }
+
+    #[tokio::test]
+    async fn reports_changed_and_one_sided_artifacts() {
+        let left = tenant(
+            json!([{ "Id": "Pkg1", "Name": "Pkg1" }, { "Id": "Pkg2", "Name": "Pkg2" }]),
+            &[
+                ("Same", flow("<same/>")),
+                ("Changed", flow("<left/>")),
+                ("OnlyLeft", flow("<x/>")),
+            ],
+        )
+        .await;
+        let right = tenant(
+            json!([{ "Id": "Pkg1", "Name": "Pkg1" }]),
+            &[
+                ("Same", flow("<same/>")),
+                ("Changed", flow("<right/>")),
+                ("OnlyRight", flow("<x/>")),
+            ],
+        )
+        .await;
+
+        let (result, report) = run_diff(&left, &right).await;
+        result.unwrap();
+        assert!(report.contains("=== Package: Pkg1"));
+        assert!(report.contains("Changed: IntegrationFlows/Changed/flow.iflw"));
+        assert!(report.contains("-<left/>"));
+        assert!(report.contains("+<right/>"));
+        assert!(report.contains("Only on DEV: IntegrationFlows/OnlyLeft"));
+        assert!(report.contains("Only on PRD: IntegrationFlows/OnlyRight"));
+        assert!(!report.contains("IntegrationFlows/Same"));
+        assert!(report.contains("=== Package: Pkg2\nPackage only on DEV"));
+        assert!(report.contains("Compared packages: 2, packages with differences: 2"));
+    }
+
+    #[tokio::test]
+    async fn identical_tenants_have_no_differences() {
+        let packages = json!([{ "Id": "Pkg1", "Name": "Pkg1" }]);
+        let left = tenant(packages.clone(), &[("Same", flow("<same/>"))]).await;
+        let right = tenant(packages, &[("Same", flow("<same/>"))]).await;
+
+        let (result, report) = run_diff(&left, &right).await;
+        result.unwrap();
+        assert!(!report.contains("==="));
+        assert!(report.contains("Compared packages: 1, packages with differences: 0"));
+    }
+
+    #[tokio::test]
+    async fn failed_comparison_fails_after_the_report() {
+        let packages = json!([{ "Id": "Pkg1", "Name": "Pkg1" }]);
+        let left = tenant(packages.clone(), &[("Flow", flow("<x/>"))]).await;
+        let right = tenant(packages, &[("Flow", ResponseTemplate::new(500))]).await;
+
+        let (result, report) = run_diff(&left, &right).await;
+        assert!(matches!(result, Err(Error::DiffIncomplete(1))));
+        assert!(report.contains("Could not compare IntegrationFlows/Flow"));
+        assert!(report.contains("Artifacts that could not be compared: 1"));
+    }
+}
@@ -1,6 +1,9 @@
 use thiserror::Error;
 
+/// Error of all library functions. New variants can be added in minor versions,
+/// match with a wildcard arm.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("Request error: {0}")]
     Request(#[from] reqwest::Error),
@@ -22,6 +25,37 @@ pub enum Error {
 
     #[error("JSON validation error: {0}")]
     JSONValidation(String),
+
+    #[error("Config error: {0}")]
+    Config(String),
+
+    #[error("Git error: {0}")]
+    Git(String),
+
+    #[error("Vault error: {0}")]
+    Vault(String),
+
+    #[error("Local files differ from tenants: {}", .0.join(", "))]
+    VerifyFailed(Vec<String>),
+
+    #[error("Failed tenants: {}", .0.join(","))]
+    TenantsFailed(Vec<String>),
+
+    #[error("Artifact pushes failed: {}", .0.join(", "))]
+    PushFailed(Vec<String>),
+
+    #[error("Diff incomplete, artifacts that could not be compared: {0}")]
+    DiffIncomplete(usize),
+
+    #[error("Unexpected response of {url}: {message}")]
+    UnexpectedResponse { url: String, message: String },
+
+    #[error("API request failed with status {status}: {url}")]
+    Api {
+        url: String,
+        status: u16,
+        body: String,
+    },
 }
 
 impl<'a> From<jsonschema::ValidationError<'a>> for Error {
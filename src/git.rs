@@ -0,0 +1,433 @@
+use crate::config::*;
+use crate::errors::Error;
+use crate::package::PACKAGE_EXPORT_DIR_NAME;
+use crate::report::TenantReport;
+use crate::snapshot::snapshot_prefix;
+use crate::state::STATE_FILE_NAME;
+use crate::tenant_content::TENANT_CONTENT_DIR_NAME;
+
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    process::{Command, Output},
+};
+
+/// Runs a git command inside `dir`, the author from the config is passed as `-c` options.
+fn git(git_config: &Git, dir: &Path, args: &[&str]) -> Result<Output, Error> {
+    let mut command = Command::new("git");
+    command.current_dir(dir);
+    if let Some(author_name) = &git_config.author_name {
+        command.arg("-c").arg(format!("user.name={}", author_name));
+    }
+    if let Some(author_email) = &git_config.author_email {
+        command
+            .arg("-c")
+            .arg(format!("user.email={}", author_email));
+    }
+    command
+        .args(args)
+        .output()
+        .map_err(|e| Error::Git(format!("Could not run git: {}", e)))
+}
+
+/// Like `git`, but a non-zero exit status is an error with the git output.
+fn git_checked(git_config: &Git, dir: &Path, args: &[&str]) -> Result<String, Error> {
+    let output = git(git_config, dir, args)?;
+    if !output.status.success() {
+        return Err(Error::Git(format!(
+            "git {} failed: {}{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// `local_dir` can be anywhere inside a work tree, a new repository is created otherwise.
+fn ensure_repository(git_config: &Git, data_dir: &Path) -> Result<(), Error> {
+    let output = git(
+        git_config,
+        data_dir,
+        &["rev-parse", "--is-inside-work-tree"],
+    )?;
+    if !output.status.success() {
+        git_checked(git_config, data_dir, &["init"])?;
+        println!("Initialized Git repository: {}", data_dir.display());
+    }
+    Ok(())
+}
+
+/// Paths under `data_dir` written by the sync, other files like the `logs` output are not committed.
+fn synced_paths(config: &Config, selected_packages: &[String]) -> Result<Vec<String>, Error> {
+    if config.output.format.extension().is_some() {
+        //pathspec pattern, matches the snapshots of the tenant
+        return Ok(vec![format!(
+            "{}*.{}",
+            snapshot_prefix(config)?,
+            config.output.format.extension().unwrap_or_default()
+        )]);
+    }
+    let mut paths = vec![STATE_FILE_NAME.to_string()];
+    paths.extend(selected_packages.iter().cloned());
+    if matches!(config.packages.package_export, PackageExport::Enabled) {
+        paths.push(PACKAGE_EXPORT_DIR_NAME.to_string());
+    }
+    if !config.tenant_content.is_empty() {
+        paths.push(TENANT_CONTENT_DIR_NAME.to_string());
+    }
+    Ok(paths)
+}
+
+/// Paths that exist or are tracked, `git add` fails for a pathspec that matches nothing.
+fn existing_paths(
+    git_config: &Git,
+    data_dir: &Path,
+    paths: &[String],
+) -> Result<Vec<String>, Error> {
+    let mut existing = Vec::new();
+    for path in paths {
+        let tracked = || -> Result<bool, Error> {
+            let output = git(git_config, data_dir, &["ls-files", "--", path])?;
+            Ok(output.status.success() && !output.stdout.is_empty())
+        };
+        if path.contains('*') || data_dir.join(path).exists() || tracked()? {
+            existing.push(path.clone());
+        }
+    }
+    Ok(existing)
+}
+
+/// Staged files under the given paths, relative to `data_dir`.
+fn staged_files(git_config: &Git, data_dir: &Path, paths: &[String]) -> Result<Vec<String>, Error> {
+    let mut args = vec!["diff", "--cached", "--name-only", "--relative", "-z", "--"];
+    args.extend(paths.iter().map(|p| p.as_str()));
+    let stdout = git_checked(git_config, data_dir, &args)?;
+    Ok(stdout
+        .split('\0')
+        .filter(|f| !f.is_empty())
+        .map(|f| f.to_string())
+        .collect())
+}
+
+/// Selected packages with changes, other folders like `tenant` and files directly
+/// in `local_dir` like the state file are left out.
+fn changed_packages(files: &[String], selected_packages: &[String]) -> BTreeSet<String> {
+    files
+        .iter()
+        .filter_map(|f| f.split_once('/').map(|(package_id, _)| package_id))
+        .filter(|package_id| selected_packages.iter().any(|p| p == package_id))
+        .map(|package_id| package_id.to_string())
+        .collect()
+}
+
+fn commit_message(
+    git_config: &Git,
+    tenant_name: Option<&str>,
+    management_host: &str,
+    timestamp: &str,
+    packages: &[&str],
+) -> String {
+    git_config
+        .message
+        .replace("{tenant}", tenant_name.unwrap_or(management_host))
+        .replace("{host}", management_host)
+        .replace("{timestamp}", timestamp)
+        .replace("{packages}", &packages.join(", "))
+}
+
+/// Commits only the given paths, changes staged by someone else stay staged.
+fn commit_paths(
+    git_config: &Git,
+    data_dir: &Path,
+    message: &str,
+    paths: &[&str],
+) -> Result<(), Error> {
+    let mut args = vec!["commit", "--quiet", "-m", message, "--"];
+    args.extend(paths);
+    git_checked(git_config, data_dir, &args)?;
+    println!("Git commit: {}", message.lines().next().unwrap_or_default());
+    Ok(())
+}
+
+/// Stages the changes of the synced paths under `local_dir` and commits them,
+/// one commit per run or per package.
+pub fn commit_sync(
+    config: &Config,
+    tenant_name: Option<&str>,
+    tenant_report: &TenantReport,
+) -> Result<(), Error> {
+    let git_config = match &config.git {
+        Some(g) if matches!(g.commit, GitCommit::Enabled) => g,
+        _ => return Ok(()),
+    };
+    let management_host = &config.tenant()?.management_host;
+    let data_dir = tenant_report.local_dir.as_path();
+
+    ensure_repository(git_config, data_dir)?;
+    let paths = existing_paths(
+        git_config,
+        data_dir,
+        &synced_paths(config, &tenant_report.selected_packages)?,
+    )?;
+    if paths.is_empty() {
+        println!("Git: no changes to commit.");
+        return Ok(());
+    }
+    let mut add_args = vec!["add", "--all", "--"];
+    add_args.extend(paths.iter().map(|p| p.as_str()));
+    git_checked(git_config, data_dir, &add_args)?;
+
+    let files = staged_files(git_config, data_dir, &paths)?;
+    if files.is_empty() {
+        println!("Git: no changes to commit.");
+        return Ok(());
+    }
+
+    let timestamp = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let packages = changed_packages(&files, &tenant_report.selected_packages);
+
+    match git_config.commit_per {
+        GitCommitPer::Run => {
+            let package_list: Vec<&str> = packages.iter().map(|p| p.as_str()).collect();
+            let message = commit_message(
+                git_config,
+                tenant_name,
+                management_host,
+                &timestamp,
+                &package_list,
+            );
+            let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+            commit_paths(git_config, data_dir, &message, &paths)?;
+        }
+        GitCommitPer::Package => {
+            //other files, e.g. the sync state and tenant content, go with the last package
+            let root_files: Vec<&str> = files
+                .iter()
+                .filter(|f| {
+                    let top = f.split_once('/').map(|(top, _)| top).unwrap_or(f);
+                    !packages.contains(top)
+                })
+                .map(|f| f.as_str())
+                .collect();
+            if packages.is_empty() {
+                let message =
+                    commit_message(git_config, tenant_name, management_host, &timestamp, &[]);
+                commit_paths(git_config, data_dir, &message, &root_files)?;
+            }
+            for (i, package_id) in packages.iter().enumerate() {
+                let message = commit_message(
+                    git_config,
+                    tenant_name,
+                    management_host,
+                    &timestamp,
+                    &[package_id],
+                );
+                let mut paths = vec![package_id.as_str()];
+                if i + 1 == packages.len() {
+                    paths.extend(root_files.iter());
+                }
+                commit_paths(git_config, data_dir, &message, &paths)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pushes the current branch to `push_remote`, if configured.
+/// Tenants can share a repository, each repository is pushed once.
+pub fn push_sync(config: &Config, data_dirs: &[&Path]) -> Result<(), Error> {
+    let (git_config, remote) = match &config.git {
+        Some(g) if matches!(g.commit, GitCommit::Enabled) => match &g.push_remote {
+            Some(remote) => (g, remote),
+            None => return Ok(()),
+        },
+        _ => return Ok(()),
+    };
+
+    let mut repositories = BTreeSet::new();
+    for data_dir in data_dirs {
+        let toplevel = git_checked(git_config, data_dir, &["rev-parse", "--show-toplevel"])?;
+        if !repositories.insert(toplevel.trim().to_string()) {
+            continue;
+        }
+        git_checked(git_config, data_dir, &["push", "--quiet", remote, "HEAD"])?;
+        println!("Git pushed to remote: {} ({})", remote, toplevel.trim());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn test_config(commit_per: &str, push_remote: &Path) -> Config {
+        crate::test_util::config(serde_json::json!({
+            "tenant_content": ["variables"],
+            "git": {
+                "commit": "enabled",
+                "commit_per": commit_per,
+                "author_name": "CPI Sync",
+                "author_email": "cpisync@example.com",
+                "message": "Sync {tenant}: {packages}",
+                "push_remote": push_remote.to_string_lossy()
+            }
+        }))
+    }
+
+    fn write(dir: &Path, path: &str, content: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn remote_git(remote: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(remote)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+
+    /// Sync output with a logs folder and a folder of an unselected package.
+    fn sync_output(commit_per: &str) -> (tempfile::TempDir, Config, TenantReport) {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("remote.git");
+        let output = Command::new("git")
+            .args(["init", "--quiet", "--bare"])
+            .arg(&remote)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+
+        let data_dir = dir.path().join("data");
+        write(&data_dir, STATE_FILE_NAME, "{}");
+        write(&data_dir, "PkgA/package.json", "{}");
+        write(&data_dir, "PkgB/IntegrationFlows/Flow/flow.iflw", "<x/>");
+        write(&data_dir, "Old/package.json", "{}");
+        write(&data_dir, "tenant/variables.json", "[]");
+        write(&data_dir, "logs/messages.jsonl", "{}");
+
+        let report = TenantReport {
+            local_dir: data_dir,
+            selected_packages: vec!["PkgA".to_string(), "PkgB".to_string()],
+            ..Default::default()
+        };
+        (dir, test_config(commit_per, &remote), report)
+    }
+
+    #[test]
+    fn commits_per_package_and_pushes_synced_paths() {
+        let (dir, config, report) = sync_output("package");
+        commit_sync(&config, Some("DEV"), &report).unwrap();
+        push_sync(&config, &[&report.local_dir]).unwrap();
+
+        let remote = dir.path().join("remote.git");
+        assert_eq!(
+            remote_git(&remote, &["log", "--reverse", "--format=%s"]),
+            "Sync DEV: PkgA\nSync DEV: PkgB\n"
+        );
+        assert_eq!(
+            remote_git(&remote, &["ls-tree", "-r", "--name-only", "HEAD"]),
+            ".cpisync-state.json\nPkgA/package.json\nPkgB/IntegrationFlows/Flow/flow.iflw\ntenant/variables.json\n"
+        );
+
+        //nothing changed, no new commit
+        commit_sync(&config, Some("DEV"), &report).unwrap();
+        assert_eq!(
+            remote_git(&remote, &["rev-list", "--count", "HEAD"]).trim(),
+            "2"
+        );
+    }
+
+    #[test]
+    fn commits_per_run_with_deleted_files() {
+        let (dir, config, report) = sync_output("run");
+        commit_sync(&config, None, &report).unwrap();
+        fs::remove_dir_all(report.local_dir.join("PkgB")).unwrap();
+        commit_sync(&config, None, &report).unwrap();
+        push_sync(&config, &[&report.local_dir]).unwrap();
+
+        let remote = dir.path().join("remote.git");
+        assert_eq!(
+            remote_git(&remote, &["log", "--reverse", "--format=%s"]),
+            "Sync tenant.example.com: PkgA, PkgB\nSync tenant.example.com: PkgB\n"
+        );
+        assert_eq!(
+            remote_git(&remote, &["ls-tree", "-r", "--name-only", "HEAD"]),
+            ".cpisync-state.json\nPkgA/package.json\ntenant/variables.json\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_tenant_does_not_stop_the_commit_of_the_others() {
+        let dev = crate::test_util::mock_server().await;
+        crate::test_util::mount_results(&dev, "/IntegrationPackages", serde_json::json!([])).await;
+        //no API check mock, the tenant fails
+        let bad = wiremock::MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let s_user = crate::test_util::s_user();
+        let config = crate::test_util::config(serde_json::json!({
+            "tenants": {
+                "DEV": crate::test_util::mock_tenant(&dev, s_user.clone()),
+                "BAD": crate::test_util::mock_tenant(&bad, s_user)
+            },
+            "packages": {
+                "filter_rules": [],
+                "local_dir": dir.path().join("data").to_string_lossy()
+            },
+            "http": { "max_attempts": 1 },
+            "git": {
+                "commit": "enabled",
+                "commit_per": "run",
+                "author_name": "CPI Sync",
+                "author_email": "cpisync@example.com",
+                "message": "Sync {tenant}: {packages}"
+            }
+        }));
+
+        let result = crate::run_with_tenants(
+            &config,
+            &dir.path().join("cpi-sync.json").to_string_lossy(),
+            &crate::CredentialInput::new(true, Some("secret".to_string()), None),
+            false,
+            &crate::TenantSelection::All,
+            None,
+        )
+        .await;
+
+        assert!(
+            matches!(&result, Err(Error::TenantsFailed(names)) if names == &["BAD"]),
+            "{:?}",
+            result
+        );
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir.path().join("data/DEV"))
+            .args(["log", "--format=%s"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "Sync DEV:");
+    }
+
+    #[test]
+    fn changed_packages_are_selected_packages() {
+        let files = [
+            ".cpisync-state.json".to_string(),
+            "PkgA/package.json".to_string(),
+            "package-exports/PkgA.zip".to_string(),
+            "tenant/variables.json".to_string(),
+            "logs/messages.jsonl".to_string(),
+        ];
+        let selected = ["PkgA".to_string(), "PkgB".to_string()];
+        assert_eq!(
+            changed_packages(&files, &selected),
+            BTreeSet::from(["PkgA".to_string()])
+        );
+    }
+}
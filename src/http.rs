@@ -0,0 +1,455 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::errors::Error;
+
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, error::Error as StdError, fs, io, time::Duration};
+
+// transient responses: rate limit and gateway errors
+const RETRY_STATUS_CODES: [u16; 4] = [429, 502, 503, 504];
+
+// retries and token refreshes are printed by the command line, the library is silent
+static PRINT_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Prints retried requests and token refreshes to stdout. Disabled by default.
+pub fn set_print_progress(enabled: bool) {
+    PRINT_PROGRESS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn print_progress() -> bool {
+    PRINT_PROGRESS.load(Ordering::Relaxed)
+}
+
+/// Proxy from the `http` config, with credentials from environment variables.
+fn proxy(http: &Http, proxy_url: &str) -> Result<reqwest::Proxy, Error> {
+    let mut proxy = reqwest::Proxy::all(proxy_url)
+        .map_err(|e| Error::Config(format!("Invalid proxy: {}: {}", proxy_url, e)))?;
+    if let Some(varkey) = &http.proxy_username_environment_variable {
+        let username = env::var(varkey).map_err(|e| {
+            Error::Config(format!(
+                "Can not find proxy username environment variable: {}: {}",
+                varkey, e
+            ))
+        })?;
+        let password = match &http.proxy_password_environment_variable {
+            Some(varkey) => env::var(varkey).map_err(|e| {
+                Error::Config(format!(
+                    "Can not find proxy password environment variable: {}: {}",
+                    varkey, e
+                ))
+            })?,
+            None => String::new(),
+        };
+        proxy = proxy.basic_auth(&username, &password);
+    }
+    if !http.no_proxy.is_empty() {
+        proxy = proxy.no_proxy(reqwest::NoProxy::from_string(&http.no_proxy.join(",")));
+    }
+    Ok(proxy)
+}
+
+/// Client builder with rustls, proxy, CA files, timeouts and user agent from the `http` config.
+/// All clients are built from it, including the one for the token request.
+pub fn client_builder(config: &Config) -> Result<reqwest::ClientBuilder, Error> {
+    let http = &config.http;
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .user_agent(&http.user_agent);
+
+    match &http.proxy {
+        Some(proxy_url) => builder = builder.proxy(proxy(http, proxy_url)?),
+        //reqwest reads HTTPS_PROXY/HTTP_PROXY and NO_PROXY itself, `no_proxy` would be ignored
+        None if !http.no_proxy.is_empty() => {
+            return Err(Error::Config(
+                "`no_proxy` needs `proxy`, set the NO_PROXY environment variable for the HTTPS_PROXY/HTTP_PROXY proxy"
+                    .to_string(),
+            ))
+        }
+        None => {}
+    }
+    for ca_file in http.ca_files.iter() {
+        let pem = fs::read(ca_file)
+            .map_err(|e| Error::Config(format!("Can not read CA file: {}: {}", ca_file, e)))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| Error::Config(format!("Invalid CA file: {}: {}", ca_file, e)))?;
+        if certificates.is_empty() {
+            return Err(Error::Config(format!(
+                "No PEM certificate in CA file: {}",
+                ca_file
+            )));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(connect_timeout_ms) = http.connect_timeout_ms {
+        builder = builder.connect_timeout(Duration::from_millis(connect_timeout_ms));
+    }
+    //total deadline of a request, reqwest has no read timeout
+    if let Some(request_timeout_ms) = http.request_timeout_ms {
+        builder = builder.timeout(Duration::from_millis(request_timeout_ms));
+    }
+    Ok(builder)
+}
+
+/// Exponential backoff with jitter: a random delay between half and the full backoff.
+fn backoff(http: &Http, attempt: u32) -> Duration {
+    let exp_ms = http
+        .backoff_initial_ms
+        .saturating_mul(1u64 << (attempt - 1).min(20));
+    let delay_ms = exp_ms.min(http.backoff_max_ms);
+    let jitter_ms = rand::thread_rng().gen_range(0..=delay_ms / 2);
+    Duration::from_millis(delay_ms - jitter_ms)
+}
+
+/// `Retry-After` value in seconds or as HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(wait.to_std().unwrap_or_default())
+}
+
+/// `Retry-After` of the response, at most `backoff_max_ms`.
+fn retry_after(http: &Http, resp: &reqwest::Response) -> Option<Duration> {
+    let wait = parse_retry_after(resp.headers().get("Retry-After")?.to_str().ok()?)?;
+    Some(wait.min(Duration::from_millis(http.backoff_max_ms)))
+}
+
+fn is_retry_status(status: reqwest::StatusCode) -> bool {
+    RETRY_STATUS_CODES.contains(&status.as_u16())
+}
+
+/// Connection errors and resets, timeouts. Errors in building the request are not transient.
+fn is_transient(err: &reqwest::Error) -> bool {
+    if err.is_builder() || err.is_redirect() || err.is_status() {
+        return false;
+    }
+    if err.is_connect() || err.is_timeout() {
+        return true;
+    }
+
+    let mut source = err.source();
+    while let Some(e) = source {
+        if let Some(io_err) = e.downcast_ref::<io::Error>() {
+            return matches!(
+                io_err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            );
+        }
+        source = e.source();
+    }
+    //e.g. connection closed before the response was complete
+    err.is_request()
+}
+
+/// Builds the request with the current authorization header.
+fn prepare(
+    request: reqwest::RequestBuilder,
+    auth_header: &Option<String>,
+) -> Result<(reqwest::Client, reqwest::Request), Error> {
+    let (client, request) = request.build_split();
+    let mut request = request?;
+    if let Some(auth_header) = auth_header {
+        let mut header_value = reqwest::header::HeaderValue::from_str(auth_header)
+            .map_err(|e| Error::Config(format!("Invalid authorization header: {}", e)))?;
+        header_value.set_sensitive(true);
+        request
+            .headers_mut()
+            .insert(reqwest::header::AUTHORIZATION, header_value);
+    }
+    Ok((client, request))
+}
+
+/// Sends the request with the retry policy of the `http` config:
+/// transient responses and connection errors are retried with backoff,
+/// `Retry-After` is honored up to `backoff_max_ms`.
+/// With `authorization` the header is set per attempt, a 401 response is retried once with a new token.
+pub async fn send(
+    config: &Config,
+    authorization: Option<&Authorization>,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Error> {
+    let http = &config.http;
+    let mut auth_header = match authorization {
+        Some(a) => Some(a.header().await?),
+        None => None,
+    };
+
+    //streaming bodies can't be cloned, they are sent once
+    if request.try_clone().is_none() {
+        let (client, request) = prepare(request, &auth_header)?;
+        return Ok(client.execute(request).await?);
+    }
+
+    let mut attempt = 1;
+    let mut token_refreshed = false;
+    loop {
+        let attempt_request = request
+            .try_clone()
+            .ok_or_else(|| Error::Config("Request can't be cloned".to_string()))?;
+        let (client, attempt_request) = prepare(attempt_request, &auth_header)?;
+        let url = attempt_request.url().clone();
+
+        let result = client.execute(attempt_request).await;
+
+        if let (Ok(resp), Some(authorization), Some(rejected_header)) =
+            (&result, authorization, &auth_header)
+        {
+            if resp.status() == reqwest::StatusCode::UNAUTHORIZED && !token_refreshed {
+                if let Some(new_header) = authorization.refresh(rejected_header).await? {
+                    token_refreshed = true;
+                    auth_header = Some(new_header);
+                    continue;
+                }
+            }
+        }
+
+        if attempt >= http.max_attempts {
+            return Ok(result?);
+        }
+        let (delay, reason) = match &result {
+            Ok(resp) if is_retry_status(resp.status()) => (
+                retry_after(http, resp).unwrap_or_else(|| backoff(http, attempt)),
+                format!("API Response Code: {}", resp.status().as_u16()),
+            ),
+            Err(err) if is_transient(err) => (backoff(http, attempt), err.to_string()),
+            _ => return Ok(result?),
+        };
+
+        attempt += 1;
+        if print_progress() {
+            println!(
+                "Request failed, retrying in {} ms (attempt {}/{}): {} ({})",
+                delay.as_millis(),
+                attempt,
+                http.max_attempts,
+                url,
+                reason
+            );
+        }
+        tokio::time::sleep(delay).await;
+
+        //the token may have expired during the backoff
+        if let Some(authorization) = authorization {
+            auth_header = Some(authorization.header().await?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, s_user};
+    use std::collections::BTreeSet;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_config(server: &MockServer, credential: serde_json::Value) -> Config {
+        test_util::config(serde_json::json!({
+            "tenant": test_util::mock_tenant(server, credential),
+            "http": { "max_attempts": 3, "backoff_initial_ms": 1, "backoff_max_ms": 20 }
+        }))
+    }
+
+    fn oauth(server: &MockServer) -> serde_json::Value {
+        serde_json::json!({ "oauth_client_credentials": {
+            "client_id": "client",
+            "token_endpoint_url": format!("{}/oauth/token", server.uri())
+        }})
+    }
+
+    fn token(access_token: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "access_token": access_token,
+            "expires_in": 3600
+        }))
+    }
+
+    #[test]
+    fn no_proxy_without_proxy_is_rejected() {
+        let mut config = test_util::config(serde_json::json!({
+            "http": { "no_proxy": ["localhost"] }
+        }));
+        assert!(matches!(
+            client_builder(&config),
+            Err(Error::Config(message)) if message.contains("`no_proxy` needs `proxy`")
+        ));
+
+        config.http.proxy = Some("http://proxy.example.com:8080".to_string());
+        assert!(client_builder(&config).is_ok());
+    }
+
+    #[test]
+    fn backoff_is_bounded_with_jitter() {
+        let http = Http {
+            backoff_initial_ms: 100,
+            backoff_max_ms: 1000,
+            ..Default::default()
+        };
+        for (attempt, delay_ms) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (40, 1000),
+        ] {
+            for _ in 0..50 {
+                let delay = backoff(&http, attempt).as_millis() as u64;
+                assert!(
+                    delay >= delay_ms / 2 && delay <= delay_ms,
+                    "attempt {}: {} ms",
+                    attempt,
+                    delay
+                );
+            }
+        }
+
+        let delays: BTreeSet<Duration> = (0..50).map(|_| backoff(&http, 5)).collect();
+        assert!(delays.len() > 1, "no jitter: {:?}", delays);
+    }
+
+    #[test]
+    fn retry_after_seconds_and_date() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let future = (chrono::Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        let wait = parse_retry_after(&future).unwrap();
+        assert!(wait > Duration::from_secs(25) && wait <= Duration::from_secs(30));
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-1"), None);
+    }
+
+    #[test]
+    fn transient_statuses() {
+        for status in [429, 502, 503, 504] {
+            assert!(is_retry_status(
+                reqwest::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+        for status in [200, 400, 401, 403, 404, 500] {
+            assert!(!is_retry_status(
+                reqwest::StatusCode::from_u16(status).unwrap()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_transient_status_with_clamped_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "3600"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let config = test_config(&server, s_user());
+        let client = client_builder(&config).unwrap().build().unwrap();
+        let url = format!("{}/", config.tenant().unwrap().api_url());
+        let started = std::time::Instant::now();
+        let resp = send(&config, None, client.get(&url)).await.unwrap();
+
+        assert_eq!(resp.status(), 200);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let config = test_config(&server, s_user());
+        let client = client_builder(&config).unwrap().build().unwrap();
+        let url = format!("{}/", config.tenant().unwrap().api_url());
+        let resp = send(&config, None, client.get(&url)).await.unwrap();
+        assert_eq!(resp.status(), 429);
+    }
+
+    #[tokio::test]
+    async fn refreshes_token_once_after_401() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(token("expired"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(token("fresh"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .and(header("Authorization", "Bearer fresh"))
+            .respond_with(ResponseTemplate::new(200))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let config = test_config(&server, oauth(&server));
+        let client = client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+        let url = format!("{}/", config.tenant().unwrap().api_url());
+        let resp = send(&config, Some(&authorization), client.get(&url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn rejected_fresh_token_is_not_refreshed_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(token("rejected"))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let config = test_config(&server, oauth(&server));
+        let client = client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+        let url = format!("{}/", config.tenant().unwrap().api_url());
+        let resp = send(&config, Some(&authorization), client.get(&url))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+    }
+}
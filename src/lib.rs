@@ -1,7 +1,32 @@
+mod auth;
+mod client;
 mod config;
+mod configurations;
+mod credentials;
+mod diff;
 pub mod errors;
-
-use crate::errors::Error;
+mod git;
+mod http;
+mod logs;
+mod normalize;
+mod package;
+mod plan;
+mod push;
+mod report;
+mod runtime;
+mod service_key;
+mod snapshot;
+mod state;
+mod storage;
+mod tenant_content;
+#[cfg(test)]
+mod test_util;
+mod vault;
+mod verify;
+
+use crate::auth::Authorization;
+use crate::client::ODataResponse;
+use crate::credentials::get_password;
 
 use config::*;
 use futures::{
@@ -10,318 +35,554 @@ use futures::{
 };
 use path_slash::PathBufExt;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use report::{ArtifactReport, ArtifactStatus, RunReport, TenantReport};
+use state::{content_hash, entries_hash, ArtifactState, PackageState, SyncState};
 use std::{
     collections::{HashMap, HashSet},
-    env,
     io::{Read, Write},
     iter::FromIterator,
     path::{Component, Path, PathBuf},
+    sync::Arc,
 };
 use std::{fs, io::Cursor, ops::Deref};
 
-pub use config::Config;
+pub use client::{Artifact, CpiClient, Package};
+pub use config::{
+    ArtifactType, CertificateFormat, Config, CredentialInside, CredentialOauthClientCertificate,
+    CredentialOauthClientCredentials, CredentialSUser, CredentialServiceKeyFile, Http, Output,
+    OutputFormat, Tenant, TenantSelection, CONFIG_VERSION,
+};
+pub use credentials::{
+    credentials_add, credentials_list, credentials_remove, CredentialInput,
+    VAULT_PASSPHRASE_ENVIRONMENT_VARIABLE,
+};
+pub use diff::diff_with_tenants;
+pub use errors::Error;
+pub use http::set_print_progress;
+pub use logs::{logs_with_config_and_password, logs_with_tenants, LogQuery};
+pub use plan::{dry_run_with_tenants, plan_with_config_and_password, SyncPlan};
+pub use push::{push_with_config, push_with_config_and_password, push_with_tenants};
+pub use storage::{FileSystemStorage, MemoryStorage, Storage};
+pub use verify::{verify_with_config_and_password, verify_with_tenants, VerifyReport};
 
 // use rand::seq::SliceRandom;
 // use rand::thread_rng;
 
-// response types
-#[derive(Serialize, Deserialize, Debug)]
-struct APIResponseResult {
-    #[serde(rename = "Id")]
-    id: String,
-    #[serde(rename = "Name")]
-    name: String,
-    #[serde(rename = "Mode")]
-    mode: Option<String>,
+// result of a single artifact download task
+struct ArtifactSyncResult {
+    // None if the download failed
+    state: Option<ArtifactState>,
+    report: ArtifactReport,
+}
+
+/// Storage path of the directory that contains the artifacts of the given type in a package.
+fn artifact_type_path(package_id: &str, artifact_type: ArtifactType, config: &Config) -> String {
+    match config.packages.artifact_type_folders {
+        ArtifactTypeFolders::Disabled => package_id.to_string(),
+        ArtifactTypeFolders::Enabled => format!("{}/{}", package_id, artifact_type.folder_name()),
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct APIResponseD {
-    results: Vec<APIResponseResult>,
+/// Storage path of an artifact: extracted directory or `.zip` file.
+fn artifact_path(
+    package_id: &str,
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+) -> String {
+    let type_path = artifact_type_path(package_id, artifact_type, config);
+    match config.packages.zip_extraction {
+        ZipExtraction::Disabled => format!("{}/{}.zip", type_path, artifact_id),
+        ZipExtraction::Enabled => format!("{}/{}", type_path, artifact_id),
+    }
 }
-#[derive(Serialize, Deserialize, Debug)]
-struct APIResponseRoot {
-    d: APIResponseD,
+
+/// Local path of an artifact: extracted directory or `.zip` file.
+fn artifact_local_path(
+    package_id: &str,
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+    data_dir: &Path,
+) -> PathBuf {
+    data_dir.join(PathBuf::from_slash(artifact_path(
+        package_id,
+        artifact_id,
+        artifact_type,
+        config,
+    )))
 }
 
-// response types: token api
+/// Extracts the artifact ZIP in memory: path inside the ZIP and normalized content.
+fn extract_entries(
+    config: &Config,
+    respbytes_cursor: Cursor<&[u8]>,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let normalizer = normalize::Normalizer::new(&config.packages)?;
+    let mut archive = zip::ZipArchive::new(respbytes_cursor)?;
+    let mut entries = Vec::new();
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let outpath_str = file
+            .enclosed_name()
+            .ok_or(Error::Filesystem("enclosed_name".to_string()))?
+            .to_str()
+            .ok_or(Error::Filesystem("enclosed_name2".to_string()))?
+            .replace('\\', "/");
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TokenAPIResponseRoot {
-    access_token: String,
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        entries.push((outpath_str.clone(), normalizer.apply(&outpath_str, content)));
+    }
+    Ok(entries)
 }
 
 async fn write_artifact(
     package_id: &str,
     artifact_id: &str,
+    artifact_type: ArtifactType,
     config: &Config,
-    data_dir: &std::path::PathBuf,
-    mut respbytes_cursor: Cursor<&[u8]>,
-) -> Result<(), Error> {
+    storage: &dyn Storage,
+    respbytes_cursor: Cursor<&[u8]>,
+) -> Result<String, Error> {
+    let artifact_path = artifact_path(package_id, artifact_id, artifact_type, config);
+
     match config.packages.zip_extraction {
         ZipExtraction::Disabled => {
-            let write_dir = data_dir
-                .join(&package_id)
-                .join(artifact_id.to_string() + ".zip");
-
-            let parent_dir = write_dir
-                .parent()
-                .ok_or(Error::Filesystem("No parent found".to_string()))?;
-            fs::create_dir_all(parent_dir)?;
+            storage.put_file(&artifact_path, respbytes_cursor.get_ref())?;
 
-            let mut write_dir = fs::File::create(&write_dir)?;
-            std::io::copy(&mut respbytes_cursor, &mut write_dir)?;
+            Ok(content_hash(respbytes_cursor.get_ref()))
         }
         ZipExtraction::Enabled => {
-            let mut archive = zip::ZipArchive::new(respbytes_cursor)?;
-
-            for i in 0..archive.len() {
-                let mut file = archive.by_index(i)?;
-
-                let outpath_str = file
-                    .enclosed_name()
-                    .ok_or(Error::Filesystem("enclosed_name".to_string()))?
-                    .to_str()
-                    .ok_or(Error::Filesystem("enclosed_name2".to_string()))?;
-                let outpath: PathBuf = PathBuf::from_slash(outpath_str);
-
+            let entries = extract_entries(config, respbytes_cursor)?;
+            for (outpath_str, content) in entries.iter() {
                 // println!(
-                //     "data_dir: {:?} , package_id:{:?} , artifact_id: {:?}, outpath: {:?}",
-                //     &data_dir, &package_id, &artifact.id, &outpath
+                //     "package_id:{:?} , artifact_id: {:?}, outpath: {:?}",
+                //     &package_id, &artifact.id, &outpath_str
                 // );
-                let write_dir = data_dir.join(&package_id).join(artifact_id).join(outpath);
-                // println!("write_dir: {:?} ", &write_dir);
-
-                let parent_dir = write_dir
-                    .parent()
-                    .ok_or(Error::Filesystem("No parent found".to_string()))?;
-                fs::create_dir_all(parent_dir)?;
-                let mut write_dir = fs::File::create(&write_dir)?;
-
-                match config.packages.prop_comment_removal {
-                    PropCommentRemoval::Disabled => {
-                        std::io::copy(&mut file, &mut write_dir)?;
-                    }
-                    PropCommentRemoval::Enabled => {
-                        if outpath_str.ends_with("parameters.prop") {
-                            let mut prop_content = String::new();
-                            file.read_to_string(&mut prop_content)?;
-
-                            let prop_lines: Vec<&str> = prop_content
-                                .lines()
-                                .filter(|l| !l.starts_with("#"))
-                                .collect();
-
-                            for line in prop_lines {
-                                write_dir.write_all(line.as_bytes())?;
-
-                                write_dir.write_all(b"\n")?;
-                            }
-                        // write_dir.write_all(lines.as_bytes());
-                        } else {
-                            std::io::copy(&mut file, &mut write_dir)?;
-                        }
-                    }
-                }
+                storage.put_file(&format!("{}/{}", artifact_path, outpath_str), content)?;
             }
+
+            //zip timestamps change on every download, hash the written files instead
+            Ok(entries_hash(&entries))
         }
     }
-
-    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn download_artifact(
     package_id: String,
-    artifact_id: String,
+    artifact: Artifact,
     config: Config,
-    data_dir: std::path::PathBuf,
+    storage: Arc<dyn Storage>,
     client: reqwest::Client,
-    authorization: String,
-    artifact_type: String,
+    authorization: Authorization,
+    artifact_type: ArtifactType,
     ignore_error_download: bool,
-) -> Result<(), Error> {
+    previous: Option<ArtifactState>,
+) -> ArtifactSyncResult {
+    let now = tokio::time::Instant::now();
+    let mut report = ArtifactReport {
+        package_id: package_id.clone(),
+        artifact_id: artifact.id.clone(),
+        name: artifact.name.clone(),
+        artifact_type,
+        version: artifact.version.clone(),
+        status: ArtifactStatus::Downloaded,
+        http_status: None,
+        error: None,
+        body_excerpt: None,
+        bytes: None,
+        duration_ms: 0,
+    };
+
+    let artifact_id = artifact.id.clone();
+    let result = match sync_artifact(
+        &package_id,
+        artifact,
+        &config,
+        storage.as_ref(),
+        &client,
+        &authorization,
+        artifact_type,
+        previous,
+        &mut report,
+    )
+    .await
+    {
+        //configuration values can change without a new artifact version,
+        //they are fetched for skipped artifacts too
+        Ok(mut state) if artifact_type == ArtifactType::IntegrationFlow => {
+            configurations::write_configurations(
+                &package_id,
+                &artifact_id,
+                &config,
+                storage.as_ref(),
+                &client,
+                &authorization,
+                state.configurations_hash.as_deref(),
+            )
+            .await
+            .map(|configurations_hash| {
+                state.configurations_hash = configurations_hash;
+                state
+            })
+        }
+        result => result,
+    };
+
+    let state = match result {
+        Ok(state) => Some(state),
+        Err(err) => {
+            println!(
+                "- Artifact: {:#?} , from Package: {:#?} failed: {}",
+                artifact_id, package_id, err
+            );
+            if ignore_error_download {
+                println!("Ignoring error (Ignore Download Error Option: True)");
+            }
+            report.fail(&err);
+            None
+        }
+    };
+
+    report.duration_ms = now.elapsed().as_millis() as u64;
+    ArtifactSyncResult { state, report }
+}
+
+/// Downloads and writes a single artifact, returns its new sync state.
+#[allow(clippy::too_many_arguments)]
+async fn sync_artifact(
+    package_id: &str,
+    artifact: Artifact,
+    config: &Config,
+    storage: &dyn Storage,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    artifact_type: ArtifactType,
+    previous: Option<ArtifactState>,
+    report: &mut ArtifactReport,
+) -> Result<ArtifactState, Error> {
+    let artifact_id = artifact.id;
+    let artifact_path = artifact_path(package_id, &artifact_id, artifact_type, config);
+
+    //incremental sync: keep the local artifact if the tenant reports the same version
+    if let Some(previous) = previous {
+        if previous.is_unchanged(&artifact.version, &artifact.modified_date)
+            && !storage.list(&artifact_path)?.is_empty()
+        {
+            println!(
+                "- Artifact: {:#?} , from Package: {:#?} unchanged, skipping.",
+                artifact_id, package_id
+            );
+            report.status = ArtifactStatus::Skipped;
+            return Ok(previous);
+        }
+    }
+
     println!(
         "- Artifact: {:#?} , from Package: {:#?}",
         artifact_id, package_id
     );
 
+    let respbytes =
+        fetch_artifact(&artifact_id, artifact_type, config, client, authorization).await?;
+    report.http_status = Some(reqwest::StatusCode::OK.as_u16());
+    report.bytes = Some(respbytes.len() as u64);
+
+    let respbytes_cursor = Cursor::new(respbytes.deref());
+
+    storage.remove_tree(&artifact_path)?;
+    let written_hash = write_artifact(
+        package_id,
+        &artifact_id,
+        artifact_type,
+        config,
+        storage,
+        respbytes_cursor,
+    )
+    .await?;
+
+    Ok(ArtifactState {
+        artifact_type,
+        version: artifact.version,
+        modified_at: artifact.modified_date,
+        content_hash: written_hash,
+        configurations_hash: None,
+    })
+}
+
+/// Downloads the artifact ZIP, a failed request is an `Error::Api`.
+async fn fetch_artifact(
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<bytes::Bytes, Error> {
     let api_artifact_payload_url = format!(
-        "https://{host}/api/v1/{artifact_type}(Id='{artifact_id}',Version='Active')/$value",
-        host = config.tenant.management_host,
+        "{api}/{artifact_type}(Id='{artifact_id}',Version='Active')/$value",
+        api = config.tenant()?.api_url(),
         artifact_id = artifact_id,
-        artifact_type = artifact_type
+        artifact_type = artifact_type.api_name()
     );
-    let resp = client
-        .get(&api_artifact_payload_url)
-        .header("Authorization", authorization)
-        .send()
-        .await?;
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client.get(&api_artifact_payload_url),
+    )
+    .await?;
 
-    let resp_success = &resp.status().is_success();
     let resp_code = resp.status();
 
-    if !resp_success {
-        println!("Artifact Download Failed!");
-        println!("API URL: {}", &api_artifact_payload_url);
-        println!("API Response Code: {:#?}", &resp_code);
-    }
-    if !resp_success && ignore_error_download {
-        println!("Ignoring error (Ignore Download Error Option: True)");
-    }
-    if !resp_success && !ignore_error_download {
-        println!("Response Body:");
-        let body_text = resp.text().await?;
-        println!("{}", &body_text);
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "API Artifact Download Failed!",
-        )
-        .into());
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_artifact_payload_url,
+            status: resp_code.as_u16(),
+            body: resp.text().await?,
+        });
     }
 
-    if *resp_success {
-        let respbytes = resp.bytes().await?;
-        let respbytes_cursor = Cursor::new(respbytes.deref());
-
-        write_artifact(
-            &package_id,
-            &artifact_id,
-            &config,
-            &data_dir,
-            respbytes_cursor,
-        )
-        .await?;
-    }
-    Ok(())
+    Ok(resp.bytes().await?)
 }
 
-async fn process_package_artifacts(
+/// Artifacts of the given type in the package, `None` if the type is not available on the tenant.
+async fn list_available_artifacts(
     package_id: &str,
-    artifact_type: &str,
+    artifact_type: ArtifactType,
     config: &Config,
     client: &reqwest::Client,
-    authorization: &str,
-    data_dir: &std::path::PathBuf,
-    ignore_error_download: &bool,
-) -> Result<Vec<impl Future<Output = Result<(), Error>>>, Error> {
+    authorization: &Authorization,
+) -> Result<Option<Vec<Artifact>>, Error> {
     let api_package_artifact_list_url = format!(
-        "https://{host}/api/v1/IntegrationPackages('{package_id}')/{artifact_type}",
-        host = config.tenant.management_host,
+        "{api}/IntegrationPackages('{package_id}')/{artifact_type}",
+        api = config.tenant()?.api_url(),
         package_id = package_id,
-        artifact_type = artifact_type
+        artifact_type = artifact_type.api_name()
     );
-    let resp = client
-        .get(&api_package_artifact_list_url)
-        .header("Authorization", authorization)
-        .header("Accept", "application/json")
-        .send()
-        .await?;
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(&api_package_artifact_list_url)
+            .header("Accept", "application/json"),
+    )
+    .await?;
 
     let resp_success = &resp.status().is_success();
     let resp_code = resp.status();
 
     let body_text = resp.text().await?;
 
+    //older tenants may not provide all artifact types, a missing package is an error
+    if resp_code == reqwest::StatusCode::NOT_FOUND {
+        check_package_exists(package_id, config, client, authorization).await?;
+        return Ok(None);
+    }
+
     if !resp_success {
-        println!("API Package List Artifacts Failed!");
-        println!("Artifact type: {}", &artifact_type);
-        println!("API URL: {}", &api_package_artifact_list_url);
-        println!("API Response Code: {:#?}", &resp_code);
-        println!("Response Body:");
-        println!("{}", &body_text);
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "API Package List Artifacts Failed!",
-        )
-        .into());
+        return Err(Error::Api {
+            url: api_package_artifact_list_url,
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
     }
 
-    let resp_obj: APIResponseRoot = match serde_json::from_slice(body_text.as_bytes()) {
-        Ok(api_resp) => api_resp,
-        Err(err) => {
-            println!("API Package List Artifacts Parse Failed!");
-            println!("Artifact type: {}", &artifact_type);
-            println!("API URL: {}", &api_package_artifact_list_url);
-            println!("API Response Code: {:#?}", &resp_code);
-            println!("Response Body:");
-            println!("{}", &body_text);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, err).into());
-        }
-    };
+    let resp_obj: ODataResponse<Artifact> = serde_json::from_str(&body_text)
+        .map_err(|e| unexpected_response(&api_package_artifact_list_url, e))?;
+
+    Ok(Some(resp_obj.d.results))
+}
+
+/// `Error::Api` if the package doesn't exist on the tenant.
+async fn check_package_exists(
+    package_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<(), Error> {
+    let api_package_url = format!(
+        "{api}/IntegrationPackages('{package_id}')",
+        api = config.tenant()?.api_url(),
+        package_id = package_id
+    );
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(&api_package_url)
+            .header("Accept", "application/json"),
+    )
+    .await?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(Error::Api {
+            url: api_package_url,
+            status: status.as_u16(),
+            body: resp.text().await?,
+        });
+    }
+    Ok(())
+}
+
+/// Like `list_available_artifacts`, prints artifact types that are not available and skips them.
+async fn list_package_artifacts(
+    package_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<Artifact>, Error> {
+    let artifacts =
+        list_available_artifacts(package_id, artifact_type, config, client, authorization).await?;
+    Ok(artifacts.unwrap_or_else(|| {
+        println!(
+            "Artifact type is not available on the tenant, skipping: {}",
+            artifact_type.api_name()
+        );
+        Vec::new()
+    }))
+}
+
+/// Response body that doesn't match the expected OData format.
+fn unexpected_response(url: &str, err: serde_json::Error) -> Error {
+    Error::UnexpectedResponse {
+        url: url.to_string(),
+        message: err.to_string(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_package_artifacts(
+    package_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &Arc<dyn Storage>,
+    ignore_error_download: &bool,
+    previous: &Option<PackageState>,
+    listed_artifact_ids: &mut HashSet<String>,
+) -> Result<Vec<impl Future<Output = ArtifactSyncResult>>, Error> {
+    let resp_obj =
+        list_package_artifacts(package_id, artifact_type, config, client, authorization).await?;
 
     let mut tasks = Vec::new();
-    for artifact in resp_obj.d.results {
+    for artifact in resp_obj {
+        listed_artifact_ids.insert(artifact.id.clone());
+        let previous_artifact = previous
+            .as_ref()
+            .and_then(|p| p.artifacts.get(&artifact.id))
+            .cloned();
+
         tasks.push(download_artifact(
             package_id.to_owned(),
-            artifact.id.to_owned(),
+            artifact,
             config.clone(),
-            data_dir.clone(),
+            storage.clone(),
             client.clone(),
-            authorization.to_string(),
-            artifact_type.to_string(),
+            authorization.clone(),
+            artifact_type,
             *ignore_error_download,
+            previous_artifact,
         ));
     }
     Ok(tasks)
 }
 
+/// `previous` is the package state of the last run, only given for incremental sync.
 async fn process_package(
     package_id: &str,
     config: &Config,
     client: &reqwest::Client,
-    authorization: &str,
-    data_dir: &std::path::PathBuf,
+    authorization: &Authorization,
+    storage: &Arc<dyn Storage>,
     ignore_error_download: &bool,
-) -> Result<Vec<impl Future<Output = Result<(), Error>>>, Error> {
-    //remove local package contents before download
-    let package_dir = data_dir.join(&package_id);
-    remove_dir_all::ensure_empty_dir(&package_dir)?;
-    // let _ = fs::remove_dir_all(package_dir);
+    previous: Option<PackageState>,
+) -> Result<Vec<impl Future<Output = ArtifactSyncResult>>, Error> {
+    if previous.is_none() {
+        //remove local package contents before download
+        storage.remove_tree(package_id)?;
+    }
 
     println!("Processing Package: {:?}", package_id);
 
-    let mut tasks1 = process_package_artifacts(
-        package_id,
-        "IntegrationDesigntimeArtifacts",
-        config,
-        client,
-        authorization,
-        data_dir,
-        ignore_error_download,
-    )
-    .await?;
+    let mut tasks = Vec::new();
+    let mut listed_artifact_ids = HashSet::new();
+    for artifact_type in config.packages.artifact_types.iter() {
+        let mut type_tasks = process_package_artifacts(
+            package_id,
+            *artifact_type,
+            config,
+            client,
+            authorization,
+            storage,
+            ignore_error_download,
+            &previous,
+            &mut listed_artifact_ids,
+        )
+        .await?;
+        tasks.append(&mut type_tasks);
+    }
 
-    let mut tasks2 = process_package_artifacts(
-        package_id,
-        "ValueMappingDesigntimeArtifacts",
-        config,
-        client,
-        authorization,
-        data_dir,
-        ignore_error_download,
-    )
-    .await?;
+    //incremental sync: only remove artifacts that are deleted on the tenant
+    if let Some(previous) = &previous {
+        for (artifact_id, artifact_state) in previous.artifacts.iter() {
+            if listed_artifact_ids.contains(artifact_id)
+                || !config
+                    .packages
+                    .artifact_types
+                    .contains(&artifact_state.artifact_type)
+            {
+                continue;
+            }
+            println!(
+                "- Artifact: {:#?} , from Package: {:#?} deleted on tenant, removing.",
+                artifact_id, package_id
+            );
+            storage.remove_tree(&artifact_path(
+                package_id,
+                artifact_id,
+                artifact_state.artifact_type,
+                config,
+            ))?;
+            if artifact_state.artifact_type == ArtifactType::IntegrationFlow {
+                configurations::remove_configurations(
+                    package_id,
+                    artifact_id,
+                    config,
+                    storage.as_ref(),
+                )?;
+            }
+        }
+    }
 
-    tasks1.append(&mut tasks2);
-    Ok(tasks1)
+    Ok(tasks)
 }
 
 async fn get_all_packages(
     config: &Config,
     client: &reqwest::Client,
-    authorization: &str,
-) -> Result<APIResponseRoot, Error> {
+    authorization: &Authorization,
+) -> Result<Vec<Package>, Error> {
     let api_package_list_url = format!(
-        "https://{host}/api/v1/IntegrationPackages",
-        host = config.tenant.management_host
+        "{api}/IntegrationPackages",
+        api = config.tenant()?.api_url()
     );
-    let resp = client
-        .get(&api_package_list_url)
-        .header("Authorization", authorization)
-        .header("Accept", "application/json")
-        .send()
-        .await?;
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(&api_package_list_url)
+            .header("Accept", "application/json"),
+    )
+    .await?;
 
     let resp_success = &resp.status().is_success();
     let resp_code = resp.status();
@@ -329,200 +590,97 @@ async fn get_all_packages(
     let body_text = resp.text().await?;
 
     if !resp_success {
-        println!("Package List Failed!");
-        println!("API URL: {}", &api_package_list_url);
-        println!("API Response Code: {:#?}", &resp_code);
-        println!("Response Body:");
-        println!("{}", &body_text);
-        return Err(
-            std::io::Error::new(std::io::ErrorKind::Other, "API Package List  Failed!").into(),
-        );
+        return Err(Error::Api {
+            url: api_package_list_url,
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
     }
 
-    let resp_obj: APIResponseRoot = match serde_json::from_slice(body_text.as_bytes()) {
-        Ok(api_resp) => api_resp,
-        Err(err) => {
-            println!("Package List Failed!");
-            println!("API URL: {}", &api_package_list_url);
-            println!("API Response Code: {:#?}", &resp_code);
-            println!("Response Body:");
-            println!("{}", &body_text);
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, err).into());
-        }
-    };
-    //println!("{:?}", &resp_obj);
+    let resp_obj: ODataResponse<Package> = serde_json::from_str(&body_text)
+        .map_err(|e| unexpected_response(&api_package_list_url, e))?;
 
-    Ok(resp_obj)
+    Ok(resp_obj.d.results)
 }
 
-pub async fn run_with_config(
-    config: &Config,
-    config_path: &String,
-    no_input: bool,
-    ignore_error_download: bool,
-) -> Result<(), Error> {
-    //println!("config: {:?}", config);
-    //println!("Using input file: {:?}", opts);
-
-    // let mut authorization: Option<String> = None;
-
-    let mut password: Option<String> = None;
-
-    //get secret from environment variable
-    match &config.tenant.credential {
-        CredentialInside::SUser(c) => {
-            match &c.password_environment_variable {
-                Some(varkey) => {
-                    match env::var(varkey) {
-                        Ok(val) => {
-                            password = Some(val);
-                        }
-                        Err(e) => {
-                            println!(
-                                "Can not find S-user Pass in environment variable: {}: {}",
-                                &varkey, e
-                            );
-                            // return Err(e.into());
-                        }
-                    };
-                }
-                None => (),
-            };
-        }
-        CredentialInside::OauthClientCredentials(c) => {
-            match &c.client_secret_environment_variable {
-                Some(varkey) => {
-                    match env::var(varkey) {
-                        Ok(val) => {
-                            password = Some(val);
-                        }
-                        Err(e) => {
-                            println!(
-                                "Can not find Client Secret environment variable: {}: {}",
-                                &varkey, e
-                            );
-                        }
-                    };
-                }
-                None => (),
-            };
-        }
+/// Writes the file, readable only by the current user on Unix.
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    if let Some(parent_dir) = path.parent() {
+        fs::create_dir_all(parent_dir)?;
     }
-
-    let username: String = match &config.tenant.credential {
-        CredentialInside::OauthClientCredentials(c) => c.client_id.to_string(),
-        CredentialInside::SUser(c) => c.username.to_string(),
-    };
-    //try to get password from command line
-    if !no_input {
-        match &password {
-            None => {
-                let message = format!(
-                    "Would you like to enter a password for user: {user} to connect host: {host}?",
-                    user = username,
-                    host = config.tenant.management_host
-                );
-
-                println!("{}", message);
-
-                let pass = rpassword::prompt_password_stdout("Password: ")?;
-                password = Some(pass);
-                //println!("Your password is {}", pass);
-            }
-            _ => {}
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        //an existing file keeps its mode on open
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
         }
     }
-
-    let password: String = match password {
-        Some(p) => p,
-        None => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Could not use any password/secret",
-            )
-            .into())
-        }
-    };
-
-    run_with_config_and_password(
-        config,
-        config_path,
-        no_input,
-        ignore_error_download,
-        &password,
-    )
-    .await?;
-
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
     Ok(())
 }
 
-pub async fn run_with_config_and_password(
-    config: &Config,
-    config_path: &String,
-    _no_input: bool,
-    ignore_error_download: bool,
-
-    password: &String,
-) -> Result<(), Error> {
-    //println!("config: {:?}", config);
-    //println!("Using input file: {:?}", opts);
-
-    let now = tokio::time::Instant::now();
-
-    let client = reqwest::Client::new();
+/// Name from the tenant with characters that are not safe in file names replaced.
+fn safe_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
 
-    // let mut authorization: Option<String> = None;
+/// Creates the authorization for the tenant credential and checks API access,
+/// a rejected check is an `Error::Api`.
+async fn check_authorization(
+    config: &Config,
+    client: &reqwest::Client,
+    password: &str,
+) -> Result<Authorization, Error> {
+    let check_api_url = format!("{api}/", api = config.tenant()?.api_url());
 
-    // let username: String = match &config.tenant.credential {
-    //     CredentialInside::OauthClientCredentials(c) => c.client_id.to_string(),
-    //     CredentialInside::SUser(c) => c.username.to_string(),
-    // };
-    //try to get password from command line
+    //oauth tokens are fetched on the first request
+    let authorization = Authorization::new(config, client, password)?;
 
-    let check_api_url = format!(
-        "https://{host}/api/v1/",
-        host = &config.tenant.management_host
-    );
+    let resp = http::send(config, Some(&authorization), client.get(&check_api_url)).await?;
 
-    //for oauth we need to get the token
-    let authorization = match &config.tenant.credential {
-        CredentialInside::OauthClientCredentials(c) => {
-            let api_token_url = format!(
-                "{url}?grant_type=client_credentials",
-                url = c.token_endpoint_url
-            );
-            let auth = basic_auth(&c.client_id, &password);
+    let resp_code = resp.status();
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: check_api_url,
+            status: resp_code.as_u16(),
+            body: resp.text().await?,
+        });
+    }
 
-            let resp = client
-                .post(&api_token_url)
-                .header("Authorization", auth)
-                .send()
-                .await?;
-            println!("Token API status: {:?}", resp.status());
-            let respbody = resp.json::<TokenAPIResponseRoot>().await?;
+    Ok(authorization)
+}
 
-            format!("Bearer {token}", token = respbody.access_token)
+/// Like `check_authorization`, prints the result of the check.
+async fn get_authorization(
+    config: &Config,
+    client: &reqwest::Client,
+    password: &str,
+) -> Result<Authorization, Error> {
+    match check_authorization(config, client, password).await {
+        Ok(authorization) => {
+            println!("API First Check Successful.");
+            Ok(authorization)
+        }
+        Err(err) => {
+            println!("API First Check Failed!");
+            Err(err)
         }
-        CredentialInside::SUser(c) => basic_auth(&c.username, &password),
-    };
-
-    let resp = client
-        .get(&check_api_url)
-        .header("Authorization", &authorization)
-        .send()
-        .await?;
-
-    let resp_success = &resp.status().is_success();
-    let resp_code = resp.status();
-
-    if !resp_success {
-        println!("API First Check Failed!");
-        println!("API Response Code: {:#?}", resp_code);
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "API Check Failed!").into());
-    } else {
-        println!("API First Check Successful.");
     }
+}
 
+/// Resolves `local_dir` against the config file location.
+/// With `create` the directory is created if it doesn't exist.
+async fn get_data_dir(config: &Config, config_path: &str, create: bool) -> Result<PathBuf, Error> {
     //https://doc.rust-lang.org/std/fs/fn.canonicalize.html
 
     let normalized_localdir = normalize_path(Path::new(&config.packages.local_dir));
@@ -537,15 +695,22 @@ pub async fn run_with_config_and_password(
     //localdir can be relative or absolute
     data_dir.push(normalized_localdir);
 
+    if !create && !data_dir.exists() {
+        return Ok(data_dir);
+    }
+
     tokio::fs::create_dir_all(&data_dir).await?;
     //UNC paths for long windows paths over 260 chars
     data_dir = data_dir.canonicalize()?;
 
-    let api_package_list = get_all_packages(&config, &client, &authorization).await?;
+    Ok(data_dir)
+}
 
+/// Applies `filter_rules` to the tenant package list and returns the selected package IDs.
+fn select_packages(config: &Config, api_package_list: &[Package]) -> Result<Vec<String>, Error> {
     let mut api_package_set: HashSet<String> = HashSet::new();
     let mut api_package_name_map: HashMap<String, String> = HashMap::new();
-    for package in api_package_list.d.results.iter() {
+    for package in api_package_list.iter() {
         api_package_set.insert(package.id.to_string());
         match api_package_name_map.entry(package.name.to_string()) {
             std::collections::hash_map::Entry::Occupied(mut e) => {
@@ -566,7 +731,7 @@ pub async fn run_with_config_and_password(
                 let re = Regex::new(&rule.pattern)?;
 
                 for p in &api_package_set {
-                    if re.is_match(&p) {
+                    if re.is_match(p) {
                         rule_package_set.insert(p.clone());
                     }
                 }
@@ -590,21 +755,14 @@ pub async fn run_with_config_and_password(
                 if !api_package_set.contains(&rule.id) {
                     println!("Package ID not found: {}", &rule.id);
 
-                    match api_package_name_map.get(&rule.id) {
-                        Some(id_for_name) => {
-                            println!(
-                                "Did you enter the Package name instead of this Package ID?: '{}'",
-                                id_for_name
-                            );
-                        }
-                        None => {}
+                    if let Some(id_for_name) = api_package_name_map.get(&rule.id) {
+                        println!(
+                            "Did you enter the Package name instead of this Package ID?: '{}'",
+                            id_for_name
+                        );
                     }
 
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Package ID not found!",
-                    )
-                    .into());
+                    return Err(std::io::Error::other("Package ID not found!").into());
                 }
 
                 match rule.operation {
@@ -619,26 +777,24 @@ pub async fn run_with_config_and_password(
         }
     }
 
-    let package_list: Vec<String> = Vec::from_iter(operating_package_set);
-
-    println!("Downloading These Packages:");
-    println!("{:?}", &package_list);
+    Ok(Vec::from_iter(operating_package_set))
+}
 
+/// Runs tasks with at most `worker_count` in flight, returning the first error (fail fast).
+async fn try_run_pooled<T, F>(
+    tasks: impl IntoIterator<Item = F>,
+    worker_count: usize,
+) -> Result<Vec<T>, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
     let mut futs = FuturesUnordered::new();
     let mut outputs = Vec::new();
 
-    //fetch package artifacts
-    for package_id in package_list.iter() {
-        futs.push(process_package(
-            package_id,
-            &config,
-            &client,
-            &authorization,
-            &data_dir,
-            &ignore_error_download,
-        ));
+    for task in tasks {
+        futs.push(task);
 
-        if futs.len() >= config.packages.download_worker_count {
+        if futs.len() >= worker_count {
             //fail fast
             outputs.push(
                 futs.next()
@@ -652,44 +808,430 @@ pub async fn run_with_config_and_password(
         outputs.push(item?);
     }
 
-    let mut futs2 = FuturesUnordered::new();
-    let mut artifact_results = Vec::new();
+    Ok(outputs)
+}
+
+/// Runs tasks with at most `worker_count` in flight and collects every result.
+async fn run_pooled<T, F>(tasks: impl IntoIterator<Item = F>, worker_count: usize) -> Vec<T>
+where
+    F: Future<Output = T>,
+{
+    let mut futs = FuturesUnordered::new();
+    let mut results = Vec::new();
+
+    for task in tasks {
+        futs.push(task);
+
+        if futs.len() >= worker_count {
+            if let Some(item) = futs.next().await {
+                results.push(item);
+            }
+        }
+    }
+    // wait for remaining
+    while let Some(item) = futs.next().await {
+        results.push(item);
+    }
+
+    results
+}
+
+/// Collects the secrets of the selected tenants first, so prompts don't overlap,
+/// then runs `run` for all tenants concurrently. Returns the result per tenant name, with its config.
+async fn run_for_tenants_results<T, F, Fut>(
+    config: &Config,
+    selection: &TenantSelection,
+    credentials: &CredentialInput,
+    run: F,
+) -> Result<Vec<(Option<String>, Config, Result<T, Error>)>, Error>
+where
+    F: Fn(Config, String) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let mut tenant_runs = Vec::new();
+    for (tenant_name, tenant_config) in config.tenant_configs(selection)? {
+        let password = get_password(&tenant_config, credentials)?;
+        tenant_runs.push((tenant_name, tenant_config, password));
+    }
+
+    let results = futures::future::join_all(tenant_runs.into_iter().map(
+        |(tenant_name, tenant_config, password)| {
+            let fut = run(tenant_config.clone(), password);
+            async move { (tenant_name, tenant_config, fut.await) }
+        },
+    ))
+    .await;
+    Ok(results)
+}
+
+/// Like `run_for_tenants_results`, but fails if any tenant failed.
+async fn run_for_tenants<T, F, Fut>(
+    config: &Config,
+    selection: &TenantSelection,
+    credentials: &CredentialInput,
+    run: F,
+) -> Result<Vec<(Option<String>, T)>, Error>
+where
+    F: Fn(Config, String) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let results = run_for_tenants_results(config, selection, credentials, run).await?;
+    tenant_outputs(results.into_iter().map(|(name, _, result)| (name, result)))
+}
+
+/// Outputs of the tenants that succeeded, `None` for a run without named tenants.
+type TenantOutputs<T> = Vec<(Option<String>, T)>;
+
+/// Splits tenant results into outputs and the names of the failed tenants,
+/// prints the outcome of each named tenant. The error of an unnamed tenant is returned.
+fn split_tenant_results<T>(
+    results: impl IntoIterator<Item = (Option<String>, Result<T, Error>)>,
+) -> Result<(TenantOutputs<T>, Vec<String>), Error> {
+    let mut outputs = Vec::new();
+    let mut failed_tenants = Vec::new();
+    for (tenant_name, result) in results {
+        match (tenant_name, result) {
+            (None, result) => outputs.push((None, result?)),
+            (Some(name), Ok(output)) => {
+                println!("Tenant completed: {}", name);
+                outputs.push((Some(name), output));
+            }
+            (Some(name), Err(err)) => {
+                println!("Tenant failed: {}: {}", name, err);
+                failed_tenants.push(name);
+            }
+        }
+    }
+    Ok((outputs, failed_tenants))
+}
+
+/// Like `split_tenant_results`, failed tenants are an `Error::TenantsFailed` naming them.
+fn tenant_outputs<T>(
+    results: impl IntoIterator<Item = (Option<String>, Result<T, Error>)>,
+) -> Result<TenantOutputs<T>, Error> {
+    let (outputs, failed_tenants) = split_tenant_results(results)?;
+    if !failed_tenants.is_empty() {
+        return Err(Error::TenantsFailed(failed_tenants));
+    }
+    Ok(outputs)
+}
+
+pub async fn run_with_tenants(
+    config: &Config,
+    config_path: &str,
+    credentials: &CredentialInput,
+    ignore_error_download: bool,
+    selection: &TenantSelection,
+    report_path: Option<&str>,
+) -> Result<(), Error> {
+    let started_at = chrono::Utc::now().to_rfc3339();
+    let now = tokio::time::Instant::now();
+
+    let results = run_for_tenants_results(
+        config,
+        selection,
+        credentials,
+        |tenant_config, password| async move {
+            sync_with_config_and_password(
+                &tenant_config,
+                config_path,
+                ignore_error_download,
+                &password,
+            )
+            .await
+        },
+    )
+    .await?;
+
+    if let Some(report_path) = report_path {
+        let tenant_reports = results
+            .iter()
+            .map(|(tenant_name, tenant_config, result)| {
+                let mut tenant_report = match result {
+                    Ok(tenant_report) => tenant_report.clone(),
+                    Err(err) => TenantReport {
+                        management_host: tenant_config
+                            .tenant()
+                            .map(|t| t.management_host.clone())
+                            .unwrap_or_default(),
+                        error: Some(err.to_string()),
+                        ..Default::default()
+                    },
+                };
+                tenant_report.tenant = tenant_name.clone();
+                tenant_report
+            })
+            .collect();
+        RunReport::new(started_at, now.elapsed().as_millis() as u64, tenant_reports)
+            .write(report_path)?;
+    }
+
+    //failed downloads fail the tenant after the report is written, the tenant is not committed
+    let (outputs, failed_tenants) = split_tenant_results(results.into_iter().map(
+        |(tenant_name, tenant_config, result)| {
+            let result = result.and_then(|tenant_report| {
+                check_failed_downloads(&tenant_report, ignore_error_download)?;
+                Ok((tenant_config, tenant_report))
+            });
+            (tenant_name, result)
+        },
+    ))?;
+
+    //commits run one after the other, tenants can share a repository
+    for (tenant_name, (tenant_config, tenant_report)) in outputs.iter() {
+        git::commit_sync(tenant_config, tenant_name.as_deref(), tenant_report)?;
+    }
+    let data_dirs: Vec<&Path> = outputs
+        .iter()
+        .map(|(_, (_, tenant_report))| tenant_report.local_dir.as_path())
+        .collect();
+    git::push_sync(config, &data_dirs)?;
+
+    //the other tenants are committed and pushed first
+    if !failed_tenants.is_empty() {
+        return Err(Error::TenantsFailed(failed_tenants));
+    }
+    Ok(())
+}
+
+pub async fn run_with_config(
+    config: &Config,
+    config_path: &str,
+    no_input: bool,
+    ignore_error_download: bool,
+) -> Result<(), Error> {
+    //println!("config: {:?}", config);
+    //println!("Using input file: {:?}", opts);
+
+    //loads a service key credential
+    let (_, config) = config.tenant_configs(&TenantSelection::Default)?.remove(0);
+    let config = &config;
+    let password = get_password(config, &CredentialInput::new(no_input, None, None))?;
+
+    run_with_config_and_password(
+        config,
+        config_path,
+        no_input,
+        ignore_error_download,
+        &password,
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn run_with_config_and_password(
+    config: &Config,
+    config_path: &str,
+    _no_input: bool,
+    ignore_error_download: bool,
+
+    password: &str,
+) -> Result<(), Error> {
+    //println!("config: {:?}", config);
+    //println!("Using input file: {:?}", opts);
+
+    let tenant_report =
+        sync_with_config_and_password(config, config_path, ignore_error_download, password).await?;
+    check_failed_downloads(&tenant_report, ignore_error_download)?;
+
+    git::commit_sync(config, None, &tenant_report)?;
+    git::push_sync(config, &[&tenant_report.local_dir])?;
+
+    Ok(())
+}
+
+/// Downloads the selected packages of a single tenant into `local_dir`, returns the report of the run.
+/// Archive output formats write a single snapshot file instead of the directory tree.
+async fn sync_with_config_and_password(
+    config: &Config,
+    config_path: &str,
+    ignore_error_download: bool,
+    password: &str,
+) -> Result<TenantReport, Error> {
+    let data_dir = get_data_dir(config, config_path, true).await?;
+
+    let mut tenant_report = match config.output.format {
+        OutputFormat::Directory => {
+            let storage = Arc::new(FileSystemStorage::new(&data_dir));
+            sync_to_storage(config, storage, ignore_error_download, password).await?
+        }
+        OutputFormat::TarGz | OutputFormat::Zip => {
+            let storage = Arc::new(snapshot::ArchiveStorage::create(config, &data_dir)?);
+            let mut tenant_report =
+                sync_to_storage(config, storage.clone(), ignore_error_download, password).await?;
+            tenant_report.snapshot = Some(storage.finish(config, &tenant_report)?);
+            tenant_report
+        }
+    };
+    tenant_report.local_dir = data_dir;
+    Ok(tenant_report)
+}
+
+/// Downloads the selected packages of a single tenant into the given storage,
+/// e.g. a `MemoryStorage`. `local_dir` and the git options are not used.
+/// Failed downloads return an error unless `ignore_error_download` is set.
+pub async fn sync_with_storage(
+    config: &Config,
+    password: &str,
+    storage: Arc<dyn Storage>,
+    ignore_error_download: bool,
+) -> Result<(), Error> {
+    let tenant_report = sync_to_storage(config, storage, ignore_error_download, password).await?;
+    check_failed_downloads(&tenant_report, ignore_error_download)
+}
+
+/// Failed artifact downloads and package exports fail the run, unless they are ignored.
+fn check_failed_downloads(
+    tenant_report: &TenantReport,
+    ignore_error_download: bool,
+) -> Result<(), Error> {
+    let failed = tenant_report.failed_downloads();
+    if failed > 0 && !ignore_error_download {
+        return Err(std::io::Error::other(format!(
+            "Failed downloads: {}, use --ignore-error-download to ignore them",
+            failed
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+async fn sync_to_storage(
+    config: &Config,
+    storage: Arc<dyn Storage>,
+    ignore_error_download: bool,
+    password: &str,
+) -> Result<TenantReport, Error> {
+    let now = tokio::time::Instant::now();
+
+    let client = http::client_builder(config)?.build()?;
+
+    let authorization = get_authorization(config, &client, password).await?;
+
+    //invalid normalization patterns fail before any download
+    normalize::Normalizer::new(&config.packages)?;
+
+    let mut sync_state = SyncState::load(storage.as_ref())?;
+    let incremental = sync_state.is_incremental(&config.packages)?;
+
+    let api_package_list = get_all_packages(config, &client, &authorization).await?;
+
+    let mut package_list = select_packages(config, &api_package_list)?;
+    package_list.sort();
+
+    println!("Downloading These Packages:");
+    println!("{:?}", &package_list);
+
+    //fetch package artifacts
+    let outputs = try_run_pooled(
+        package_list.iter().map(|package_id| {
+            let previous = match incremental {
+                true => Some(
+                    sync_state
+                        .packages
+                        .get(package_id)
+                        .cloned()
+                        .unwrap_or_default(),
+                ),
+                false => None,
+            };
+            process_package(
+                package_id,
+                config,
+                &client,
+                &authorization,
+                &storage,
+                &ignore_error_download,
+                previous,
+            )
+        }),
+        config.packages.download_worker_count,
+    )
+    .await?;
 
     // let mut outputs2 = outputs.into_iter().flatten().collect::<Vec<_>>();
     // outputs2.shuffle(&mut thread_rng());
     // for task in outputs2.into_iter() {
-    for task in outputs.into_iter().flatten() {
-        // task.await;
-        futs2.push(task);
+    let artifact_results = run_pooled(
+        outputs.into_iter().flatten(),
+        config.packages.download_worker_count,
+    )
+    .await;
 
-        if futs2.len() >= config.packages.download_worker_count {
-            //fail fast
-            artifact_results.push(
-                futs2
-                    .next()
-                    .await
-                    .ok_or(Error::Filesystem("Futures #1".to_string()))?,
-            );
-        }
+    //failed artifacts are left out of the state, so they are downloaded again next time
+    for package_id in package_list.iter() {
+        sync_state
+            .packages
+            .insert(package_id.clone(), PackageState::default());
     }
-
-    // wait for remaining
-    while let Some(item) = futs2.next().await {
-        artifact_results.push(item);
+    let mut artifact_reports = Vec::new();
+    for result in artifact_results {
+        if let Some(state) = result.state {
+            sync_state
+                .packages
+                .entry(result.report.package_id.clone())
+                .or_default()
+                .artifacts
+                .insert(result.report.artifact_id.clone(), state);
+        }
+        artifact_reports.push(result.report);
     }
+    sync_state.settings_hash = Some(state::settings_hash(&config.packages)?);
+    sync_state.save(storage.as_ref())?;
 
     println!(
         "Download time elapsed in seconds: {}",
         now.elapsed().as_secs()
     );
 
-    Ok(())
-}
+    let mut tenant_report = TenantReport {
+        management_host: config.tenant()?.management_host.clone(),
+        selected_packages: package_list,
+        elapsed_ms: now.elapsed().as_millis() as u64,
+        ..Default::default()
+    };
+    tenant_report.add_artifacts(artifact_reports);
+    if tenant_report.failed > 0 {
+        println!("Failed artifact downloads: {}", tenant_report.failed);
+    }
+
+    package::write_package_metadata(
+        storage.as_ref(),
+        &tenant_report.selected_packages,
+        &api_package_list,
+        &tenant_report.artifacts,
+    )?;
+
+    if matches!(config.packages.package_export, PackageExport::Enabled) {
+        tenant_report.package_exports = package::export_packages(
+            config,
+            &client,
+            &authorization,
+            storage.as_ref(),
+            &tenant_report.selected_packages,
+        )
+        .await?;
+    }
+
+    if !config.tenant_content.is_empty() {
+        tenant_content::write_tenant_content(config, &client, &authorization, storage.as_ref())
+            .await?;
+    }
+
+    if matches!(config.packages.runtime_status, RuntimeStatus::Enabled) {
+        tenant_report.version_drift = runtime::write_runtime_status(
+            config,
+            &client,
+            &authorization,
+            storage.as_ref(),
+            &tenant_report.selected_packages,
+            &tenant_report.artifacts,
+        )
+        .await?;
+    }
 
-fn basic_auth(user: &str, pass: &str) -> String {
-    let encoded = base64::encode(format!("{username}:{pass}", username = &user, pass = &pass));
-    let authorization = format!("Basic {encoded}", encoded = encoded);
-    authorization
+    Ok(tenant_report)
 }
 
 fn normalize_path(path: &Path) -> PathBuf {
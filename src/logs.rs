@@ -0,0 +1,573 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::credentials::CredentialInput;
+use crate::errors::Error;
+use crate::runtime::odata_date;
+use crate::{
+    get_authorization, get_data_dir, http, run_for_tenants, safe_file_name, unexpected_response,
+};
+
+use serde_json::{Map, Value};
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_LOG_DIR_NAME: &str = "logs";
+const ATTACHMENT_DIR_NAME: &str = "attachments";
+const LOG_PAGE_SIZE: usize = 100;
+
+/// Filters and options of the `logs` command.
+#[derive(Debug, Clone, Default)]
+pub struct LogQuery {
+    pub iflow: Option<String>,
+    // e.g. FAILED, COMPLETED, RETRY
+    pub status: Option<String>,
+    pub correlation_id: Option<String>,
+    // RFC 3339, compared with the log end time
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub max_results: Option<usize>,
+    pub attachments: bool,
+    pub error_details: bool,
+    // relative to the tenant `local_dir`, default: `logs`
+    pub output_dir: Option<String>,
+}
+
+/// OData string literal, quotes are doubled.
+fn odata_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// OData datetime literal in UTC from an RFC 3339 timestamp.
+fn odata_datetime(option: &str, value: &str) -> Result<String, Error> {
+    let datetime = chrono::DateTime::parse_from_rfc3339(value).map_err(|e| {
+        Error::Config(format!(
+            "Invalid --{} timestamp, expected RFC 3339 like 2024-01-31T12:00:00Z: {}: {}",
+            option, value, e
+        ))
+    })?;
+    Ok(format!(
+        "datetime'{}'",
+        datetime
+            .with_timezone(&chrono::Utc)
+            .format("%Y-%m-%dT%H:%M:%S")
+    ))
+}
+
+fn log_filter(query: &LogQuery) -> Result<Option<String>, Error> {
+    let mut conditions = Vec::new();
+    if let Some(iflow) = &query.iflow {
+        conditions.push(format!("IntegrationFlowName eq {}", odata_string(iflow)));
+    }
+    if let Some(status) = &query.status {
+        conditions.push(format!("Status eq {}", odata_string(status)));
+    }
+    if let Some(correlation_id) = &query.correlation_id {
+        conditions.push(format!("CorrelationId eq {}", odata_string(correlation_id)));
+    }
+    if let Some(from) = &query.from {
+        conditions.push(format!("LogEnd ge {}", odata_datetime("from", from)?));
+    }
+    if let Some(to) = &query.to {
+        conditions.push(format!("LogEnd le {}", odata_datetime("to", to)?));
+    }
+    match conditions.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(conditions.join(" and "))),
+    }
+}
+
+/// Entries of an OData collection, a response without `d.results` is an `Error::UnexpectedResponse`.
+async fn get_results(
+    url: &str,
+    query: &[(&str, String)],
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<Value>, Error> {
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(url)
+            .query(query)
+            .header("Accept", "application/json"),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    let body_text = resp.text().await?;
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: url.to_string(),
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
+    }
+    let resp_obj: Value =
+        serde_json::from_str(&body_text).map_err(|e| unexpected_response(url, e))?;
+    match resp_obj.pointer("/d/results") {
+        Some(Value::Array(results)) => Ok(results.clone()),
+        _ => Err(Error::UnexpectedResponse {
+            url: url.to_string(),
+            message: "no d.results".to_string(),
+        }),
+    }
+}
+
+/// Log properties without OData metadata and navigation links, dates as RFC 3339.
+fn log_properties(entry: &Value) -> Map<String, Value> {
+    let mut properties = Map::new();
+    if let Value::Object(entry) = entry {
+        for (key, value) in entry.iter() {
+            match value {
+                Value::Object(_) => continue,
+                Value::String(s) => {
+                    properties.insert(key.clone(), Value::String(odata_date(s)));
+                }
+                _ => {
+                    properties.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+    properties
+}
+
+/// Pages through `MessageProcessingLogs`, newest first.
+/// Messages that end while paging shift the pages, a message seen twice is kept once.
+async fn list_logs(
+    query: &LogQuery,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<Value>, Error> {
+    if query.max_results == Some(0) {
+        return Ok(Vec::new());
+    }
+    let api_logs_url = format!(
+        "{api}/MessageProcessingLogs",
+        api = config.tenant()?.api_url()
+    );
+    let filter = log_filter(query)?;
+
+    let mut logs = Vec::new();
+    let mut message_guids = HashSet::new();
+    let mut skip = 0;
+    loop {
+        let page_size = match query.max_results {
+            Some(max) => LOG_PAGE_SIZE.min(max - logs.len()),
+            None => LOG_PAGE_SIZE,
+        };
+        let mut params = vec![
+            ("$orderby", "LogEnd desc".to_string()),
+            ("$top", page_size.to_string()),
+            ("$skip", skip.to_string()),
+        ];
+        if let Some(filter) = &filter {
+            params.push(("$filter", filter.clone()));
+        }
+
+        let page = get_results(&api_logs_url, &params, config, client, authorization).await?;
+        let last_page = page.len() < page_size;
+        skip += page.len();
+        for log in page {
+            match log["MessageGuid"].as_str() {
+                Some(guid) if !message_guids.insert(guid.to_string()) => {}
+                _ => logs.push(log),
+            }
+        }
+        println!("Message processing logs: {}", logs.len());
+
+        if last_page || query.max_results == Some(logs.len()) {
+            return Ok(logs);
+        }
+    }
+}
+
+/// Error details of a failed message, `None` if the message has none (404).
+async fn get_error_information(
+    message_guid: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Option<String>, Error> {
+    let api_error_url = format!(
+        "{api}/MessageProcessingLogs('{guid}')/ErrorInformation/$value",
+        api = config.tenant()?.api_url(),
+        guid = message_guid
+    );
+    let resp = http::send(config, Some(authorization), client.get(&api_error_url)).await?;
+    let resp_code = resp.status();
+    if resp_code == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_error_url,
+            status: resp_code.as_u16(),
+            body: resp.text().await?,
+        });
+    }
+    Ok(Some(resp.text().await?))
+}
+
+/// Writes the attachments of a message into `attachments/<message guid>`,
+/// returns their metadata with the local file name.
+async fn write_attachments(
+    message_guid: &str,
+    attachment_dir: &Path,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<Value>, Error> {
+    let api = config.tenant()?.api_url();
+    let api_attachments_url = format!(
+        "{api}/MessageProcessingLogs('{guid}')/Attachments",
+        api = api,
+        guid = message_guid
+    );
+    let attachments = get_results(&api_attachments_url, &[], config, client, authorization).await?;
+
+    let message_dir = attachment_dir.join(safe_file_name(message_guid));
+    let mut written = Vec::new();
+    for (index, attachment) in attachments.iter().enumerate() {
+        let mut properties = log_properties(attachment);
+        let attachment_id = match attachment.get("Id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => continue,
+        };
+        let api_attachment_url = format!(
+            "{api}/MessageProcessingLogAttachments({id})/$value",
+            api = api,
+            id = odata_string(attachment_id)
+        );
+        let resp = http::send(config, Some(authorization), client.get(&api_attachment_url)).await?;
+        let resp_code = resp.status();
+        if !resp_code.is_success() {
+            return Err(Error::Api {
+                url: api_attachment_url,
+                status: resp_code.as_u16(),
+                body: resp.text().await?,
+            });
+        }
+
+        //names are not unique within a message
+        let name = attachment
+            .get("Name")
+            .and_then(|v| v.as_str())
+            .unwrap_or("attachment");
+        let file_name = format!("{:02}_{}", index + 1, safe_file_name(name));
+        fs::create_dir_all(&message_dir)?;
+        fs::write(message_dir.join(&file_name), resp.bytes().await?)?;
+
+        properties.insert(
+            "File".to_string(),
+            Value::String(format!("{}/{}", safe_file_name(message_guid), file_name)),
+        );
+        written.push(Value::Object(properties));
+    }
+    Ok(written)
+}
+
+/// Queries message processing logs of the tenant and writes them as JSON lines,
+/// with optional error details and attachments.
+pub async fn logs_with_config_and_password(
+    config: &Config,
+    config_path: &str,
+    password: &str,
+    query: &LogQuery,
+) -> Result<PathBuf, Error> {
+    let client = http::client_builder(config)?.build()?;
+    let authorization = get_authorization(config, &client, password).await?;
+
+    let data_dir = get_data_dir(config, config_path, true).await?;
+    let log_dir = data_dir.join(query.output_dir.as_deref().unwrap_or(DEFAULT_LOG_DIR_NAME));
+    fs::create_dir_all(&log_dir)?;
+    let log_path = log_dir.join(format!(
+        "message-processing-logs_{}.jsonl",
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+
+    let logs = list_logs(query, config, &client, &authorization).await?;
+
+    let mut log_file = fs::File::create(&log_path)?;
+    for log in logs.iter() {
+        let mut properties = log_properties(log);
+        let message_guid = log
+            .get("MessageGuid")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+
+        //failed, retried and escalated messages have error details
+        let completed = log.get("Status").and_then(|v| v.as_str()) == Some("COMPLETED");
+        if query.error_details && !completed && !message_guid.is_empty() {
+            if let Some(error_information) =
+                get_error_information(message_guid, config, &client, &authorization).await?
+            {
+                properties.insert(
+                    "ErrorInformation".to_string(),
+                    Value::String(error_information),
+                );
+            }
+        }
+        if query.attachments && !message_guid.is_empty() {
+            let attachments = write_attachments(
+                message_guid,
+                &log_dir.join(ATTACHMENT_DIR_NAME),
+                config,
+                &client,
+                &authorization,
+            )
+            .await?;
+            properties.insert("Attachments".to_string(), Value::Array(attachments));
+        }
+
+        writeln!(log_file, "{}", serde_json::to_string(&properties)?)?;
+    }
+
+    println!(
+        "Message processing logs written: {} to {}",
+        logs.len(),
+        log_path.display()
+    );
+    Ok(log_path)
+}
+
+pub async fn logs_with_tenants(
+    config: &Config,
+    config_path: &str,
+    credentials: &CredentialInput,
+    selection: &TenantSelection,
+    query: &LogQuery,
+) -> Result<(), Error> {
+    run_for_tenants(
+        config,
+        selection,
+        credentials,
+        |tenant_config, password| async move {
+            logs_with_config_and_password(&tenant_config, config_path, &password, query).await
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const LOGS_PATH: &str = "/api/v1/MessageProcessingLogs";
+
+    fn tenant_config(server: &MockServer) -> Config {
+        test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    fn connect(config: &Config) -> (reqwest::Client, Authorization) {
+        let client = http::client_builder(config).unwrap().build().unwrap();
+        let authorization = Authorization::new(config, &client, "secret").unwrap();
+        (client, authorization)
+    }
+
+    /// Answers the log page with `$top` and `$skip` with `count` logs.
+    async fn mount_page(server: &MockServer, top: usize, skip: usize, count: usize) {
+        let logs: Vec<Value> = (skip..skip + count)
+            .map(|i| json!({ "MessageGuid": format!("m{}", i), "Status": "COMPLETED" }))
+            .collect();
+        Mock::given(method("GET"))
+            .and(path(LOGS_PATH))
+            .and(query_param("$top", top.to_string()))
+            .and(query_param("$skip", skip.to_string()))
+            .and(query_param("$orderby", "LogEnd desc"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "d": { "results": logs } })),
+            )
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[test]
+    fn odata_literals() {
+        assert_eq!(odata_string("Flow"), "'Flow'");
+        assert_eq!(odata_string("it's"), "'it''s'");
+        assert_eq!(
+            odata_datetime("from", "2024-01-31T12:00:00+02:00").unwrap(),
+            "datetime'2024-01-31T10:00:00'"
+        );
+        assert!(matches!(
+            odata_datetime("to", "2024-01-31"),
+            Err(Error::Config(message)) if message.contains("--to")
+        ));
+    }
+
+    #[test]
+    fn filter_combines_the_query_options() {
+        assert_eq!(log_filter(&LogQuery::default()).unwrap(), None);
+
+        let query = LogQuery {
+            iflow: Some("Order's Flow".to_string()),
+            status: Some("FAILED".to_string()),
+            correlation_id: Some("c1".to_string()),
+            from: Some("2024-01-01T00:00:00Z".to_string()),
+            to: Some("2024-01-02T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            log_filter(&query).unwrap().unwrap(),
+            "IntegrationFlowName eq 'Order''s Flow' and Status eq 'FAILED' and CorrelationId eq 'c1' \
+             and LogEnd ge datetime'2024-01-01T00:00:00' and LogEnd le datetime'2024-01-02T00:00:00'"
+        );
+    }
+
+    #[tokio::test]
+    async fn logs_are_paged_until_a_short_page() {
+        let server = MockServer::start().await;
+        mount_page(&server, 100, 0, 100).await;
+        mount_page(&server, 100, 100, 20).await;
+        let config = tenant_config(&server);
+        let (client, authorization) = connect(&config);
+
+        let logs = list_logs(&LogQuery::default(), &config, &client, &authorization)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 120);
+        assert_eq!(logs[119]["MessageGuid"], "m119");
+    }
+
+    #[tokio::test]
+    async fn max_results_limits_the_last_page() {
+        let server = MockServer::start().await;
+        mount_page(&server, 100, 0, 100).await;
+        mount_page(&server, 30, 100, 30).await;
+        let config = tenant_config(&server);
+        let (client, authorization) = connect(&config);
+
+        let query = LogQuery {
+            max_results: Some(130),
+            ..Default::default()
+        };
+        let logs = list_logs(&query, &config, &client, &authorization)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 130);
+    }
+
+    #[tokio::test]
+    async fn zero_max_results_sends_no_request() {
+        let server = MockServer::start().await;
+        let config = tenant_config(&server);
+        let (client, authorization) = connect(&config);
+
+        let query = LogQuery {
+            max_results: Some(0),
+            ..Default::default()
+        };
+        let logs = list_logs(&query, &config, &client, &authorization)
+            .await
+            .unwrap();
+        assert!(logs.is_empty());
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn message_shifted_into_the_next_page_is_kept_once() {
+        let server = MockServer::start().await;
+        mount_page(&server, 100, 0, 100).await;
+        //a new message ended after the first page, m99 is returned again
+        let logs: Vec<Value> = (99..119)
+            .map(|i| json!({ "MessageGuid": format!("m{}", i), "Status": "COMPLETED" }))
+            .collect();
+        Mock::given(method("GET"))
+            .and(path(LOGS_PATH))
+            .and(query_param("$skip", "100"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "d": { "results": logs } })),
+            )
+            .mount(&server)
+            .await;
+        let config = tenant_config(&server);
+        let (client, authorization) = connect(&config);
+
+        let logs = list_logs(&LogQuery::default(), &config, &client, &authorization)
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 119);
+        assert_eq!(logs[99]["MessageGuid"], "m99");
+        assert_eq!(logs[118]["MessageGuid"], "m118");
+    }
+
+    #[tokio::test]
+    async fn missing_error_information_is_none_other_failures_are_errors() {
+        let server = MockServer::start().await;
+        for (guid, response) in [
+            (
+                "m1",
+                ResponseTemplate::new(200).set_body_string("Mapping failed"),
+            ),
+            ("m2", ResponseTemplate::new(404)),
+            ("m3", ResponseTemplate::new(500)),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "{}('{}')/ErrorInformation/$value",
+                    LOGS_PATH, guid
+                )))
+                .respond_with(response)
+                .mount(&server)
+                .await;
+        }
+        let config = tenant_config(&server);
+        let (client, authorization) = connect(&config);
+
+        assert_eq!(
+            get_error_information("m1", &config, &client, &authorization)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("Mapping failed")
+        );
+        assert_eq!(
+            get_error_information("m2", &config, &client, &authorization)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            get_error_information("m3", &config, &client, &authorization).await,
+            Err(Error::Api { status: 500, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_log_page_is_an_unexpected_response() {
+        for body in [
+            json!({ "d": {} }).to_string(),
+            "<html>Login</html>".to_string(),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path(LOGS_PATH))
+                .respond_with(ResponseTemplate::new(200).set_body_string(body))
+                .mount(&server)
+                .await;
+            let config = tenant_config(&server);
+            let (client, authorization) = connect(&config);
+
+            assert!(matches!(
+                list_logs(&LogQuery::default(), &config, &client, &authorization).await,
+                Err(Error::UnexpectedResponse { url, .. }) if url.ends_with("/MessageProcessingLogs")
+            ));
+        }
+    }
+}
@@ -1,49 +1,200 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use cpi_sync::errors::Error;
+use cpi_sync::{CredentialInput, TenantSelection};
 
 use crossterm::event::{read, Event};
 use jsonschema::{self, Draft, JSONSchema};
 use serde_json::{self, Value};
-use std::{fs::File, io::Read};
+use std::{fs::File, io::Read, path::PathBuf};
 
 //config types
 
 //cli type
 #[derive(Parser, Debug)]
-#[clap(version = "0.3.1", author = "Fatih.Pense @ pizug.com")]
+#[clap(version, author = "Fatih.Pense @ pizug.com")]
 struct Opts {
-    #[clap(short, long, default_value = "./cpi-sync.json")]
+    #[clap(short, long, global = true, default_value = "./cpi-sync.json")]
     config: String,
-    #[clap(long, help = "Disable features that require user input")]
+    #[clap(long, global = true, help = "Disable features that require user input")]
     no_input: bool,
-    #[clap(long, help = "Ignore errors for downloading artifacts")]
+    #[clap(long, global = true, help = "Ignore errors for downloading artifacts")]
     ignore_error_download: bool,
+    #[clap(
+        long,
+        global = true,
+        multiple_occurrences = true,
+        help = "Tenant name from the `tenants` config, can be given multiple times"
+    )]
+    tenant: Vec<String>,
+    #[clap(
+        long,
+        global = true,
+        conflicts_with = "tenant",
+        help = "Run for all tenants from the `tenants` config"
+    )]
+    all_tenants: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Read the tenant secret from stdin, used for tenants without another secret source"
+    )]
+    secret_stdin: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Credential vault file, default: `cpisync/credentials.vault.json` in the user config directory"
+    )]
+    vault: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Show which packages and artifacts a sync would download, without changing local files"
+    )]
+    dry_run: bool,
+    #[clap(
+        long,
+        requires = "dry-run",
+        help = "Write the dry run plan as JSON to this file"
+    )]
+    plan_output: Option<String>,
+    #[clap(
+        long,
+        conflicts_with = "dry-run",
+        help = "Write a JSON report of the sync run to this file"
+    )]
+    report: Option<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[clap(about = "Download packages from the tenant (default)")]
+    Pull,
+    #[clap(about = "Upload local artifacts of the selected packages to the tenant")]
+    Push,
+    #[clap(about = "Compare the selected packages of two tenants from the `tenants` config")]
+    Diff {
+        #[clap(help = "Left tenant name")]
+        left: String,
+        #[clap(help = "Right tenant name")]
+        right: String,
+        #[clap(long, help = "Write the report to a file instead of the console")]
+        output: Option<String>,
+    },
+    #[clap(about = "Check that the local files of the selected packages match the tenant")]
+    Verify {
+        #[clap(long, help = "Write the verification result as JSON to this file")]
+        output: Option<String>,
+    },
+    #[clap(about = "Export message processing logs of the tenant as JSON lines")]
+    Logs {
+        #[clap(long, help = "Integration Flow name")]
+        iflow: Option<String>,
+        #[clap(long, help = "Message status, e.g. FAILED, COMPLETED, RETRY")]
+        status: Option<String>,
+        #[clap(long, help = "Correlation ID")]
+        correlation_id: Option<String>,
+        #[clap(long, help = "Logs that ended at or after this time, RFC 3339")]
+        from: Option<String>,
+        #[clap(long, help = "Logs that ended at or before this time, RFC 3339")]
+        to: Option<String>,
+        #[clap(long, help = "Maximum number of logs, newest first")]
+        max_results: Option<usize>,
+        #[clap(long, help = "Download the message attachments")]
+        attachments: bool,
+        #[clap(long, help = "Add the error details of messages that did not complete")]
+        error_details: bool,
+        #[clap(
+            long,
+            help = "Output folder, relative paths are under the tenant `local_dir`. Default: logs"
+        )]
+        output_dir: Option<String>,
+    },
+    #[clap(subcommand, about = "Manage secrets in the encrypted credential vault")]
+    Credentials(CredentialsCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum CredentialsCommand {
+    #[clap(about = "Store the secret of the selected tenants")]
+    Add,
+    #[clap(about = "Remove the secret of the selected tenants")]
+    Remove,
+    #[clap(about = "List the vault entries, without secrets")]
+    List,
 }
 
 fn pause() -> Result<(), Error> {
     println!("Press any key to continue...");
     loop {
         // `read()` blocks until an `Event` is available
-        match read()? {
-            Event::Key(_) => {
-                // println!("{:?}", event);
-                break;
-            }
-            _ => {}
+        if let Event::Key(_) = read()? {
+            // println!("{:?}", event);
+            break;
         }
     }
     Ok(())
 }
 
-async fn run_console(opts: &Opts) -> Result<(), Error> {
-    println!("Start CPI Sync?");
-    if !opts.no_input {
-        pause()?;
+/// Reads the whole stdin, a trailing line break is not part of the secret.
+fn read_secret_stdin() -> Result<String, Error> {
+    let mut secret = String::new();
+    std::io::stdin().read_to_string(&mut secret)?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// `--dry-run`, `--plan-output` and `--report` only apply to a sync, other commands would ignore them.
+/// JSON schema of the config file.
+fn config_schema() -> Result<Value, Error> {
+    Ok(serde_json::from_str(include_str!(
+        "../resources/config.schema.json"
+    ))?)
+}
+
+fn check_sync_options(opts: &Opts) -> Result<(), Error> {
+    if matches!(opts.command, None | Some(Command::Pull)) {
+        return Ok(());
     }
+    let sync_options = [
+        ("--dry-run", opts.dry_run),
+        ("--plan-output", opts.plan_output.is_some()),
+        ("--report", opts.report.is_some()),
+    ];
+    for (option, is_set) in sync_options {
+        if is_set {
+            return Err(Error::Config(format!(
+                "{} can only be used with pull",
+                option
+            )));
+        }
+    }
+    Ok(())
+}
 
-    let schema_str = include_str!("../resources/config.schema.json");
-    let json_schema: Value = serde_json::from_str(schema_str)?;
+#[allow(clippy::needless_return)]
+async fn run_console(opts: &Opts) -> Result<(), Error> {
+    check_sync_options(opts)?;
+
+    let secret_stdin = match opts.secret_stdin {
+        true => Some(read_secret_stdin()?),
+        false => None,
+    };
+    let credentials = CredentialInput::new(opts.no_input, secret_stdin, opts.vault.clone());
+
+    match opts.command {
+        Some(Command::Credentials(CredentialsCommand::List)) => {
+            return cpi_sync::credentials_list(&credentials);
+        }
+        Some(Command::Credentials(_)) => {}
+        _ => {
+            println!("Start CPI Sync?");
+            if !opts.no_input {
+                pause()?;
+            }
+        }
+    }
 
+    let json_schema = config_schema()?;
     let compiled_schema = JSONSchema::options()
         .with_draft(Draft::Draft7)
         .compile(&json_schema)?;
@@ -60,22 +211,116 @@ async fn run_console(opts: &Opts) -> Result<(), Error> {
         for error in errors {
             println!("Validation error: {}", error);
         }
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "JSON Schema validation error.",
-        )
-        .into());
+        return Err(std::io::Error::other("JSON Schema validation error.").into());
     }
 
     let config: cpi_sync::Config = serde_json::from_str(&config_str)?;
+    cpi_sync::set_print_progress(true);
 
-    return cpi_sync::run_with_config(
-        &config,
-        &opts.config,
-        opts.no_input,
-        opts.ignore_error_download,
-    )
-    .await;
+    let tenant_selection = if opts.all_tenants {
+        TenantSelection::All
+    } else if !opts.tenant.is_empty() {
+        TenantSelection::Named(opts.tenant.clone())
+    } else {
+        TenantSelection::Default
+    };
+
+    match opts.command {
+        None | Some(Command::Pull) if opts.dry_run => {
+            return cpi_sync::dry_run_with_tenants(
+                &config,
+                &opts.config,
+                &credentials,
+                &tenant_selection,
+                opts.plan_output.as_deref(),
+            )
+            .await;
+        }
+        None | Some(Command::Pull) => {
+            return cpi_sync::run_with_tenants(
+                &config,
+                &opts.config,
+                &credentials,
+                opts.ignore_error_download,
+                &tenant_selection,
+                opts.report.as_deref(),
+            )
+            .await;
+        }
+        Some(Command::Push) => {
+            return cpi_sync::push_with_tenants(
+                &config,
+                &opts.config,
+                &credentials,
+                &tenant_selection,
+            )
+            .await;
+        }
+        Some(Command::Diff {
+            ref left,
+            ref right,
+            ref output,
+        }) => {
+            return cpi_sync::diff_with_tenants(
+                &config,
+                &credentials,
+                left,
+                right,
+                output.as_deref(),
+            )
+            .await;
+        }
+        Some(Command::Verify { ref output }) => {
+            return cpi_sync::verify_with_tenants(
+                &config,
+                &opts.config,
+                &credentials,
+                &tenant_selection,
+                output.as_deref(),
+            )
+            .await;
+        }
+        Some(Command::Logs {
+            ref iflow,
+            ref status,
+            ref correlation_id,
+            ref from,
+            ref to,
+            max_results,
+            attachments,
+            error_details,
+            ref output_dir,
+        }) => {
+            let query = cpi_sync::LogQuery {
+                iflow: iflow.clone(),
+                status: status.clone(),
+                correlation_id: correlation_id.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                max_results,
+                attachments,
+                error_details,
+                output_dir: output_dir.clone(),
+            };
+            return cpi_sync::logs_with_tenants(
+                &config,
+                &opts.config,
+                &credentials,
+                &tenant_selection,
+                &query,
+            )
+            .await;
+        }
+        Some(Command::Credentials(CredentialsCommand::Add)) => {
+            return cpi_sync::credentials_add(&config, &tenant_selection, &credentials);
+        }
+        Some(Command::Credentials(CredentialsCommand::Remove)) => {
+            return cpi_sync::credentials_remove(&config, &tenant_selection, &credentials);
+        }
+        Some(Command::Credentials(CredentialsCommand::List)) => {
+            return cpi_sync::credentials_list(&credentials);
+        }
+    }
 }
 
 #[allow(clippy::needless_return)]
@@ -101,3 +346,102 @@ async fn main() -> Result<(), Error> {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Opts {
+        Opts::try_parse_from(args).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dry_run_push_is_rejected() {
+        //fails before the config is read or the tenant is contacted
+        let opts = parse(&["cpi-sync", "--no-input", "--dry-run", "push"]);
+        match run_console(&opts).await {
+            Err(Error::Config(message)) => {
+                assert_eq!(message, "--dry-run can only be used with pull")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sync_options_need_pull() {
+        for args in [
+            vec!["cpi-sync", "--report", "r.json", "verify"],
+            vec!["cpi-sync", "--dry-run", "--plan-output", "p.json", "logs"],
+            vec!["cpi-sync", "--report", "r.json", "diff", "DEV", "PROD"],
+        ] {
+            assert!(matches!(
+                check_sync_options(&parse(&args)),
+                Err(Error::Config(_))
+            ));
+        }
+        for args in [
+            vec!["cpi-sync", "--dry-run"],
+            vec!["cpi-sync", "--dry-run", "--plan-output", "p.json", "pull"],
+            vec!["cpi-sync", "--report", "r.json"],
+            vec!["cpi-sync", "push"],
+        ] {
+            assert!(check_sync_options(&parse(&args)).is_ok());
+        }
+    }
+
+    #[test]
+    fn dot_tenant_names_fail_the_schema() {
+        let schema = config_schema().unwrap();
+        let compiled_schema = JSONSchema::options()
+            .with_draft(Draft::Draft7)
+            .compile(&schema)
+            .unwrap();
+        let tenant = serde_json::json!({
+            "management_host": "tenant.example.com",
+            "credential": { "s_user": { "username": "S1" } }
+        });
+        for (name, valid) in [
+            ("DEV", true),
+            ("dev.eu-1", true),
+            (".", false),
+            ("..", false),
+        ] {
+            let config = serde_json::json!({
+                "cpisync": cpi_sync::CONFIG_VERSION,
+                "tenants": { name: tenant },
+                "packages": { "filter_rules": [] }
+            });
+            assert_eq!(compiled_schema.is_valid(&config), valid, "{}", name);
+        }
+    }
+
+    #[test]
+    fn no_proxy_without_proxy_fails_the_schema() {
+        let schema = config_schema().unwrap();
+        let compiled_schema = JSONSchema::options()
+            .with_draft(Draft::Draft7)
+            .compile(&schema)
+            .unwrap();
+        for (http, valid) in [
+            (serde_json::json!({ "no_proxy": ["localhost"] }), false),
+            (
+                serde_json::json!({
+                    "proxy": "http://proxy.example.com:8080",
+                    "no_proxy": ["localhost"]
+                }),
+                true,
+            ),
+        ] {
+            let config = serde_json::json!({
+                "cpisync": cpi_sync::CONFIG_VERSION,
+                "tenant": {
+                    "management_host": "tenant.example.com",
+                    "credential": { "s_user": { "username": "S1" } }
+                },
+                "packages": { "filter_rules": [] },
+                "http": http
+            });
+            assert_eq!(compiled_schema.is_valid(&config), valid, "{}", http);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use crate::config::*;
+use crate::errors::Error;
+
+use quick_xml::events::{BytesStart, Event};
+use regex::Regex;
+use std::borrow::Cow;
+
+// comment lines of parameters.prop contain the export timestamp
+const PROP_COMMENT_GLOB: &str = "parameters.prop";
+const PROP_COMMENT_PATTERN: &str = "^#";
+const XML_INDENT_SIZE: usize = 2;
+
+enum Step {
+    StripLines(Regex),
+    LineEndings,
+    Xml { sort_attributes: bool },
+    SortProperties,
+}
+
+struct Rule {
+    glob: Regex,
+    // globs without a `/` match the file name in any folder
+    match_file_name: bool,
+    steps: Vec<Step>,
+}
+
+/// Content normalization of extracted artifact files, from `normalization` and `prop_comment_removal`.
+/// Each file goes through the steps of all matching rules, in config order.
+pub struct Normalizer {
+    rules: Vec<Rule>,
+}
+
+/// Regex of a glob: `**` matches any path, `*` and `?` don't match `/`.
+fn glob_regex(glob: &str) -> Result<Regex, Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                //`**/` also matches no folder
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    pattern.push_str("(?:.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+        .map_err(|e| Error::Config(format!("Invalid normalization glob: {}: {}", glob, e)))
+}
+
+fn compile_step(step: &NormalizationStep) -> Result<Step, Error> {
+    Ok(match step {
+        NormalizationStep::StripLines(strip_lines) => {
+            Step::StripLines(Regex::new(&strip_lines.pattern).map_err(|e| {
+                Error::Config(format!(
+                    "Invalid normalization pattern: {}: {}",
+                    strip_lines.pattern, e
+                ))
+            })?)
+        }
+        NormalizationStep::LineEndings => Step::LineEndings,
+        NormalizationStep::Xml(xml) => Step::Xml {
+            sort_attributes: matches!(xml.sort_attributes, XmlSortAttributes::Enabled),
+        },
+        NormalizationStep::SortProperties => Step::SortProperties,
+    })
+}
+
+fn strip_lines(text: &str, pattern: &Regex) -> String {
+    let mut result = String::new();
+    for line in text.lines().filter(|l| !pattern.is_match(l)) {
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Comment or blank line of a `.properties` file.
+fn is_property_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#') || line.starts_with('!')
+}
+
+/// Line ends with an odd number of backslashes, the entry continues on the next line.
+fn continues_property(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+/// Key of a `.properties` entry, up to the first unescaped separator.
+fn property_key(entry: &str) -> String {
+    let mut key = String::new();
+    let mut chars = entry.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                key.push(c);
+                if let Some(escaped) = chars.next() {
+                    key.push(escaped);
+                }
+            }
+            '=' | ':' | ' ' | '\t' => break,
+            c => key.push(c),
+        }
+    }
+    key
+}
+
+/// Sorts the entries by key, comments stay with the entry that follows them.
+/// Comments at the start and at the end stay in place, blank lines between entries are dropped.
+fn sort_properties(text: &str) -> String {
+    let mut lines = text.lines().peekable();
+
+    let mut header = Vec::new();
+    while let Some(line) = lines.next_if(|l| is_property_comment(l)) {
+        header.push(line);
+    }
+
+    let mut entries: Vec<(String, Vec<&str>)> = Vec::new();
+    let mut pending = Vec::new();
+    while let Some(line) = lines.next() {
+        if is_property_comment(line) {
+            if !line.trim().is_empty() {
+                pending.push(line);
+            }
+            continue;
+        }
+        let key = property_key(line);
+        let mut entry_lines = std::mem::take(&mut pending);
+        entry_lines.push(line);
+        let mut last = line;
+        while continues_property(last) {
+            match lines.next() {
+                Some(next) => {
+                    entry_lines.push(next);
+                    last = next;
+                }
+                None => break,
+            }
+        }
+        entries.push((key, entry_lines));
+    }
+    //stable, entries with the same key keep their order
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut result = String::new();
+    let entry_lines = entries.iter().flat_map(|(_, lines)| lines.iter());
+    for line in header.iter().chain(entry_lines).chain(pending.iter()) {
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+/// Start tag with the attributes sorted by name.
+fn sort_attributes(start: &BytesStart) -> Result<BytesStart<'static>, quick_xml::Error> {
+    let name = std::str::from_utf8(start.name().as_ref())?.to_string();
+    let mut attributes = Vec::new();
+    for attribute in start.attributes() {
+        let attribute = attribute?;
+        let key = attribute.key.as_ref().to_vec();
+        //single quoted values can contain double quotes
+        let value = String::from_utf8(attribute.value.to_vec())?.replace('"', "&quot;");
+        attributes.push((key, value));
+    }
+    attributes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut sorted = BytesStart::new(name);
+    for (key, value) in attributes.iter() {
+        sorted.push_attribute((key.as_slice(), value.as_bytes()));
+    }
+    Ok(sorted)
+}
+
+/// Whitespace text with a line break, only formatting, e.g. a value of a single space is kept.
+fn is_formatting(text: &[u8]) -> bool {
+    text.contains(&b'\n') && text.iter().all(|b| b.is_ascii_whitespace())
+}
+
+/// Indents the elements, line breaks and indentation between elements are dropped.
+/// Text, CDATA and comments are written unchanged, whitespace next to CDATA is kept.
+fn normalize_xml(text: &str, sort: bool) -> Result<String, quick_xml::Error> {
+    let mut reader = quick_xml::Reader::from_str(text);
+    let mut writer = quick_xml::Writer::new_with_indent(Vec::new(), b' ', XML_INDENT_SIZE);
+    //formatting text is only written if a CDATA section follows
+    let mut pending_text = None;
+    let mut after_cdata = false;
+    loop {
+        let event = match reader.read_event()? {
+            Event::Eof => break,
+            Event::Text(t) if is_formatting(&t) && !after_cdata => {
+                pending_text = Some(t.into_owned());
+                continue;
+            }
+            Event::Start(start) if sort => Event::Start(sort_attributes(&start)?),
+            Event::Empty(start) if sort => Event::Empty(sort_attributes(&start)?),
+            event => event,
+        };
+        if let Some(t) = pending_text.take() {
+            if matches!(event, Event::CData(_)) {
+                writer.write_event(Event::Text(t))?;
+            }
+        }
+        after_cdata = matches!(event, Event::CData(_));
+        writer.write_event(event)?;
+    }
+    let mut result = String::from_utf8(writer.into_inner())?;
+    result.push('\n');
+    Ok(result)
+}
+
+impl Normalizer {
+    pub fn new(packages: &Packages) -> Result<Normalizer, Error> {
+        let mut rules = Vec::new();
+        if let PropCommentRemoval::Enabled = packages.prop_comment_removal {
+            rules.push(Rule {
+                glob: glob_regex(PROP_COMMENT_GLOB)?,
+                match_file_name: true,
+                steps: vec![Step::StripLines(Regex::new(PROP_COMMENT_PATTERN)?)],
+            });
+        }
+        for rule in packages.normalization.iter() {
+            rules.push(Rule {
+                glob: glob_regex(&rule.glob)?,
+                match_file_name: !rule.glob.contains('/'),
+                steps: rule
+                    .steps
+                    .iter()
+                    .map(compile_step)
+                    .collect::<Result<Vec<_>, _>>()?,
+            });
+        }
+        Ok(Normalizer { rules })
+    }
+
+    /// Normalized content of a file, `path` is relative to the artifact root.
+    /// Files that are not UTF-8 are not changed.
+    pub fn apply(&self, path: &str, content: Vec<u8>) -> Vec<u8> {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        let steps: Vec<&Step> = self
+            .rules
+            .iter()
+            .filter(|r| match r.match_file_name {
+                true => r.glob.is_match(file_name),
+                false => r.glob.is_match(path),
+            })
+            .flat_map(|r| r.steps.iter())
+            .collect();
+        if steps.is_empty() {
+            return content;
+        }
+
+        let mut text: Cow<str> = match std::str::from_utf8(&content) {
+            Ok(text) => Cow::Borrowed(text),
+            Err(_) => return content,
+        };
+        for step in steps {
+            text = match step {
+                Step::StripLines(pattern) => Cow::Owned(strip_lines(&text, pattern)),
+                Step::LineEndings => Cow::Owned(normalize_line_endings(&text)),
+                Step::SortProperties => Cow::Owned(sort_properties(&text)),
+                Step::Xml { sort_attributes } => match normalize_xml(&text, *sort_attributes) {
+                    Ok(xml) => Cow::Owned(xml),
+                    Err(err) => {
+                        println!("XML normalization skipped: {}: {}", path, err);
+                        text
+                    }
+                },
+            };
+        }
+        text.into_owned().into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_paths() {
+        let cases = [
+            ("*.xsd", "a.xsd", true),
+            ("*.xsd", "src/a.xsd", false),
+            ("src/**/*.xsd", "src/a.xsd", true),
+            ("src/**/*.xsd", "src/main/resources/a.xsd", true),
+            ("src/**", "src/main/a.groovy", true),
+            ("src/*.xsd", "src/main/a.xsd", false),
+            ("a?.prop", "ab.prop", true),
+            ("a?.prop", "a/.prop", false),
+            ("a.prop", "aXprop", false),
+            ("[x].prop", "[x].prop", true),
+        ];
+        for (glob, path, matches) in cases {
+            assert_eq!(
+                glob_regex(glob).unwrap().is_match(path),
+                matches,
+                "{} {}",
+                glob,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn strip_lines_removes_matching_lines() {
+        let pattern = Regex::new("^#").unwrap();
+        let text = "#Mon Jan 01\r\nA=1\n # kept\nB=2";
+        let stripped = strip_lines(text, &pattern);
+        assert_eq!(stripped, "A=1\n # kept\nB=2\n");
+        assert_eq!(strip_lines(&stripped, &pattern), stripped);
+    }
+
+    #[test]
+    fn sort_properties_keeps_comments_and_continuations() {
+        let text = "# header\nc=3\n\n# about b\nb=2 \\\n  continued\na\\=x:1\n# trailer\n";
+        let sorted = sort_properties(text);
+        assert_eq!(
+            sorted,
+            "# header\na\\=x:1\n# about b\nb=2 \\\n  continued\nc=3\n# trailer\n"
+        );
+        assert_eq!(sort_properties(&sorted), sorted);
+    }
+
+    #[test]
+    fn normalize_xml_indents_and_sorts_attributes() {
+        let text = "<?xml version=\"1.0\"?>\n<root b='\"x\"' a=\"1\"><e>  </e>\n\n    <f z=\"1\" y=\"2\"/><!-- c --></root>";
+        let normalized = normalize_xml(text, true).unwrap();
+        assert_eq!(
+            normalized,
+            "<?xml version=\"1.0\"?>\n<root a=\"1\" b=\"&quot;x&quot;\">\n  <e>  </e>\n  <f y=\"2\" z=\"1\"/>\n  <!-- c -->\n</root>\n"
+        );
+        assert_eq!(normalize_xml(&normalized, true).unwrap(), normalized);
+
+        let unsorted = normalize_xml(text, false).unwrap();
+        assert!(unsorted.contains("<f z=\"1\" y=\"2\"/>"));
+    }
+
+    #[test]
+    fn normalize_xml_keeps_whitespace_next_to_cdata() {
+        let text = "<root><e/>\r\n<![CDATA[ a < b ]]>\r\n</root>";
+        let normalized = normalize_xml(text, true).unwrap();
+        assert!(
+            normalized.contains("<e/>\r\n<![CDATA[ a < b ]]>\r\n</root>"),
+            "{:?}",
+            normalized
+        );
+        assert_eq!(normalize_xml(&normalized, true).unwrap(), normalized);
+    }
+}
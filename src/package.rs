@@ -0,0 +1,353 @@
+use crate::auth::Authorization;
+use crate::client::Package;
+use crate::config::*;
+use crate::configurations::configurations_path;
+use crate::errors::Error;
+use crate::report::{ArtifactReport, PackageExportReport};
+use crate::runtime::RUNTIME_STATUS_FILE_NAME;
+use crate::storage::Storage;
+use crate::{http, run_pooled};
+
+use serde::Serialize;
+
+pub const PACKAGE_METADATA_FILE_NAME: &str = "package.json";
+// outside of the package directories, so exports are kept by a full sync
+pub const PACKAGE_EXPORT_DIR_NAME: &str = "package-exports";
+// sorts by time, e.g. 20240131T235959Z
+const PACKAGE_EXPORT_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+#[derive(Serialize, Debug)]
+struct PackageArtifact {
+    artifact_id: String,
+    name: String,
+    artifact_type: ArtifactType,
+    version: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct PackageMetadata {
+    id: String,
+    name: String,
+    version: Option<String>,
+    vendor: Option<String>,
+    description: Option<String>,
+    short_text: Option<String>,
+    mode: Option<String>,
+    keywords: Option<String>,
+    supported_platform: Option<String>,
+    products: Option<String>,
+    industries: Option<String>,
+    line_of_business: Option<String>,
+    countries: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<String>,
+    modified_by: Option<String>,
+    modified_date: Option<String>,
+    // artifacts of the package on the tenant, sorted by type and id
+    artifacts: Vec<PackageArtifact>,
+}
+
+/// Files a sync writes into the package directory next to the artifacts: `package.json`,
+/// `runtime-status.json` and the configurations of the given iFlows, as configured.
+pub fn package_files(
+    package_id: &str,
+    integration_flow_ids: &[&str],
+    config: &Config,
+) -> Vec<String> {
+    let mut files = vec![format!("{}/{}", package_id, PACKAGE_METADATA_FILE_NAME)];
+    if matches!(config.packages.runtime_status, RuntimeStatus::Enabled) {
+        files.push(format!("{}/{}", package_id, RUNTIME_STATUS_FILE_NAME));
+    }
+    files.extend(integration_flow_ids.iter().filter_map(|artifact_id| {
+        configurations_path(
+            package_id,
+            artifact_id,
+            &config.packages.configuration_export,
+            config,
+        )
+    }));
+    files
+}
+
+/// Writes `package.json` into each package directory with the package metadata
+/// and the artifacts of the package with their versions.
+pub fn write_package_metadata(
+    storage: &dyn Storage,
+    package_list: &[String],
+    api_package_list: &[Package],
+    artifacts: &[ArtifactReport],
+) -> Result<(), Error> {
+    for package in api_package_list
+        .iter()
+        .filter(|p| package_list.contains(&p.id))
+    {
+        let mut package_artifacts: Vec<PackageArtifact> = artifacts
+            .iter()
+            .filter(|a| a.package_id == package.id)
+            .map(|a| PackageArtifact {
+                artifact_id: a.artifact_id.clone(),
+                name: a.name.clone(),
+                artifact_type: a.artifact_type,
+                version: a.version.clone(),
+            })
+            .collect();
+        package_artifacts.sort_by(|a, b| {
+            (a.artifact_type.api_name(), &a.artifact_id)
+                .cmp(&(b.artifact_type.api_name(), &b.artifact_id))
+        });
+
+        let metadata = PackageMetadata {
+            id: package.id.clone(),
+            name: package.name.clone(),
+            version: package.version.clone(),
+            vendor: package.vendor.clone(),
+            description: package.description.clone(),
+            short_text: package.short_text.clone(),
+            mode: package.mode.clone(),
+            keywords: package.keywords.clone(),
+            supported_platform: package.supported_platform.clone(),
+            products: package.products.clone(),
+            industries: package.industries.clone(),
+            line_of_business: package.line_of_business.clone(),
+            countries: package.countries.clone(),
+            created_by: package.created_by.clone(),
+            creation_date: package.creation_date.clone(),
+            modified_by: package.modified_by.clone(),
+            modified_date: package.modified_date.clone(),
+            artifacts: package_artifacts,
+        };
+
+        storage.put_file(
+            &format!("{}/{}", package.id, PACKAGE_METADATA_FILE_NAME),
+            (serde_json::to_string_pretty(&metadata)? + "\n").as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+async fn download_package_export(
+    package_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<u8>, Error> {
+    let api_package_export_url = format!(
+        "{api}/IntegrationPackages('{package_id}')/$value",
+        api = config.tenant()?.api_url(),
+        package_id = package_id
+    );
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client.get(&api_package_export_url),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_package_export_url,
+            status: resp_code.as_u16(),
+            body: resp.text().await?,
+        });
+    }
+    Ok(resp.bytes().await?.to_vec())
+}
+
+/// Timestamp part of `<package_id>_<timestamp>.zip`, `None` for other files.
+fn export_timestamp<'a>(file_name: &'a str, package_id: &str) -> Option<&'a str> {
+    file_name
+        .strip_prefix(package_id)
+        .and_then(|n| n.strip_prefix('_'))
+        .and_then(|n| n.strip_suffix(".zip"))
+        .filter(|t| {
+            chrono::NaiveDateTime::parse_from_str(t, PACKAGE_EXPORT_TIMESTAMP_FORMAT).is_ok()
+        })
+}
+
+/// Timestamped exports of the package that exceed `retention`, oldest first.
+pub fn expired_exports(
+    storage: &dyn Storage,
+    package_id: &str,
+    retention: usize,
+) -> Result<Vec<String>, Error> {
+    let mut exports: Vec<(String, String)> = Vec::new();
+    for path in storage.list(PACKAGE_EXPORT_DIR_NAME)? {
+        let timestamp = path
+            .rsplit('/')
+            .next()
+            .and_then(|n| export_timestamp(n, package_id));
+        if let Some(timestamp) = timestamp {
+            exports.push((timestamp.to_string(), path));
+        }
+    }
+    exports.sort();
+
+    let remove_count = exports.len().saturating_sub(retention);
+    Ok(exports
+        .into_iter()
+        .take(remove_count)
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Removes the oldest timestamped exports of the package, keeping `retention` files.
+fn apply_export_retention(
+    storage: &dyn Storage,
+    package_id: &str,
+    retention: usize,
+) -> Result<(), Error> {
+    for path in expired_exports(storage, package_id, retention)? {
+        println!("Removing old package export: {}", path);
+        storage.remove_tree(&path)?;
+    }
+    Ok(())
+}
+
+/// Path of the package export below the storage root, e.g. `package-exports/Pkg_20240131T235959Z.zip`.
+pub fn export_path(package_id: &str, config: &Config, timestamp: &str) -> String {
+    let file_name = match config.packages.package_export_timestamp {
+        PackageExportTimestamp::Enabled => format!("{}_{}.zip", package_id, timestamp),
+        PackageExportTimestamp::Disabled => format!("{}.zip", package_id),
+    };
+    format!("{}/{}", PACKAGE_EXPORT_DIR_NAME, file_name)
+}
+
+/// Export timestamp of the current time.
+pub fn export_timestamp_now() -> String {
+    chrono::Utc::now()
+        .format(PACKAGE_EXPORT_TIMESTAMP_FORMAT)
+        .to_string()
+}
+
+async fn export_package(
+    package_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &dyn Storage,
+    timestamp: &str,
+) -> PackageExportReport {
+    println!("- Exporting Package: {:#?}", package_id);
+    let mut report = PackageExportReport {
+        package_id: package_id.to_string(),
+        path: None,
+        http_status: None,
+        error: None,
+        bytes: None,
+    };
+
+    let path = export_path(package_id, config, timestamp);
+
+    let result = match download_package_export(package_id, config, client, authorization).await {
+        Ok(content) => {
+            report.bytes = Some(content.len() as u64);
+            storage.put_file(&path, &content)
+        }
+        Err(err) => Err(err),
+    };
+    let result = match (result, config.packages.package_export_retention) {
+        (Ok(()), Some(retention)) => apply_export_retention(storage, package_id, retention),
+        (result, _) => result,
+    };
+
+    match result {
+        Ok(()) => report.path = Some(path),
+        Err(err) => {
+            println!("Package export failed: {}: {}", package_id, err);
+            if let Error::Api { status, .. } = &err {
+                report.http_status = Some(*status);
+            }
+            report.error = Some(err.to_string());
+        }
+    }
+    report
+}
+
+/// Downloads the package export ZIPs of the selected packages into `package-exports`,
+/// these can be imported again through the UI.
+pub async fn export_packages(
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &dyn Storage,
+    package_list: &[String],
+) -> Result<Vec<PackageExportReport>, Error> {
+    let timestamp = export_timestamp_now();
+
+    let mut reports = run_pooled(
+        package_list.iter().map(|package_id| {
+            export_package(
+                package_id,
+                config,
+                client,
+                authorization,
+                storage,
+                &timestamp,
+            )
+        }),
+        config.packages.download_worker_count,
+    )
+    .await;
+    reports.sort_by(|a, b| a.package_id.cmp(&b.package_id));
+
+    let failed_count = reports.iter().filter(|r| r.error.is_some()).count();
+    if failed_count > 0 {
+        println!("Failed package exports: {}", failed_count);
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test_util::packages_config;
+
+    #[test]
+    fn package_files_follow_the_config() {
+        let config = packages_config(serde_json::json!({ "filter_rules": [] }));
+        assert_eq!(
+            package_files("Pkg", &["Flow1"], &config),
+            vec!["Pkg/package.json"]
+        );
+
+        let config = packages_config(serde_json::json!({
+            "filter_rules": [],
+            "runtime_status": "enabled",
+            "configuration_export": "properties"
+        }));
+        assert_eq!(
+            package_files("Pkg", &["Flow1"], &config),
+            vec![
+                "Pkg/package.json",
+                "Pkg/runtime-status.json",
+                "Pkg/IntegrationFlows/Flow1.configurations.properties"
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_exports_are_the_oldest() {
+        let storage = MemoryStorage::new();
+        for file in [
+            "Pkg_20240102T000000Z.zip",
+            "Pkg_20240101T000000Z.zip",
+            "Pkg_20240103T000000Z.zip",
+            "Pkg.zip",
+            "Pkg2_20230101T000000Z.zip",
+        ] {
+            storage
+                .put_file(&format!("{}/{}", PACKAGE_EXPORT_DIR_NAME, file), b"")
+                .unwrap();
+        }
+        assert_eq!(
+            expired_exports(&storage, "Pkg", 1).unwrap(),
+            vec![
+                "package-exports/Pkg_20240101T000000Z.zip",
+                "package-exports/Pkg_20240102T000000Z.zip"
+            ]
+        );
+        assert!(expired_exports(&storage, "Pkg", 3).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,645 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::configurations::configurations_paths;
+use crate::credentials::CredentialInput;
+use crate::errors::Error;
+use crate::http;
+use crate::package::{expired_exports, export_path, export_timestamp_now, package_files};
+use crate::snapshot::{expired_snapshots, snapshot_path};
+use crate::state::SyncState;
+use crate::storage::{FileSystemStorage, MemoryStorage, Storage};
+use crate::tenant_content::TENANT_CONTENT_DIR_NAME;
+use crate::{
+    artifact_local_path, get_all_packages, get_authorization, get_data_dir, list_package_artifacts,
+    run_for_tenants, select_packages, try_run_pooled,
+};
+
+use path_slash::PathBufExt;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanAction {
+    #[serde(rename = "create")]
+    Create,
+    #[serde(rename = "overwrite")]
+    Overwrite,
+    #[serde(rename = "unchanged")]
+    Unchanged,
+    #[serde(rename = "delete")]
+    Delete,
+}
+
+impl PlanAction {
+    fn for_path(path: &Path) -> PlanAction {
+        match path.exists() {
+            true => PlanAction::Overwrite,
+            false => PlanAction::Create,
+        }
+    }
+
+    /// Archive output writes every file into a new snapshot.
+    fn for_output(path: &Path, config: &Config) -> PlanAction {
+        match config.output.format {
+            OutputFormat::Directory => PlanAction::for_path(path),
+            _ => PlanAction::Create,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PlanAction::Create => "create",
+            PlanAction::Overwrite => "overwrite",
+            PlanAction::Unchanged => "unchanged",
+            PlanAction::Delete => "delete",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArtifactPlan {
+    pub id: String,
+    pub artifact_type: Option<ArtifactType>,
+    pub version: Option<String>,
+    pub path: PathBuf,
+    pub action: PlanAction,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PackagePlan {
+    pub id: String,
+    pub path: PathBuf,
+    pub action: PlanAction,
+    pub artifacts: Vec<ArtifactPlan>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FilePlan {
+    pub path: PathBuf,
+    pub action: PlanAction,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SyncPlan {
+    pub tenant: Option<String>,
+    pub management_host: String,
+    pub local_dir: PathBuf,
+    pub output_format: OutputFormat,
+    pub packages: Vec<PackagePlan>,
+    // outside of the package directories: package exports, tenant content and snapshots
+    pub files: Vec<FilePlan>,
+}
+
+/// Existing local artifact paths of a package, the ones a full sync would remove.
+fn existing_local_paths(package_dir: &Path, config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    if !package_dir.is_dir() {
+        return Ok(paths);
+    }
+    let type_folders: Vec<&str> = ArtifactType::ALL.iter().map(|t| t.folder_name()).collect();
+
+    for entry in fs::read_dir(package_dir)? {
+        let path = entry?.path();
+        let is_type_folder = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| type_folders.contains(&n))
+            .unwrap_or(false);
+
+        match (&config.packages.artifact_type_folders, is_type_folder) {
+            (ArtifactTypeFolders::Enabled, true) if path.is_dir() => {
+                for type_entry in fs::read_dir(&path)? {
+                    paths.push(type_entry?.path());
+                }
+            }
+            _ => paths.push(path),
+        }
+    }
+    Ok(paths)
+}
+
+async fn plan_package(
+    package_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    data_dir: &Path,
+    sync_state: &SyncState,
+) -> Result<PackagePlan, Error> {
+    let incremental = sync_state.is_incremental(&config.packages)?;
+    let previous = sync_state.packages.get(package_id);
+    let package_dir = data_dir.join(package_id);
+
+    let mut artifacts = Vec::new();
+    let mut planned_paths = BTreeSet::new();
+    let mut integration_flow_ids = Vec::new();
+    for artifact_type in config.packages.artifact_types.iter() {
+        let resp_obj =
+            list_package_artifacts(package_id, *artifact_type, config, client, authorization)
+                .await?;
+
+        for artifact in resp_obj {
+            let path =
+                artifact_local_path(package_id, &artifact.id, *artifact_type, config, data_dir);
+            let unchanged = incremental
+                && path.exists()
+                && previous
+                    .and_then(|p| p.artifacts.get(&artifact.id))
+                    .map(|a| a.is_unchanged(&artifact.version, &artifact.modified_date))
+                    .unwrap_or(false);
+            let action = match unchanged {
+                true => PlanAction::Unchanged,
+                false => PlanAction::for_output(&path, config),
+            };
+            if *artifact_type == ArtifactType::IntegrationFlow {
+                integration_flow_ids.push(artifact.id.clone());
+            }
+            planned_paths.insert(path.clone());
+            artifacts.push(ArtifactPlan {
+                id: artifact.id,
+                artifact_type: Some(*artifact_type),
+                version: artifact.version,
+                path,
+                action,
+            });
+        }
+    }
+
+    //package.json, runtime status and configurations are written again
+    let integration_flow_ids: Vec<&str> =
+        integration_flow_ids.iter().map(|id| id.as_str()).collect();
+    for file in package_files(package_id, &integration_flow_ids, config) {
+        let path = data_dir.join(PathBuf::from_slash(&file));
+        planned_paths.insert(path.clone());
+        artifacts.push(ArtifactPlan {
+            id: file.rsplit('/').next().unwrap_or(&file).to_string(),
+            artifact_type: None,
+            version: None,
+            action: PlanAction::for_output(&path, config),
+            path,
+        });
+    }
+
+    //same rules as process_package: full sync empties the package directory,
+    //incremental sync removes artifacts deleted on the tenant.
+    //archive output doesn't change the local directory
+    let mut deleted_paths = BTreeMap::new();
+    match (incremental, previous) {
+        _ if config.output.format != OutputFormat::Directory => {}
+        (false, _) => {
+            for path in existing_local_paths(&package_dir, config)? {
+                if !planned_paths.contains(&path) {
+                    deleted_paths.insert(path, None);
+                }
+            }
+        }
+        (true, Some(previous)) => {
+            for (artifact_id, artifact_state) in previous.artifacts.iter() {
+                if !config
+                    .packages
+                    .artifact_types
+                    .contains(&artifact_state.artifact_type)
+                {
+                    continue;
+                }
+                let path = artifact_local_path(
+                    package_id,
+                    artifact_id,
+                    artifact_state.artifact_type,
+                    config,
+                    data_dir,
+                );
+                if !planned_paths.contains(&path) && path.exists() {
+                    deleted_paths.insert(path, Some(artifact_state.artifact_type));
+                    if artifact_state.artifact_type == ArtifactType::IntegrationFlow {
+                        for file in configurations_paths(package_id, artifact_id, config) {
+                            let path = data_dir.join(PathBuf::from_slash(&file));
+                            if path.exists() {
+                                deleted_paths.insert(path, None);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        (true, None) => {}
+    }
+    for (path, artifact_type) in deleted_paths {
+        artifacts.push(ArtifactPlan {
+            id: path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            artifact_type,
+            version: None,
+            path,
+            action: PlanAction::Delete,
+        });
+    }
+
+    Ok(PackagePlan {
+        id: package_id.to_string(),
+        action: PlanAction::for_output(&package_dir, config),
+        path: package_dir,
+        artifacts,
+    })
+}
+
+/// Package exports, tenant content and the snapshot archive of a sync, with the files
+/// removed by the retention options.
+fn plan_files(
+    config: &Config,
+    data_dir: &Path,
+    storage: &dyn Storage,
+    package_list: &[String],
+) -> Result<Vec<FilePlan>, Error> {
+    let mut files = Vec::new();
+    let mut add_file = |path: PathBuf, action: PlanAction| files.push(FilePlan { path, action });
+
+    if matches!(config.packages.package_export, PackageExport::Enabled) {
+        let timestamp = export_timestamp_now();
+        //the new timestamped export counts for the retention
+        let retention = config.packages.package_export_retention.map(|retention| {
+            match config.packages.package_export_timestamp {
+                PackageExportTimestamp::Enabled => retention.saturating_sub(1),
+                PackageExportTimestamp::Disabled => retention,
+            }
+        });
+        for package_id in package_list.iter() {
+            let path = data_dir.join(PathBuf::from_slash(export_path(
+                package_id, config, &timestamp,
+            )));
+            add_file(path.clone(), PlanAction::for_output(&path, config));
+            if let Some(retention) = retention {
+                for expired in expired_exports(storage, package_id, retention)? {
+                    add_file(
+                        data_dir.join(PathBuf::from_slash(expired)),
+                        PlanAction::Delete,
+                    );
+                }
+            }
+        }
+    }
+
+    if !config.tenant_content.is_empty() {
+        let path = data_dir.join(TENANT_CONTENT_DIR_NAME);
+        add_file(path.clone(), PlanAction::for_output(&path, config));
+    }
+
+    if config.output.format != OutputFormat::Directory {
+        add_file(
+            snapshot_path(config, data_dir, &chrono::Utc::now())?,
+            PlanAction::Create,
+        );
+        if let Some(retention) = config.output.retention {
+            for expired in expired_snapshots(config, data_dir, retention.saturating_sub(1))? {
+                add_file(expired, PlanAction::Delete);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Config for the authorization of a dry run: OAuth tokens are not read from
+/// or written to the `token_cache_file`, a dry run doesn't write any file.
+fn without_token_cache(config: &Config) -> Config {
+    let mut config = config.clone();
+    if let Some(tenant) = config.tenant.as_mut() {
+        match &mut tenant.credential {
+            CredentialInside::OauthClientCredentials(c) => c.token_cache_file = None,
+            CredentialInside::OauthClientCertificate(c) => c.token_cache_file = None,
+            _ => {}
+        }
+    }
+    config
+}
+
+/// Evaluates the filter rules and lists the artifacts like a sync, without touching the filesystem.
+/// With archive output the package files are planned as entries of the new snapshot.
+pub async fn plan_with_config_and_password(
+    config: &Config,
+    config_path: &str,
+    password: &str,
+) -> Result<SyncPlan, Error> {
+    let client = http::client_builder(config)?.build()?;
+
+    let authorization = get_authorization(&without_token_cache(config), &client, password).await?;
+
+    let data_dir = get_data_dir(config, config_path, false).await?;
+    //archive output starts from an empty storage, like the sync
+    let storage: Box<dyn Storage> = match config.output.format {
+        OutputFormat::Directory => Box::new(FileSystemStorage::new(&data_dir)),
+        _ => Box::new(MemoryStorage::new()),
+    };
+    let sync_state = SyncState::load(storage.as_ref())?;
+
+    let api_package_list = get_all_packages(config, &client, &authorization).await?;
+
+    let mut package_list = select_packages(config, &api_package_list)?;
+    package_list.sort();
+
+    let packages = try_run_pooled(
+        package_list.iter().map(|package_id| {
+            plan_package(
+                package_id,
+                config,
+                &client,
+                &authorization,
+                &data_dir,
+                &sync_state,
+            )
+        }),
+        config.packages.download_worker_count,
+    )
+    .await?;
+
+    let files = plan_files(config, &data_dir, storage.as_ref(), &package_list)?;
+
+    Ok(SyncPlan {
+        tenant: None,
+        management_host: config.tenant()?.management_host.clone(),
+        local_dir: data_dir,
+        output_format: config.output.format,
+        packages,
+        files,
+    })
+}
+
+fn print_plan(plan: &SyncPlan) {
+    match &plan.tenant {
+        Some(name) => println!(
+            "Dry Run Plan for Tenant: {} ({})",
+            name, &plan.management_host
+        ),
+        None => println!("Dry Run Plan for Tenant: {}", &plan.management_host),
+    }
+    println!("Local directory: {}", plan.local_dir.display());
+
+    for package in plan.packages.iter() {
+        println!(
+            "Package: {} ({}) {}",
+            &package.id,
+            package.action.name(),
+            package.path.display()
+        );
+        for artifact in package.artifacts.iter() {
+            println!(
+                "  {:<10} {} {}",
+                artifact.action.name(),
+                artifact
+                    .path
+                    .strip_prefix(&package.path)
+                    .unwrap_or(&artifact.path)
+                    .display(),
+                artifact
+                    .version
+                    .as_deref()
+                    .map(|v| format!("({})", v))
+                    .unwrap_or_default()
+            );
+        }
+    }
+    for file in plan.files.iter() {
+        println!(
+            "{:<12} {}",
+            file.action.name(),
+            file.path
+                .strip_prefix(&plan.local_dir)
+                .unwrap_or(&file.path)
+                .display()
+        );
+    }
+}
+
+/// Prints what a sync would do for the selected tenants, optionally writes the plan as JSON.
+pub async fn dry_run_with_tenants(
+    config: &Config,
+    config_path: &str,
+    credentials: &CredentialInput,
+    selection: &TenantSelection,
+    plan_output: Option<&str>,
+) -> Result<(), Error> {
+    let outputs = run_for_tenants(
+        config,
+        selection,
+        credentials,
+        |tenant_config, password| async move {
+            plan_with_config_and_password(&tenant_config, config_path, &password).await
+        },
+    )
+    .await?;
+
+    let mut plans = Vec::new();
+    for (tenant_name, mut plan) in outputs {
+        plan.tenant = tenant_name;
+        print_plan(&plan);
+        plans.push(plan);
+    }
+
+    if let Some(plan_output) = plan_output {
+        fs::write(plan_output, serde_json::to_string_pretty(&plans)? + "\n")?;
+        println!("Dry run plan written to: {}", plan_output);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{settings_hash, ArtifactState, PackageState, STATE_FILE_NAME};
+    use crate::test_util;
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn tenant() -> MockServer {
+        let server = test_util::mock_server().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages",
+            json!([{ "Id": "Pkg1", "Name": "Package 1" }]),
+        )
+        .await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts",
+            json!([
+                { "Id": "Same", "Name": "Same", "Version": "1.0.0" },
+                { "Id": "Changed", "Name": "Changed", "Version": "2.0.0" },
+                { "Id": "New", "Name": "New", "Version": "1.0.0" }
+            ]),
+        )
+        .await;
+        server
+    }
+
+    fn tenant_config(
+        server: &MockServer,
+        data_dir: &Path,
+        credential: serde_json::Value,
+    ) -> Config {
+        test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, credential),
+            "packages": {
+                "filter_rules": [{ "type": "single", "id": "Pkg1" }],
+                "local_dir": data_dir.to_string_lossy(),
+                "artifact_types": ["IntegrationDesigntimeArtifacts"],
+                "incremental_sync": "enabled"
+            },
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    fn artifact_state(version: &str) -> ArtifactState {
+        ArtifactState {
+            artifact_type: ArtifactType::IntegrationFlow,
+            version: Some(version.to_string()),
+            modified_at: None,
+            content_hash: String::new(),
+            configurations_hash: None,
+        }
+    }
+
+    /// Local files of a previous sync: `Removed` was deleted on the tenant since.
+    fn previous_sync(data_dir: &Path, packages: &Packages) {
+        let mut package = PackageState::default();
+        for (artifact_id, version) in [
+            ("Same", "1.0.0"),
+            ("Changed", "1.0.0"),
+            ("Removed", "1.0.0"),
+        ] {
+            package
+                .artifacts
+                .insert(artifact_id.to_string(), artifact_state(version));
+            let artifact_dir = data_dir
+                .join("Pkg1")
+                .join("IntegrationFlows")
+                .join(artifact_id);
+            fs::create_dir_all(&artifact_dir).unwrap();
+            fs::write(artifact_dir.join("flow.iflw"), "<x/>").unwrap();
+        }
+        let mut state = SyncState {
+            settings_hash: Some(settings_hash(packages).unwrap()),
+            ..Default::default()
+        };
+        state.packages.insert("Pkg1".to_string(), package);
+        state.save(&FileSystemStorage::new(data_dir)).unwrap();
+    }
+
+    fn local_files(dir: &Path) -> Vec<String> {
+        FileSystemStorage::new(dir).list("").unwrap()
+    }
+
+    #[tokio::test]
+    async fn plan_compares_artifacts_with_the_saved_state() {
+        let server = tenant().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = tenant_config(&server, dir.path(), test_util::s_user());
+        previous_sync(dir.path(), &config.packages);
+        let files_before = local_files(dir.path());
+
+        let config_path = dir.path().join("cpi-sync.json");
+        let plan = plan_with_config_and_password(&config, &config_path.to_string_lossy(), "secret")
+            .await
+            .unwrap();
+
+        assert_eq!(plan.packages.len(), 1);
+        let actions: BTreeMap<&str, PlanAction> = plan.packages[0]
+            .artifacts
+            .iter()
+            .map(|a| (a.id.as_str(), a.action))
+            .collect();
+        assert_eq!(
+            actions,
+            BTreeMap::from([
+                ("Changed", PlanAction::Overwrite),
+                ("New", PlanAction::Create),
+                ("Removed", PlanAction::Delete),
+                ("Same", PlanAction::Unchanged),
+                ("package.json", PlanAction::Create),
+            ])
+        );
+        assert_eq!(local_files(dir.path()), files_before);
+        assert!(files_before.contains(&STATE_FILE_NAME.to_string()));
+    }
+
+    #[tokio::test]
+    async fn plan_overwrites_all_artifacts_after_a_settings_change() {
+        let server = tenant().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = tenant_config(&server, dir.path(), test_util::s_user());
+        previous_sync(dir.path(), &config.packages);
+        config.packages.prop_comment_removal = PropCommentRemoval::Enabled;
+
+        let config_path = dir.path().join("cpi-sync.json");
+        let plan = plan_with_config_and_password(&config, &config_path.to_string_lossy(), "secret")
+            .await
+            .unwrap();
+
+        let same = plan.packages[0]
+            .artifacts
+            .iter()
+            .find(|a| a.id == "Same")
+            .unwrap();
+        assert_eq!(same.action, PlanAction::Overwrite);
+    }
+
+    #[tokio::test]
+    async fn dry_run_writes_only_the_plan_output() {
+        let server = tenant().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "token",
+                "expires_in": 3600
+            })))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        let token_cache = dir.path().join("token.json");
+        let credential = json!({ "oauth_client_credentials": {
+            "client_id": "client",
+            "token_endpoint_url": format!("{}/oauth/token", server.uri()),
+            "token_cache_file": token_cache.to_string_lossy()
+        }});
+        let config = tenant_config(&server, &data_dir, credential);
+        let plan_output = dir.path().join("plan.json");
+
+        dry_run_with_tenants(
+            &config,
+            &dir.path().join("cpi-sync.json").to_string_lossy(),
+            &CredentialInput::new(true, Some("secret".to_string()), None),
+            &TenantSelection::Default,
+            Some(&plan_output.to_string_lossy()),
+        )
+        .await
+        .unwrap();
+
+        assert!(!data_dir.exists());
+        assert!(!token_cache.exists());
+        let plans: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&plan_output).unwrap()).unwrap();
+        let plan = &plans[0];
+        assert_eq!(plan["tenant"], serde_json::Value::Null);
+        assert_eq!(plan["management_host"], "tenant.example.com");
+        assert_eq!(plan["output_format"], "directory");
+        assert_eq!(plan["packages"][0]["id"], "Pkg1");
+        assert_eq!(plan["packages"][0]["action"], "create");
+        let artifacts = plan["packages"][0]["artifacts"].as_array().unwrap();
+        assert_eq!(artifacts.len(), 4);
+        assert!(artifacts
+            .iter()
+            .all(|a| a["action"] == "create" && a["path"].as_str().is_some()));
+        assert_eq!(
+            artifacts[0]["artifact_type"],
+            "IntegrationDesigntimeArtifacts"
+        );
+        assert_eq!(artifacts[0]["version"], "1.0.0");
+    }
+}
@@ -0,0 +1,543 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::credentials::{get_password, CredentialInput};
+use crate::errors::Error;
+use crate::http;
+use crate::{
+    artifact_local_path, get_all_packages, get_authorization, get_data_dir, list_package_artifacts,
+    run_for_tenants, run_pooled, select_packages, try_run_pooled,
+};
+
+use futures::Future;
+use path_slash::PathBufExt;
+use serde_json::json;
+use std::{
+    fs,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+// artifact types that can be updated through the API
+const PUSH_ARTIFACT_TYPES: [ArtifactType; 1] = [ArtifactType::IntegrationFlow];
+
+/// Fetches a CSRF token, modifying requests are rejected without it.
+/// The session cookie that belongs to the token is kept by the client cookie store.
+async fn fetch_csrf_token(
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<String, Error> {
+    let check_api_url = format!("{api}/", api = config.tenant()?.api_url());
+
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client.get(&check_api_url).header("X-CSRF-Token", "Fetch"),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: check_api_url,
+            status: resp_code.as_u16(),
+            body: resp.text().await?,
+        });
+    }
+
+    resp.headers()
+        .get("X-CSRF-Token")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+        .ok_or(Error::UnexpectedResponse {
+            url: check_api_url,
+            message: "X-CSRF-Token header is missing".to_string(),
+        })
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// `prop_comment_removal` drops the generated timestamp comment of `parameters.prop`.
+/// Comments are not significant for the tenant, so we only add a fresh timestamp line back.
+fn restore_prop_comments(prop_content: &str) -> String {
+    if prop_content.starts_with('#') {
+        return prop_content.to_string();
+    }
+    format!(
+        "#{timestamp}\n{content}",
+        timestamp = chrono::Utc::now().format("%a %b %d %H:%M:%S UTC %Y"),
+        content = prop_content
+    )
+}
+
+/// Re-creates the artifact ZIP from an extracted artifact directory.
+fn zip_artifact_dir(config: &Config, artifact_dir: &Path) -> Result<Vec<u8>, Error> {
+    if !artifact_dir.join("META-INF").join("MANIFEST.MF").is_file() {
+        return Err(Error::Filesystem(format!(
+            "Not an extracted artifact, META-INF/MANIFEST.MF is missing: {}",
+            artifact_dir.display()
+        )));
+    }
+
+    let mut files = Vec::new();
+    collect_files(artifact_dir, &mut files)?;
+    files.sort();
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    for file in files {
+        let entry_name = file
+            .strip_prefix(artifact_dir)
+            .map_err(|e| Error::Filesystem(e.to_string()))?
+            .to_path_buf()
+            .to_slash()
+            .ok_or(Error::Filesystem("to_slash".to_string()))?;
+
+        let mut content = fs::read(&file)?;
+
+        if let PropCommentRemoval::Enabled = config.packages.prop_comment_removal {
+            if entry_name.ends_with("parameters.prop") {
+                let prop_content = String::from_utf8_lossy(&content);
+                content = restore_prop_comments(&prop_content).into_bytes();
+            }
+        }
+
+        zip.start_file(entry_name, options)?;
+        zip.write_all(&content)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn read_local_artifact(
+    package_id: &str,
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+    data_dir: &Path,
+) -> Result<Option<Vec<u8>>, Error> {
+    let local_path = artifact_local_path(package_id, artifact_id, artifact_type, config, data_dir);
+
+    match config.packages.zip_extraction {
+        ZipExtraction::Disabled => {
+            if !local_path.is_file() {
+                return Ok(None);
+            }
+            Ok(Some(fs::read(local_path)?))
+        }
+        ZipExtraction::Enabled => {
+            let artifact_dir = local_path;
+            if !artifact_dir.is_dir() {
+                return Ok(None);
+            }
+            Ok(Some(zip_artifact_dir(config, &artifact_dir)?))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn push_artifact(
+    package_id: String,
+    artifact_id: String,
+    artifact_name: String,
+    config: Config,
+    data_dir: PathBuf,
+    client: reqwest::Client,
+    authorization: Authorization,
+    csrf_token: String,
+    artifact_type: ArtifactType,
+) -> Result<(), Error> {
+    let content =
+        match read_local_artifact(&package_id, &artifact_id, artifact_type, &config, &data_dir)? {
+            Some(c) => c,
+            None => {
+                println!(
+                    "- Artifact: {:#?} , from Package: {:#?} not found locally, skipping.",
+                    artifact_id, package_id
+                );
+                return Ok(());
+            }
+        };
+
+    println!(
+        "- Pushing Artifact: {:#?} , to Package: {:#?}",
+        artifact_id, package_id
+    );
+
+    let api_artifact_url = format!(
+        "{api}/{artifact_type}(Id='{artifact_id}',Version='Active')",
+        api = config.tenant()?.api_url(),
+        artifact_id = artifact_id,
+        artifact_type = artifact_type.api_name()
+    );
+    let body = json!({
+        "Name": artifact_name,
+        "ArtifactContent": base64::encode(&content),
+    });
+
+    let resp = http::send(
+        &config,
+        Some(&authorization),
+        client
+            .put(&api_artifact_url)
+            .header("X-CSRF-Token", csrf_token)
+            .header("Accept", "application/json")
+            .json(&body),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_artifact_url,
+            status: resp_code.as_u16(),
+            body: resp.text().await?,
+        });
+    }
+
+    Ok(())
+}
+
+async fn process_package_push(
+    package_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    csrf_token: &str,
+    data_dir: &Path,
+) -> Result<Vec<impl Future<Output = (String, Result<(), Error>)>>, Error> {
+    println!("Processing Package: {:?}", package_id);
+
+    let mut tasks = Vec::new();
+    for artifact_type in PUSH_ARTIFACT_TYPES
+        .into_iter()
+        .filter(|t| config.packages.artifact_types.contains(t))
+    {
+        let resp_obj =
+            list_package_artifacts(package_id, artifact_type, config, client, authorization)
+                .await?;
+
+        for artifact in resp_obj {
+            let artifact_path = format!("{}/{}", package_id, artifact.id);
+            let push = push_artifact(
+                package_id.to_owned(),
+                artifact.id,
+                artifact.name,
+                config.clone(),
+                data_dir.to_path_buf(),
+                client.clone(),
+                authorization.clone(),
+                csrf_token.to_string(),
+                artifact_type,
+            );
+            tasks.push(async move { (artifact_path, push.await) });
+        }
+    }
+    Ok(tasks)
+}
+
+pub async fn push_with_tenants(
+    config: &Config,
+    config_path: &str,
+    credentials: &CredentialInput,
+    selection: &TenantSelection,
+) -> Result<(), Error> {
+    let no_input = credentials.no_input;
+    run_for_tenants(
+        config,
+        selection,
+        credentials,
+        |tenant_config, password| async move {
+            push_with_config_and_password(&tenant_config, config_path, no_input, &password).await
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+pub async fn push_with_config(
+    config: &Config,
+    config_path: &str,
+    no_input: bool,
+) -> Result<(), Error> {
+    //loads a service key credential
+    let (_, config) = config.tenant_configs(&TenantSelection::Default)?.remove(0);
+    let config = &config;
+    let password = get_password(config, &CredentialInput::new(no_input, None, None))?;
+
+    push_with_config_and_password(config, config_path, no_input, &password).await
+}
+
+/// Push reads the extracted files of a directory sync. Files changed by `normalization` rules
+/// can not be zipped back into a valid artifact, only the timestamp comment of
+/// `prop_comment_removal` is restored.
+fn check_push_config(config: &Config) -> Result<(), Error> {
+    if config.output.format != OutputFormat::Directory {
+        return Err(Error::Config(
+            "push reads the local files, `output.format` must be directory".to_string(),
+        ));
+    }
+    if matches!(config.packages.zip_extraction, ZipExtraction::Enabled)
+        && !config.packages.normalization.is_empty()
+    {
+        return Err(Error::Config(
+            "push uploads the extracted files, which are changed by `normalization`, remove the normalization rules to push".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Uploads local artifacts of the selected packages, updating the existing designtime artifacts.
+/// Artifacts that exist only locally are not created on the tenant.
+/// Failed artifacts are printed with their error and fail with `Error::PushFailed` naming them.
+pub async fn push_with_config_and_password(
+    config: &Config,
+    config_path: &str,
+    _no_input: bool,
+    password: &str,
+) -> Result<(), Error> {
+    check_push_config(config)?;
+
+    let now = tokio::time::Instant::now();
+
+    let client = http::client_builder(config)?.cookie_store(true).build()?;
+
+    let authorization = get_authorization(config, &client, password).await?;
+    let csrf_token = fetch_csrf_token(config, &client, &authorization).await?;
+
+    let data_dir = get_data_dir(config, config_path, true).await?;
+
+    let api_package_list = get_all_packages(config, &client, &authorization).await?;
+
+    let package_list = select_packages(config, &api_package_list)?;
+
+    println!("Pushing These Packages:");
+    println!("{:?}", &package_list);
+
+    let outputs = try_run_pooled(
+        package_list.iter().map(|package_id| {
+            process_package_push(
+                package_id,
+                config,
+                &client,
+                &authorization,
+                &csrf_token,
+                &data_dir,
+            )
+        }),
+        config.packages.download_worker_count,
+    )
+    .await?;
+
+    let push_results = run_pooled(
+        outputs.into_iter().flatten(),
+        config.packages.download_worker_count,
+    )
+    .await;
+
+    println!("Push time elapsed in seconds: {}", now.elapsed().as_secs());
+
+    let mut failed = Vec::new();
+    for (artifact_path, result) in push_results {
+        if let Err(err) = result {
+            println!("- Artifact: {:#?} push failed: {}", artifact_path, err);
+            failed.push(artifact_path);
+        }
+    }
+    if !failed.is_empty() {
+        failed.sort();
+        println!("Failed artifact pushes: {}", failed.len());
+        return Err(Error::PushFailed(failed));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, packages_config};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Tenant with the iFlows `Broken` and `Rejected` in `Pkg1`, updates of `Rejected` fail.
+    async fn tenant() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(200).insert_header("X-CSRF-Token", "token"))
+            .mount(&server)
+            .await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages",
+            json!([{ "Id": "Pkg1", "Name": "Pkg1" }]),
+        )
+        .await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts",
+            json!([
+                { "Id": "Broken", "Name": "Broken" },
+                { "Id": "Rejected", "Name": "Rejected" }
+            ]),
+        )
+        .await;
+        Mock::given(method("PUT"))
+            .and(path(
+                "/api/v1/IntegrationDesigntimeArtifacts(Id='Rejected',Version='Active')",
+            ))
+            .respond_with(ResponseTemplate::new(500).set_body_string("rejected"))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn tenant_config(server: &MockServer, data_dir: &Path) -> Config {
+        test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "packages": {
+                "filter_rules": [{ "type": "single", "id": "Pkg1" }],
+                "local_dir": data_dir.to_string_lossy(),
+                "artifact_types": ["IntegrationDesigntimeArtifacts"]
+            },
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    fn write(data_dir: &Path, path: &str, content: &str) {
+        let path = data_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_pushes_name_the_artifacts() {
+        let server = tenant().await;
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "Pkg1/IntegrationFlows/Broken/flow.iflw", "<x/>");
+        write(
+            dir.path(),
+            "Pkg1/IntegrationFlows/Rejected/META-INF/MANIFEST.MF",
+            "Bundle-SymbolicName: Rejected",
+        );
+
+        let result = push_with_config_and_password(
+            &tenant_config(&server, dir.path()),
+            &dir.path().join("cpi-sync.json").to_string_lossy(),
+            true,
+            "secret",
+        )
+        .await;
+        match result {
+            Err(Error::PushFailed(artifacts)) => {
+                assert_eq!(artifacts, vec!["Pkg1/Broken", "Pkg1/Rejected"])
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_push_is_an_api_error() {
+        let server = tenant().await;
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "Pkg1/IntegrationFlows/Rejected/META-INF/MANIFEST.MF",
+            "Bundle-SymbolicName: Rejected",
+        );
+        let config = tenant_config(&server, dir.path());
+        let client = http::client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+
+        let result = push_artifact(
+            "Pkg1".to_string(),
+            "Rejected".to_string(),
+            "Rejected".to_string(),
+            config,
+            dir.path().to_path_buf(),
+            client,
+            authorization,
+            "token".to_string(),
+            ArtifactType::IntegrationFlow,
+        )
+        .await;
+        match result {
+            Err(Error::Api { url, status, body }) => {
+                assert!(
+                    url.ends_with("IntegrationDesigntimeArtifacts(Id='Rejected',Version='Active')")
+                );
+                assert_eq!(status, 500);
+                assert_eq!(body, "rejected");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn csrf_token_failures_are_api_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/"))
+            .respond_with(ResponseTemplate::new(403).set_body_string("forbidden"))
+            .mount(&server)
+            .await;
+        let config = tenant_config(&server, Path::new("."));
+        let client = http::client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+        assert!(matches!(
+            fetch_csrf_token(&config, &client, &authorization).await,
+            Err(Error::Api { status: 403, body, .. }) if body == "forbidden"
+        ));
+
+        let server = test_util::mock_server().await;
+        let config = tenant_config(&server, Path::new("."));
+        assert!(matches!(
+            fetch_csrf_token(&config, &client, &authorization).await,
+            Err(Error::UnexpectedResponse { .. })
+        ));
+    }
+
+    #[test]
+    fn normalized_files_are_not_pushed() {
+        let normalization = json!([{ "glob": "*.xsd", "steps": [{ "type": "line_endings" }] }]);
+        let config = packages_config(json!({
+            "filter_rules": [],
+            "prop_comment_removal": "enabled",
+            "normalization": normalization
+        }));
+        assert!(matches!(check_push_config(&config), Err(Error::Config(_))));
+
+        let config = packages_config(json!({
+            "filter_rules": [],
+            "zip_extraction": "disabled",
+            "normalization": normalization
+        }));
+        assert!(check_push_config(&config).is_ok());
+
+        let config = packages_config(json!({
+            "filter_rules": [],
+            "prop_comment_removal": "enabled"
+        }));
+        assert!(check_push_config(&config).is_ok());
+    }
+
+    #[test]
+    fn snapshots_are_not_pushed() {
+        let mut config = packages_config(json!({ "filter_rules": [] }));
+        config.output.format = OutputFormat::TarGz;
+        assert!(matches!(check_push_config(&config), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::ArtifactType;
+use crate::errors::Error;
+
+use serde::Serialize;
+use std::{fs, path::PathBuf};
+
+// length of response body excerpts in the report
+const BODY_EXCERPT_LENGTH: usize = 500;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactStatus {
+    #[serde(rename = "downloaded")]
+    Downloaded,
+    #[serde(rename = "skipped")]
+    Skipped,
+    #[serde(rename = "failed")]
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ArtifactReport {
+    pub package_id: String,
+    pub artifact_id: String,
+    pub name: String,
+    pub artifact_type: ArtifactType,
+    pub version: Option<String>,
+    pub status: ArtifactStatus,
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+    pub body_excerpt: Option<String>,
+    pub bytes: Option<u64>,
+    pub duration_ms: u64,
+}
+
+impl ArtifactReport {
+    /// Records the error, with HTTP status and body excerpt for API errors.
+    pub fn fail(&mut self, err: &Error) {
+        self.status = ArtifactStatus::Failed;
+        self.error = Some(err.to_string());
+        if let Error::Api { status, body, .. } = err {
+            self.http_status = Some(*status);
+            self.body_excerpt = Some(body_excerpt(body));
+        }
+    }
+}
+
+/// Deployed version that differs from the designtime version.
+#[derive(Serialize, Debug, Clone)]
+pub struct VersionDrift {
+    pub package_id: String,
+    pub artifact_id: String,
+    pub designtime_version: String,
+    pub runtime_version: String,
+}
+
+/// Package export ZIP download, see `package_export`.
+#[derive(Serialize, Debug, Clone)]
+pub struct PackageExportReport {
+    pub package_id: String,
+    // relative to `local_dir`
+    pub path: Option<String>,
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+    pub bytes: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct TenantReport {
+    pub tenant: Option<String>,
+    pub management_host: String,
+    pub local_dir: PathBuf,
+    pub selected_packages: Vec<String>,
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+    pub error: Option<String>,
+    pub artifacts: Vec<ArtifactReport>,
+    // only with `runtime_status` enabled
+    pub version_drift: Vec<VersionDrift>,
+    // only with `package_export` enabled
+    pub package_exports: Vec<PackageExportReport>,
+    // only with `output.format` tar.gz or zip
+    pub snapshot: Option<PathBuf>,
+}
+
+impl TenantReport {
+    /// Adds the artifact results sorted by package and artifact, and updates the totals.
+    pub fn add_artifacts(&mut self, mut artifacts: Vec<ArtifactReport>) {
+        artifacts
+            .sort_by(|a, b| (&a.package_id, &a.artifact_id).cmp(&(&b.package_id, &b.artifact_id)));
+        for artifact in artifacts.iter() {
+            match artifact.status {
+                ArtifactStatus::Downloaded => self.downloaded += 1,
+                ArtifactStatus::Skipped => self.skipped += 1,
+                ArtifactStatus::Failed => self.failed += 1,
+            }
+            self.bytes += artifact.bytes.unwrap_or(0);
+        }
+        self.artifacts.extend(artifacts);
+    }
+
+    /// Failed artifact downloads and package exports.
+    pub fn failed_downloads(&self) -> usize {
+        self.failed
+            + self
+                .package_exports
+                .iter()
+                .filter(|p| p.error.is_some())
+                .count()
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RunReport {
+    pub started_at: String,
+    pub elapsed_ms: u64,
+    // no failed tenant, artifact or package export
+    pub success: bool,
+    pub tenants: Vec<TenantReport>,
+}
+
+impl RunReport {
+    pub fn new(started_at: String, elapsed_ms: u64, tenants: Vec<TenantReport>) -> RunReport {
+        let success = tenants
+            .iter()
+            .all(|t| t.error.is_none() && t.failed_downloads() == 0);
+        RunReport {
+            started_at,
+            elapsed_ms,
+            success,
+            tenants,
+        }
+    }
+
+    pub fn write(&self, report_path: &str) -> Result<(), Error> {
+        fs::write(report_path, serde_json::to_string_pretty(self)? + "\n")?;
+        println!("Run report written to: {}", report_path);
+        Ok(())
+    }
+}
+
+pub fn body_excerpt(body: &str) -> String {
+    match body.char_indices().nth(BODY_EXCERPT_LENGTH) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn artifact(artifact_id: &str, status: ArtifactStatus, bytes: Option<u64>) -> ArtifactReport {
+        ArtifactReport {
+            package_id: "Pkg1".to_string(),
+            artifact_id: artifact_id.to_string(),
+            name: artifact_id.to_string(),
+            artifact_type: ArtifactType::IntegrationFlow,
+            version: Some("1.0.0".to_string()),
+            status,
+            http_status: None,
+            error: None,
+            body_excerpt: None,
+            bytes,
+            duration_ms: 10,
+        }
+    }
+
+    fn run_report() -> RunReport {
+        let mut failed = artifact("Failed", ArtifactStatus::Downloaded, None);
+        failed.fail(&Error::Api {
+            url: "https://tenant.example.com/api/v1/x".to_string(),
+            status: 500,
+            body: "Internal Server Error".to_string(),
+        });
+        let mut tenant = TenantReport {
+            tenant: Some("DEV".to_string()),
+            management_host: "tenant.example.com".to_string(),
+            local_dir: PathBuf::from("out/DEV"),
+            selected_packages: vec!["Pkg1".to_string()],
+            elapsed_ms: 100,
+            ..Default::default()
+        };
+        tenant.add_artifacts(vec![
+            failed,
+            artifact("Skipped", ArtifactStatus::Skipped, None),
+            artifact("Downloaded", ArtifactStatus::Downloaded, Some(42)),
+        ]);
+        RunReport::new("2021-02-01T10:52:21Z".to_string(), 120, vec![tenant])
+    }
+
+    #[test]
+    fn body_excerpt_is_cut_on_a_char_boundary() {
+        assert_eq!(body_excerpt("short"), "short");
+        let limit = "ä".repeat(BODY_EXCERPT_LENGTH);
+        assert_eq!(body_excerpt(&limit), limit);
+        assert_eq!(body_excerpt(&(limit.clone() + "ö")), limit + "...");
+    }
+
+    #[test]
+    fn failed_artifact_fails_the_run() {
+        let report = run_report();
+        let tenant = &report.tenants[0];
+        assert_eq!(
+            (
+                tenant.downloaded,
+                tenant.skipped,
+                tenant.failed,
+                tenant.bytes
+            ),
+            (1, 1, 1, 42)
+        );
+        assert!(!report.success);
+        assert!(RunReport::new(String::new(), 0, vec![TenantReport::default()]).success);
+    }
+
+    #[test]
+    fn report_is_written_as_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let report_path = dir.path().join("report.json");
+        run_report().write(report_path.to_str().unwrap()).unwrap();
+
+        let report_str = fs::read_to_string(&report_path).unwrap();
+        assert!(report_str.ends_with("}\n"));
+        let report: Value = serde_json::from_str(&report_str).unwrap();
+        assert_eq!(
+            report,
+            json!({
+                "started_at": "2021-02-01T10:52:21Z",
+                "elapsed_ms": 120,
+                "success": false,
+                "tenants": [{
+                    "tenant": "DEV",
+                    "management_host": "tenant.example.com",
+                    "local_dir": "out/DEV",
+                    "selected_packages": ["Pkg1"],
+                    "downloaded": 1,
+                    "skipped": 1,
+                    "failed": 1,
+                    "bytes": 42,
+                    "elapsed_ms": 100,
+                    "error": null,
+                    "artifacts": [
+                        {
+                            "package_id": "Pkg1",
+                            "artifact_id": "Downloaded",
+                            "name": "Downloaded",
+                            "artifact_type": "IntegrationDesigntimeArtifacts",
+                            "version": "1.0.0",
+                            "status": "downloaded",
+                            "http_status": null,
+                            "error": null,
+                            "body_excerpt": null,
+                            "bytes": 42,
+                            "duration_ms": 10
+                        },
+                        {
+                            "package_id": "Pkg1",
+                            "artifact_id": "Failed",
+                            "name": "Failed",
+                            "artifact_type": "IntegrationDesigntimeArtifacts",
+                            "version": "1.0.0",
+                            "status": "failed",
+                            "http_status": 500,
+                            "error": Error::Api {
+                                url: "https://tenant.example.com/api/v1/x".to_string(),
+                                status: 500,
+                                body: "Internal Server Error".to_string(),
+                            }
+                            .to_string(),
+                            "body_excerpt": "Internal Server Error",
+                            "bytes": null,
+                            "duration_ms": 10
+                        },
+                        {
+                            "package_id": "Pkg1",
+                            "artifact_id": "Skipped",
+                            "name": "Skipped",
+                            "artifact_type": "IntegrationDesigntimeArtifacts",
+                            "version": "1.0.0",
+                            "status": "skipped",
+                            "http_status": null,
+                            "error": null,
+                            "body_excerpt": null,
+                            "bytes": null,
+                            "duration_ms": 10
+                        }
+                    ],
+                    "version_drift": [],
+                    "package_exports": [],
+                    "snapshot": null
+                }]
+            })
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::errors::Error;
+use crate::report::{ArtifactReport, VersionDrift};
+use crate::storage::Storage;
+use crate::{http, unexpected_response};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const RUNTIME_STATUS_FILE_NAME: &str = "runtime-status.json";
+
+#[derive(Deserialize, Debug, Clone)]
+struct RuntimeArtifactResult {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "Version")]
+    version: Option<String>,
+    #[serde(rename = "Name")]
+    name: Option<String>,
+    #[serde(rename = "Type")]
+    runtime_type: Option<String>,
+    #[serde(rename = "DeployedBy")]
+    deployed_by: Option<String>,
+    #[serde(rename = "DeployedOn")]
+    deployed_on: Option<String>,
+    #[serde(rename = "Status")]
+    status: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RuntimeArtifactD {
+    results: Vec<RuntimeArtifactResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RuntimeArtifactRoot {
+    d: RuntimeArtifactD,
+}
+
+#[derive(Serialize, Debug)]
+struct RuntimeArtifactStatus {
+    artifact_id: String,
+    artifact_type: ArtifactType,
+    name: Option<String>,
+    runtime_type: Option<String>,
+    designtime_version: Option<String>,
+    // not in `IntegrationRuntimeArtifacts`, the runtime fields are empty
+    deployed: bool,
+    runtime_version: Option<String>,
+    version_drift: bool,
+    status: Option<String>,
+    deployed_by: Option<String>,
+    deployed_at: Option<String>,
+    // only for failed deployments
+    error_information: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct PackageRuntimeStatus {
+    package_id: String,
+    // artifacts of the package, deployed or not, sorted by id
+    artifacts: Vec<RuntimeArtifactStatus>,
+}
+
+/// OData dates like `/Date(1612176741000)/` as RFC 3339, other values unchanged.
+pub fn odata_date(value: &str) -> String {
+    value
+        .strip_prefix("/Date(")
+        .and_then(|v| v.strip_suffix(")/"))
+        .and_then(|ms| ms.parse::<i64>().ok())
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .map(|d| d.to_rfc3339())
+        .unwrap_or_else(|| value.to_string())
+}
+
+async fn get_runtime_artifacts(
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<RuntimeArtifactResult>, Error> {
+    let api_runtime_url = format!(
+        "{api}/IntegrationRuntimeArtifacts",
+        api = config.tenant()?.api_url()
+    );
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(&api_runtime_url)
+            .header("Accept", "application/json"),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    let body_text = resp.text().await?;
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_runtime_url,
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
+    }
+    let resp_obj: RuntimeArtifactRoot =
+        serde_json::from_str(&body_text).map_err(|e| unexpected_response(&api_runtime_url, e))?;
+    Ok(resp_obj.d.results)
+}
+
+/// Error details of a failed deployment, `None` if the tenant has none (404).
+async fn get_error_information(
+    artifact_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Option<serde_json::Value>, Error> {
+    let api_error_url = format!(
+        "{api}/IntegrationRuntimeArtifacts('{id}')/ErrorInformation/$value",
+        api = config.tenant()?.api_url(),
+        id = artifact_id
+    );
+    let resp = http::send(config, Some(authorization), client.get(&api_error_url)).await?;
+    let resp_code = resp.status();
+    if resp_code == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let body_text = resp.text().await?;
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_error_url,
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
+    }
+    Ok(Some(
+        serde_json::from_str(&body_text).unwrap_or(serde_json::Value::String(body_text)),
+    ))
+}
+
+/// Writes `runtime-status.json` into each package directory with the deployment state
+/// of its artifacts, returns the artifacts whose deployed version differs from designtime.
+/// Artifacts that are not deployed are listed with `deployed: false` and have no drift.
+pub async fn write_runtime_status(
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &dyn Storage,
+    package_list: &[String],
+    artifacts: &[ArtifactReport],
+) -> Result<Vec<VersionDrift>, Error> {
+    println!("Fetching runtime status.");
+    let runtime_artifacts: BTreeMap<String, RuntimeArtifactResult> =
+        get_runtime_artifacts(config, client, authorization)
+            .await?
+            .into_iter()
+            .map(|a| (a.id.clone(), a))
+            .collect();
+
+    let mut version_drift = Vec::new();
+    for package_id in package_list.iter() {
+        let mut package_status = PackageRuntimeStatus {
+            package_id: package_id.clone(),
+            artifacts: Vec::new(),
+        };
+
+        for artifact in artifacts.iter().filter(|a| &a.package_id == package_id) {
+            let runtime = match runtime_artifacts.get(&artifact.artifact_id) {
+                Some(runtime) => runtime,
+                None => {
+                    package_status.artifacts.push(RuntimeArtifactStatus {
+                        artifact_id: artifact.artifact_id.clone(),
+                        artifact_type: artifact.artifact_type,
+                        name: None,
+                        runtime_type: None,
+                        designtime_version: artifact.version.clone(),
+                        deployed: false,
+                        runtime_version: None,
+                        version_drift: false,
+                        status: None,
+                        deployed_by: None,
+                        deployed_at: None,
+                        error_information: None,
+                    });
+                    continue;
+                }
+            };
+
+            let drift = match (&artifact.version, &runtime.version) {
+                (Some(designtime_version), Some(runtime_version))
+                    if designtime_version != runtime_version =>
+                {
+                    println!(
+                        "Version drift: {}/{}: designtime {}, deployed {}",
+                        package_id, artifact.artifact_id, designtime_version, runtime_version
+                    );
+                    version_drift.push(VersionDrift {
+                        package_id: package_id.clone(),
+                        artifact_id: artifact.artifact_id.clone(),
+                        designtime_version: designtime_version.clone(),
+                        runtime_version: runtime_version.clone(),
+                    });
+                    true
+                }
+                _ => false,
+            };
+
+            let error_information = match runtime.status.as_deref() {
+                Some("ERROR") => {
+                    get_error_information(&runtime.id, config, client, authorization).await?
+                }
+                _ => None,
+            };
+
+            package_status.artifacts.push(RuntimeArtifactStatus {
+                artifact_id: artifact.artifact_id.clone(),
+                artifact_type: artifact.artifact_type,
+                name: runtime.name.clone(),
+                runtime_type: runtime.runtime_type.clone(),
+                designtime_version: artifact.version.clone(),
+                deployed: true,
+                runtime_version: runtime.version.clone(),
+                version_drift: drift,
+                status: runtime.status.clone(),
+                deployed_by: runtime.deployed_by.clone(),
+                deployed_at: runtime.deployed_on.as_deref().map(odata_date),
+                error_information,
+            });
+        }
+        package_status
+            .artifacts
+            .sort_by(|a, b| a.artifact_id.cmp(&b.artifact_id));
+
+        storage.put_file(
+            &format!("{}/{}", package_id, RUNTIME_STATUS_FILE_NAME),
+            (serde_json::to_string_pretty(&package_status)? + "\n").as_bytes(),
+        )?;
+    }
+
+    if !version_drift.is_empty() {
+        println!("Artifacts with version drift: {}", version_drift.len());
+    }
+    Ok(version_drift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ArtifactStatus;
+    use crate::storage::MemoryStorage;
+    use crate::test_util;
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn artifact(artifact_id: &str, version: &str) -> ArtifactReport {
+        ArtifactReport {
+            package_id: "Pkg1".to_string(),
+            artifact_id: artifact_id.to_string(),
+            name: artifact_id.to_string(),
+            artifact_type: ArtifactType::IntegrationFlow,
+            version: Some(version.to_string()),
+            status: ArtifactStatus::Downloaded,
+            http_status: None,
+            error: None,
+            body_excerpt: None,
+            bytes: None,
+            duration_ms: 0,
+        }
+    }
+
+    async fn tenant(error_information: ResponseTemplate) -> MockServer {
+        let server = MockServer::start().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationRuntimeArtifacts",
+            json!([
+                { "Id": "Drift", "Version": "1.0.0", "Status": "STARTED",
+                  "DeployedOn": "/Date(1612176741000)/" },
+                { "Id": "Same", "Version": "1.0.0", "Status": "STARTED" },
+                { "Id": "Failed", "Version": "1.0.0", "Status": "ERROR" }
+            ]),
+        )
+        .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/api/v1/IntegrationRuntimeArtifacts('Failed')/ErrorInformation/$value",
+            ))
+            .respond_with(error_information)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    async fn write_status(
+        server: &MockServer,
+        storage: &MemoryStorage,
+    ) -> Result<Vec<VersionDrift>, Error> {
+        let config = test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "http": { "max_attempts": 1 }
+        }));
+        let client = http::client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+        let artifacts = [
+            artifact("Drift", "1.0.1"),
+            artifact("Same", "1.0.0"),
+            artifact("Failed", "1.0.0"),
+            artifact("Undeployed", "1.0.0"),
+        ];
+        write_runtime_status(
+            &config,
+            &client,
+            &authorization,
+            storage,
+            &["Pkg1".to_string()],
+            &artifacts,
+        )
+        .await
+    }
+
+    #[test]
+    fn odata_dates_are_rfc_3339() {
+        assert_eq!(
+            odata_date("/Date(1612176741000)/"),
+            "2021-02-01T10:52:21+00:00"
+        );
+        assert_eq!(odata_date("STARTED"), "STARTED");
+        assert_eq!(odata_date("/Date(x)/"), "/Date(x)/");
+    }
+
+    #[tokio::test]
+    async fn runtime_status_lists_deployed_and_undeployed_artifacts() {
+        let server = tenant(
+            ResponseTemplate::new(200).set_body_json(json!({ "message": "Deployment failed" })),
+        )
+        .await;
+        let storage = MemoryStorage::new();
+
+        let drift = write_status(&server, &storage).await.unwrap();
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].artifact_id, "Drift");
+        assert_eq!(drift[0].designtime_version, "1.0.1");
+        assert_eq!(drift[0].runtime_version, "1.0.0");
+
+        let status: Value = serde_json::from_slice(
+            &storage
+                .get_file("Pkg1/runtime-status.json")
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        let artifacts: BTreeMap<&str, &Value> = status["artifacts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| (a["artifact_id"].as_str().unwrap(), a))
+            .collect();
+        assert_eq!(artifacts.len(), 4);
+        assert_eq!(artifacts["Drift"]["version_drift"], true);
+        assert_eq!(
+            artifacts["Drift"]["deployed_at"],
+            "2021-02-01T10:52:21+00:00"
+        );
+        assert_eq!(artifacts["Same"]["version_drift"], false);
+        assert_eq!(
+            artifacts["Failed"]["error_information"]["message"],
+            "Deployment failed"
+        );
+        assert_eq!(artifacts["Undeployed"]["deployed"], false);
+        assert_eq!(artifacts["Undeployed"]["version_drift"], false);
+        assert_eq!(artifacts["Undeployed"]["runtime_version"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn missing_error_information_is_empty_other_failures_are_errors() {
+        let server = tenant(ResponseTemplate::new(404)).await;
+        let storage = MemoryStorage::new();
+        write_status(&server, &storage).await.unwrap();
+        let status: Value = serde_json::from_slice(
+            &storage
+                .get_file("Pkg1/runtime-status.json")
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(status["artifacts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|a| a["error_information"].is_null()));
+
+        let server = tenant(ResponseTemplate::new(500)).await;
+        assert!(matches!(
+            write_status(&server, &MemoryStorage::new()).await,
+            Err(Error::Api { status: 500, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unparseable_runtime_list_is_an_unexpected_response() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/IntegrationRuntimeArtifacts"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html>Login</html>"))
+            .mount(&server)
+            .await;
+        assert!(matches!(
+            write_status(&server, &MemoryStorage::new()).await,
+            Err(Error::UnexpectedResponse { url, .. }) if url.ends_with("/IntegrationRuntimeArtifacts")
+        ));
+    }
+}
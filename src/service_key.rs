@@ -0,0 +1,231 @@
+use crate::config::*;
+use crate::errors::Error;
+
+use jsonschema::{Draft, JSONSchema};
+use serde::Deserialize;
+use std::fs;
+
+#[derive(Deserialize, Debug)]
+struct ServiceKeyOauth {
+    clientid: String,
+    clientsecret: String,
+    url: String,
+    tokenurl: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServiceKey {
+    oauth: ServiceKeyOauth,
+}
+
+/// Validates the key against `#/definitions/service_key` of the config schema.
+fn validate_service_key(path: &str, key_json: &serde_json::Value) -> Result<(), Error> {
+    let mut schema: serde_json::Value =
+        serde_json::from_str(include_str!("../resources/config.schema.json"))?;
+    let definitions = schema["definitions"].take();
+    let key_schema = serde_json::json!({
+        "definitions": definitions,
+        "allOf": [{ "$ref": "#/definitions/service_key" }]
+    });
+    let compiled_schema = JSONSchema::options()
+        .with_draft(Draft::Draft7)
+        .compile(&key_schema)?;
+
+    if let Err(errors) = compiled_schema.validate(key_json) {
+        let messages: Vec<String> = errors.map(|e| e.to_string()).collect();
+        return Err(Error::JSONValidation(format!(
+            "Invalid service key: {}: {}",
+            path,
+            messages.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Host of an `https://` URL from the service key.
+fn url_host(field: &str, url: &str) -> Result<String, Error> {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .ok_or_else(|| Error::Config(format!("Invalid service key `{}`: {}", field, url)))
+}
+
+/// Loads the service key of a `service_key_file` credential into OAuth client credentials.
+/// `management_host` comes from `oauth.url` unless set in the config,
+/// the token endpoint is `oauth.tokenurl` with `/oauth/token`.
+/// Other credentials are returned unchanged.
+pub fn resolve_tenant(tenant: &Tenant) -> Result<Tenant, Error> {
+    let credential = match &tenant.credential {
+        CredentialInside::ServiceKeyFile(c) => c,
+        _ => return Ok(tenant.clone()),
+    };
+
+    let key_str = fs::read_to_string(&credential.path).map_err(|e| {
+        Error::Config(format!(
+            "Can not read service key file: {}: {}",
+            credential.path, e
+        ))
+    })?;
+    let key_json: serde_json::Value = serde_json::from_str(&key_str)?;
+    validate_service_key(&credential.path, &key_json)?;
+    let key: ServiceKey = serde_json::from_value(key_json)?;
+
+    let management_host = match tenant.management_host.is_empty() {
+        true => url_host("oauth.url", &key.oauth.url)?,
+        false => tenant.management_host.clone(),
+    };
+    url_host("oauth.tokenurl", &key.oauth.tokenurl)?;
+    let tokenurl = key.oauth.tokenurl.trim_end_matches('/');
+    let token_endpoint_url = match tokenurl.ends_with("/oauth/token") {
+        true => tokenurl.to_string(),
+        false => format!("{}/oauth/token", tokenurl),
+    };
+
+    Ok(Tenant {
+        management_host,
+        api_base_url: tenant.api_base_url.clone(),
+        credential: CredentialInside::OauthClientCredentials(CredentialOauthClientCredentials {
+            client_id: key.oauth.clientid,
+            token_endpoint_url,
+            client_secret_environment_variable: None,
+            client_secret_file: None,
+            token_cache_file: credential.token_cache_file.clone(),
+            client_secret: Some(key.oauth.clientsecret),
+        }),
+        name: tenant.name.clone(),
+    })
+}
+
+/// Error for a `service_key_file` credential that didn't go through `resolve_tenant`.
+pub fn not_loaded(credential: &CredentialServiceKeyFile) -> Error {
+    Error::Config(format!(
+        "Service key not loaded, use `Config::tenant_configs`: {}",
+        credential.path
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Resolves a tenant with a `service_key_file` credential for the given key.
+    fn resolve(management_host: &str, key: Value) -> Result<Tenant, Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.json");
+        fs::write(&path, key.to_string()).unwrap();
+        let tenant: Tenant = serde_json::from_value(json!({
+            "management_host": management_host,
+            "credential": {
+                "service_key_file": {
+                    "path": path.to_str().unwrap(),
+                    "token_cache_file": "token.json"
+                }
+            }
+        }))
+        .unwrap();
+        resolve_tenant(&tenant)
+    }
+
+    fn key(url: &str, tokenurl: &str) -> Value {
+        json!({
+            "oauth": {
+                "clientid": "sb-client",
+                "clientsecret": "secret",
+                "url": url,
+                "tokenurl": tokenurl
+            }
+        })
+    }
+
+    fn token_endpoint_url(tenant: &Tenant) -> &str {
+        match &tenant.credential {
+            CredentialInside::OauthClientCredentials(c) => &c.token_endpoint_url,
+            _ => panic!("not an OAuth client credential"),
+        }
+    }
+
+    #[test]
+    fn key_is_loaded_into_oauth_client_credentials() {
+        let tenant = resolve(
+            "",
+            key(
+                "https://tenant.it-cpi001.cfapps.eu10.hana.ondemand.com",
+                "https://tenant.authentication.eu10.hana.ondemand.com/oauth/token",
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            tenant.management_host,
+            "tenant.it-cpi001.cfapps.eu10.hana.ondemand.com"
+        );
+        match &tenant.credential {
+            CredentialInside::OauthClientCredentials(c) => {
+                assert_eq!(c.client_id, "sb-client");
+                assert_eq!(c.client_secret.as_deref(), Some("secret"));
+                assert_eq!(c.token_cache_file.as_deref(), Some("token.json"));
+            }
+            _ => panic!("not an OAuth client credential"),
+        }
+    }
+
+    #[test]
+    fn management_host_of_the_config_is_kept() {
+        let tenant = resolve(
+            "custom.example.com",
+            key("https://tenant.example.com/", "https://auth.example.com"),
+        )
+        .unwrap();
+        assert_eq!(tenant.management_host, "custom.example.com");
+    }
+
+    #[test]
+    fn oauth_token_is_appended_once() {
+        for tokenurl in [
+            "https://auth.example.com",
+            "https://auth.example.com/",
+            "https://auth.example.com/oauth/token",
+            "https://auth.example.com/oauth/token/",
+        ] {
+            let tenant = resolve("", key("https://tenant.example.com", tokenurl)).unwrap();
+            assert_eq!(
+                token_endpoint_url(&tenant),
+                "https://auth.example.com/oauth/token",
+                "{}",
+                tokenurl
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        let mut missing_secret = key("https://tenant.example.com", "https://auth.example.com");
+        missing_secret["oauth"]
+            .as_object_mut()
+            .unwrap()
+            .remove("clientsecret");
+        for key in [
+            json!({}),
+            json!({ "oauth": "sb-client" }),
+            missing_secret,
+            key("http://tenant.example.com", "https://auth.example.com"),
+            key("https://tenant.example.com", "auth.example.com"),
+            key("", "https://auth.example.com"),
+        ] {
+            assert!(
+                matches!(resolve("", key.clone()), Err(Error::JSONValidation(_))),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn missing_key_file_is_a_config_error() {
+        let tenant: Tenant = serde_json::from_value(json!({
+            "credential": { "service_key_file": { "path": "does-not-exist.json" } }
+        }))
+        .unwrap();
+        assert!(matches!(resolve_tenant(&tenant), Err(Error::Config(_))));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::config::*;
+use crate::errors::Error;
+use crate::report::TenantReport;
+use crate::safe_file_name;
+use crate::state::{content_hash, STATE_FILE_NAME};
+use crate::storage::{is_below, Storage};
+
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+// sorts by time, e.g. 20240131T235959Z
+const SNAPSHOT_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+#[derive(Serialize, Debug)]
+struct ManifestArtifact {
+    package_id: String,
+    artifact_id: String,
+    artifact_type: ArtifactType,
+    version: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ManifestFile {
+    path: String,
+    bytes: u64,
+    sha256: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Manifest {
+    management_host: String,
+    created_at: String,
+    selected_packages: Vec<String>,
+    // downloaded and skipped artifacts, failed ones are missing in the snapshot
+    artifacts: Vec<ManifestArtifact>,
+    // sorted by path, without the manifest itself, which is the last entry of the archive
+    files: Vec<ManifestFile>,
+}
+
+/// Snapshot file name prefix of the tenant: `<tenant name>_` for tenants of the `tenants` map,
+/// otherwise `<management_host>_`.
+pub fn snapshot_prefix(config: &Config) -> Result<String, Error> {
+    let tenant = config.tenant()?;
+    let name = tenant.name.as_ref().unwrap_or(&tenant.management_host);
+    Ok(format!("{}_", safe_file_name(name)))
+}
+
+/// Timestamp part of `<prefix><timestamp>.<extension>`, `None` for other files.
+fn snapshot_timestamp<'a>(file_name: &'a str, prefix: &str, extension: &str) -> Option<&'a str> {
+    file_name
+        .strip_prefix(prefix)
+        .and_then(|n| n.strip_suffix(extension))
+        .and_then(|n| n.strip_suffix('.'))
+        .filter(|t| chrono::NaiveDateTime::parse_from_str(t, SNAPSHOT_TIMESTAMP_FORMAT).is_ok())
+}
+
+// archive file of the snapshot, entries are appended as the sync writes them
+enum ArchiveWriter {
+    TarGz(tar::Builder<flate2::write::GzEncoder<fs::File>>),
+    Zip(zip::ZipWriter<fs::File>),
+}
+
+impl ArchiveWriter {
+    fn append(&mut self, path: &str, content: &[u8], mtime: u64) -> Result<(), Error> {
+        match self {
+            ArchiveWriter::TarGz(archive) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(content.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(mtime);
+                archive.append_data(&mut header, path, content)?;
+            }
+            ArchiveWriter::Zip(archive) => {
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated);
+                archive.start_file(path, options)?;
+                archive.write_all(content)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self {
+            ArchiveWriter::TarGz(archive) => {
+                archive.into_inner()?.finish()?;
+            }
+            ArchiveWriter::Zip(mut archive) => {
+                archive.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ArchiveState {
+    // `None` once the snapshot is finished
+    writer: Option<ArchiveWriter>,
+    files: BTreeMap<String, ManifestFile>,
+}
+
+/// Storage that streams the files of a sync run into a snapshot archive
+/// `<prefix><timestamp>.<tar.gz|zip>` in `data_dir`, see `snapshot_prefix`.
+/// Written files can not be read or removed again, the archive is only
+/// complete after `finish`, an unfinished archive is removed on drop.
+pub struct ArchiveStorage {
+    state: Mutex<ArchiveState>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    snapshot_path: PathBuf,
+    // a partial archive never matches the retention pattern
+    partial_path: PathBuf,
+}
+
+impl ArchiveStorage {
+    pub fn create(config: &Config, data_dir: &Path) -> Result<ArchiveStorage, Error> {
+        let created_at = chrono::Utc::now();
+        let snapshot_path = snapshot_path(config, data_dir, &created_at)?;
+        let partial_path = snapshot_path.with_extension("partial");
+        let file = fs::File::create(&partial_path)?;
+        let writer = match config.output.format {
+            OutputFormat::Zip => ArchiveWriter::Zip(zip::ZipWriter::new(file)),
+            _ => ArchiveWriter::TarGz(tar::Builder::new(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            ))),
+        };
+        Ok(ArchiveStorage {
+            state: Mutex::new(ArchiveState {
+                writer: Some(writer),
+                files: BTreeMap::new(),
+            }),
+            created_at,
+            snapshot_path,
+            partial_path,
+        })
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, ArchiveState>, Error> {
+        self.state
+            .lock()
+            .map_err(|_| Error::Filesystem("Snapshot lock poisoned".to_string()))
+    }
+
+    /// Appends `manifest.json`, completes the archive and applies the retention, returns its path.
+    pub fn finish(&self, config: &Config, tenant_report: &TenantReport) -> Result<PathBuf, Error> {
+        let mut state = self.lock()?;
+        let mut writer = state
+            .writer
+            .take()
+            .ok_or(Error::Filesystem("Snapshot already finished".to_string()))?;
+        let files: Vec<ManifestFile> = std::mem::take(&mut state.files).into_values().collect();
+        let file_count = files.len() + 1;
+
+        let manifest = Manifest {
+            management_host: tenant_report.management_host.clone(),
+            created_at: self.created_at.to_rfc3339(),
+            selected_packages: tenant_report.selected_packages.clone(),
+            artifacts: tenant_report
+                .artifacts
+                .iter()
+                .filter(|a| a.error.is_none())
+                .map(|a| ManifestArtifact {
+                    package_id: a.package_id.clone(),
+                    artifact_id: a.artifact_id.clone(),
+                    artifact_type: a.artifact_type,
+                    version: a.version.clone(),
+                })
+                .collect(),
+            files,
+        };
+        writer.append(
+            MANIFEST_FILE_NAME,
+            (serde_json::to_string_pretty(&manifest)? + "\n").as_bytes(),
+            self.mtime(),
+        )?;
+        writer.finish()?;
+        fs::rename(&self.partial_path, &self.snapshot_path)?;
+        println!(
+            "Snapshot written: {} files to {}",
+            file_count,
+            self.snapshot_path.display()
+        );
+
+        if let (Some(retention), Some(data_dir)) =
+            (config.output.retention, self.snapshot_path.parent())
+        {
+            apply_snapshot_retention(config, data_dir, retention)?;
+        }
+        Ok(self.snapshot_path.clone())
+    }
+
+    fn mtime(&self) -> u64 {
+        self.created_at.timestamp().max(0) as u64
+    }
+}
+
+impl Drop for ArchiveStorage {
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.get_mut() {
+            //the file is closed before it is removed
+            if state.writer.take().is_some() {
+                let _ = fs::remove_file(&self.partial_path);
+            }
+        }
+    }
+}
+
+impl Storage for ArchiveStorage {
+    fn put_file(&self, path: &str, content: &[u8]) -> Result<(), Error> {
+        //the sync state is only used by directory output
+        if path == STATE_FILE_NAME {
+            return Ok(());
+        }
+        let mtime = self.mtime();
+        let mut state = self.lock()?;
+        if state.files.contains_key(path) {
+            return Err(Error::Filesystem(format!(
+                "File already written to the snapshot: {}",
+                path
+            )));
+        }
+        match state.writer.as_mut() {
+            Some(writer) => writer.append(path, content, mtime)?,
+            None => return Err(Error::Filesystem("Snapshot already finished".to_string())),
+        }
+        state.files.insert(
+            path.to_string(),
+            ManifestFile {
+                path: path.to_string(),
+                bytes: content.len() as u64,
+                sha256: content_hash(content),
+            },
+        );
+        Ok(())
+    }
+
+    //every snapshot is a full download, nothing is read back
+    fn get_file(&self, _path: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    fn remove_tree(&self, path: &str) -> Result<(), Error> {
+        match self.list(path)?.first() {
+            Some(file) => Err(Error::Filesystem(format!(
+                "File can not be removed from the snapshot: {}",
+                file
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .lock()?
+            .files
+            .keys()
+            .filter(|file| is_below(file, path))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Snapshots of the tenant in `data_dir` that exceed `retention`, oldest first.
+pub fn expired_snapshots(
+    config: &Config,
+    data_dir: &Path,
+    retention: usize,
+) -> Result<Vec<PathBuf>, Error> {
+    let extension = snapshot_extension(config)?;
+    let prefix = snapshot_prefix(config)?;
+    if !data_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut snapshots: Vec<(String, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        let timestamp = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| snapshot_timestamp(n, &prefix, extension));
+        if let Some(timestamp) = timestamp {
+            snapshots.push((timestamp.to_string(), path));
+        }
+    }
+    snapshots.sort();
+
+    let remove_count = snapshots.len().saturating_sub(retention);
+    Ok(snapshots
+        .into_iter()
+        .take(remove_count)
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// Removes the oldest snapshots of the tenant, keeping `retention` files.
+fn apply_snapshot_retention(
+    config: &Config,
+    data_dir: &Path,
+    retention: usize,
+) -> Result<(), Error> {
+    for path in expired_snapshots(config, data_dir, retention)? {
+        println!("Removing old snapshot: {}", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn snapshot_extension(config: &Config) -> Result<&'static str, Error> {
+    config
+        .output
+        .format
+        .extension()
+        .ok_or(Error::Config("Output format has no archive".to_string()))
+}
+
+/// Path of a new snapshot of the tenant in `data_dir`: `<prefix><timestamp>.<tar.gz|zip>`.
+pub fn snapshot_path(
+    config: &Config,
+    data_dir: &Path,
+    now: &chrono::DateTime<chrono::Utc>,
+) -> Result<PathBuf, Error> {
+    Ok(data_dir.join(format!(
+        "{}{}.{}",
+        snapshot_prefix(config)?,
+        now.format(SNAPSHOT_TIMESTAMP_FORMAT),
+        snapshot_extension(config)?
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::io::Read;
+
+    fn tenant_config(format: &str) -> Config {
+        let tenant = serde_json::json!({
+            "management_host": "dev.example.com",
+            "credential": test_util::s_user()
+        });
+        test_util::config(serde_json::json!({
+            "tenants": { "DEV": tenant },
+            "output": { "format": format, "retention": 1 }
+        }))
+        .tenant_configs(&TenantSelection::All)
+        .unwrap()
+        .remove(0)
+        .1
+    }
+
+    #[test]
+    fn prefix_uses_the_tenant_name() {
+        let config = tenant_config("zip");
+        assert_eq!(snapshot_prefix(&config).unwrap(), "DEV_");
+
+        let mut config = config;
+        config.tenant.as_mut().unwrap().name = None;
+        assert_eq!(snapshot_prefix(&config).unwrap(), "dev.example.com_");
+    }
+
+    #[test]
+    fn tar_gz_snapshot_is_streamed_with_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = tenant_config("tar.gz");
+        let old_snapshot = dir.path().join("DEV_20200101T000000Z.tar.gz");
+        fs::write(&old_snapshot, b"").unwrap();
+
+        let storage = ArchiveStorage::create(&config, dir.path()).unwrap();
+        storage.put_file("Pkg/b.txt", b"b").unwrap();
+        storage.put_file("Pkg/a.txt", b"a").unwrap();
+        storage.put_file(STATE_FILE_NAME, b"{}").unwrap();
+        assert_eq!(storage.list("Pkg").unwrap(), vec!["Pkg/a.txt", "Pkg/b.txt"]);
+        assert_eq!(storage.get_file("Pkg/a.txt").unwrap(), None);
+        assert!(storage.put_file("Pkg/a.txt", b"a2").is_err());
+        assert!(storage.remove_tree("Pkg").is_err());
+        storage.remove_tree("Other").unwrap();
+
+        let path = storage.finish(&config, &TenantReport::default()).unwrap();
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("DEV_"));
+        assert!(!old_snapshot.exists());
+
+        let mut entries = BTreeMap::new();
+        let mut archive =
+            tar::Archive::new(flate2::read::GzDecoder::new(fs::File::open(&path).unwrap()));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.insert(entry.path().unwrap().to_string_lossy().to_string(), content);
+        }
+        assert_eq!(
+            entries.keys().collect::<Vec<_>>(),
+            vec!["Pkg/a.txt", "Pkg/b.txt", MANIFEST_FILE_NAME]
+        );
+        let manifest: serde_json::Value =
+            serde_json::from_str(&entries[MANIFEST_FILE_NAME]).unwrap();
+        assert_eq!(manifest["files"][0]["path"], "Pkg/a.txt");
+        assert_eq!(manifest["files"][0]["sha256"], content_hash(b"a"));
+        assert_eq!(manifest["files"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn unfinished_snapshot_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = ArchiveStorage::create(&tenant_config("zip"), dir.path()).unwrap();
+        storage.put_file("Pkg/a.txt", b"a").unwrap();
+        drop(storage);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::config::{ArtifactType, IncrementalSync, Packages};
+use crate::errors::Error;
+use crate::storage::Storage;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub const STATE_FILE_NAME: &str = ".cpisync-state.json";
+
+// local sync state, used for incremental sync
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncState {
+    // hash of the package settings that change the written files, see `settings_hash`
+    #[serde(default)]
+    pub settings_hash: Option<String>,
+    pub packages: BTreeMap<String, PackageState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PackageState {
+    pub artifacts: BTreeMap<String, ArtifactState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArtifactState {
+    pub artifact_type: ArtifactType,
+    pub version: Option<String>,
+    pub modified_at: Option<String>,
+    pub content_hash: String,
+    // hash of the written configurations file of an iFlow
+    #[serde(default)]
+    pub configurations_hash: Option<String>,
+}
+
+impl ArtifactState {
+    /// Same version and modification time as the tenant list reports.
+    pub fn is_unchanged(&self, version: &Option<String>, modified_at: &Option<String>) -> bool {
+        self.version.is_some() && &self.version == version && &self.modified_at == modified_at
+    }
+}
+
+impl SyncState {
+    /// Loads the state file from the storage root, a missing or unreadable file is an empty state.
+    pub fn load(storage: &dyn Storage) -> Result<SyncState, Error> {
+        match storage.get_file(STATE_FILE_NAME)? {
+            Some(state_bytes) => match serde_json::from_slice(&state_bytes) {
+                Ok(state) => Ok(state),
+                //the full sync writes a new state file
+                Err(err) => {
+                    println!("Sync state file is unreadable, ignoring it: {}", err);
+                    Ok(SyncState::default())
+                }
+            },
+            None => Ok(SyncState::default()),
+        }
+    }
+
+    /// Incremental sync is enabled and the state was written with the same settings,
+    /// otherwise every artifact is downloaded again.
+    pub fn is_incremental(&self, packages: &Packages) -> Result<bool, Error> {
+        Ok(
+            matches!(packages.incremental_sync, IncrementalSync::Enabled)
+                && self.settings_hash == Some(settings_hash(packages)?),
+        )
+    }
+
+    pub fn save(&self, storage: &dyn Storage) -> Result<(), Error> {
+        let state_str = serde_json::to_string_pretty(self)?;
+        storage.put_file(STATE_FILE_NAME, (state_str + "\n").as_bytes())
+    }
+}
+
+/// Hash of the settings that change the written artifact files.
+pub fn settings_hash(packages: &Packages) -> Result<String, Error> {
+    let settings = serde_json::json!({
+        "zip_extraction": packages.zip_extraction,
+        "prop_comment_removal": packages.prop_comment_removal,
+        "normalization": packages.normalization,
+        "artifact_type_folders": packages.artifact_type_folders,
+    });
+    Ok(content_hash(&serde_json::to_vec(&settings)?))
+}
+
+pub fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Hash of extracted files, independent of the entry order in the ZIP.
+pub fn entries_hash(entries: &[(String, Vec<u8>)]) -> String {
+    let mut sorted: Vec<&(String, Vec<u8>)> = entries.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hasher = Sha256::new();
+    for (path, content) in sorted {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(content);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, PropCommentRemoval};
+    use crate::storage::MemoryStorage;
+    use crate::test_util;
+    use serde_json::json;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Tenant with the given iFlows and versions in `Pkg1`, the content names the version.
+    async fn tenant(flows: &[(&str, &str)]) -> MockServer {
+        let server = test_util::mock_server().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages",
+            json!([{ "Id": "Pkg1", "Name": "Pkg1" }]),
+        )
+        .await;
+        let flow_list: Vec<serde_json::Value> = flows
+            .iter()
+            .map(|(id, version)| json!({ "Id": id, "Name": id, "Version": version }))
+            .collect();
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts",
+            json!(flow_list),
+        )
+        .await;
+        for (artifact_id, version) in flows {
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "/api/v1/IntegrationDesigntimeArtifacts(Id='{}',Version='Active')/$value",
+                    artifact_id
+                )))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_bytes(test_util::zip(&[("flow.iflw", version)])),
+                )
+                .mount(&server)
+                .await;
+            test_util::mount_results(
+                &server,
+                &format!(
+                    "/IntegrationDesigntimeArtifacts(Id='{}',Version='Active')/Configurations",
+                    artifact_id
+                ),
+                json!([{ "ParameterKey": "url", "ParameterValue": "https://example.com" }]),
+            )
+            .await;
+        }
+        server
+    }
+
+    fn tenant_config(server: &MockServer, zip_extraction: &str) -> Config {
+        test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "packages": {
+                "filter_rules": [{ "type": "single", "id": "Pkg1" }],
+                "artifact_types": ["IntegrationDesigntimeArtifacts"],
+                "incremental_sync": "enabled",
+                "zip_extraction": zip_extraction,
+                "configuration_export": "json"
+            },
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    async fn sync(config: &Config, storage: &Arc<MemoryStorage>) {
+        crate::sync_with_storage(config, "secret", storage.clone(), false)
+            .await
+            .unwrap();
+    }
+
+    /// Artifact downloads the tenant received for the iFlow.
+    async fn downloads(server: &MockServer, artifact_id: &str) -> usize {
+        let download_path = format!(
+            "/api/v1/IntegrationDesigntimeArtifacts(Id='{}',Version='Active')/$value",
+            artifact_id
+        );
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|r| r.url.path() == download_path)
+            .count()
+    }
+
+    fn file(storage: &MemoryStorage, path: &str) -> Option<String> {
+        storage
+            .get_file(path)
+            .unwrap()
+            .map(|content| String::from_utf8(content).unwrap())
+    }
+
+    #[tokio::test]
+    async fn unchanged_version_is_not_downloaded_again() {
+        let server = tenant(&[("Flow1", "1.0.0")]).await;
+        let config = tenant_config(&server, "enabled");
+        let storage = Arc::new(MemoryStorage::new());
+        sync(&config, &storage).await;
+        sync(&config, &storage).await;
+
+        assert_eq!(downloads(&server, "Flow1").await, 1);
+        assert_eq!(
+            file(&storage, "Pkg1/IntegrationFlows/Flow1/flow.iflw").as_deref(),
+            Some("1.0.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn version_bump_is_downloaded() {
+        let storage = Arc::new(MemoryStorage::new());
+        let server = tenant(&[("Flow1", "1.0.0")]).await;
+        sync(&tenant_config(&server, "enabled"), &storage).await;
+
+        let server = tenant(&[("Flow1", "1.0.1")]).await;
+        sync(&tenant_config(&server, "enabled"), &storage).await;
+
+        assert_eq!(downloads(&server, "Flow1").await, 1);
+        assert_eq!(
+            file(&storage, "Pkg1/IntegrationFlows/Flow1/flow.iflw").as_deref(),
+            Some("1.0.1")
+        );
+        let state = SyncState::load(storage.as_ref()).unwrap();
+        assert_eq!(
+            state.packages["Pkg1"].artifacts["Flow1"].version.as_deref(),
+            Some("1.0.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn artifact_removed_on_the_tenant_is_deleted() {
+        for (zip_extraction, artifact_path) in [
+            ("enabled", "Pkg1/IntegrationFlows/Flow2/flow.iflw"),
+            ("disabled", "Pkg1/IntegrationFlows/Flow2.zip"),
+        ] {
+            let storage = Arc::new(MemoryStorage::new());
+            let server = tenant(&[("Flow1", "1.0.0"), ("Flow2", "1.0.0")]).await;
+            sync(&tenant_config(&server, zip_extraction), &storage).await;
+            let configurations_path = "Pkg1/IntegrationFlows/Flow2.configurations.json";
+            assert!(storage.get_file(artifact_path).unwrap().is_some());
+            assert!(file(&storage, configurations_path).is_some());
+
+            let server = tenant(&[("Flow1", "1.0.0")]).await;
+            sync(&tenant_config(&server, zip_extraction), &storage).await;
+
+            assert_eq!(downloads(&server, "Flow1").await, 0);
+            assert!(storage
+                .list("Pkg1/IntegrationFlows/Flow2")
+                .unwrap()
+                .is_empty());
+            assert_eq!(storage.get_file(artifact_path).unwrap(), None);
+            assert_eq!(file(&storage, configurations_path), None);
+            let state = SyncState::load(storage.as_ref()).unwrap();
+            assert!(!state.packages["Pkg1"].artifacts.contains_key("Flow2"));
+        }
+    }
+
+    #[tokio::test]
+    async fn changed_settings_force_a_full_download() {
+        let server = tenant(&[("Flow1", "1.0.0")]).await;
+        let mut config = tenant_config(&server, "enabled");
+        let storage = Arc::new(MemoryStorage::new());
+        sync(&config, &storage).await;
+        let state = SyncState::load(storage.as_ref()).unwrap();
+        assert_eq!(
+            state.settings_hash,
+            Some(settings_hash(&config.packages).unwrap())
+        );
+
+        config.packages.prop_comment_removal = PropCommentRemoval::Enabled;
+        assert!(!state.is_incremental(&config.packages).unwrap());
+        sync(&config, &storage).await;
+
+        assert_eq!(downloads(&server, "Flow1").await, 2);
+        let state = SyncState::load(storage.as_ref()).unwrap();
+        assert!(state.is_incremental(&config.packages).unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_or_corrupt_state_falls_back_to_a_full_sync() {
+        let server = tenant(&[("Flow1", "1.0.0")]).await;
+        let config = tenant_config(&server, "enabled");
+        let storage = Arc::new(MemoryStorage::new());
+        sync(&config, &storage).await;
+
+        storage.remove_tree(STATE_FILE_NAME).unwrap();
+        sync(&config, &storage).await;
+        assert_eq!(downloads(&server, "Flow1").await, 2);
+
+        storage.put_file(STATE_FILE_NAME, b"{ not json").unwrap();
+        assert!(SyncState::load(storage.as_ref())
+            .unwrap()
+            .packages
+            .is_empty());
+        sync(&config, &storage).await;
+        assert_eq!(downloads(&server, "Flow1").await, 3);
+
+        //the full sync writes a readable state again
+        let state = SyncState::load(storage.as_ref()).unwrap();
+        assert!(state.packages["Pkg1"].artifacts.contains_key("Flow1"));
+    }
+}
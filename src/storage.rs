@@ -0,0 +1,299 @@
+use crate::errors::Error;
+
+use path_slash::PathBufExt;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Output of a sync: artifacts, configurations, package metadata and the sync state.
+/// Paths are relative to the storage root and use `/` separators, e.g. `Pkg/IntegrationFlows/Flow1/META-INF/MANIFEST.MF`.
+pub trait Storage: Send + Sync {
+    /// Writes the file, parent directories are created.
+    fn put_file(&self, path: &str, content: &[u8]) -> Result<(), Error>;
+
+    /// Reads the file, `None` if it doesn't exist.
+    fn get_file(&self, path: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Removes the file or the directory tree at the path, a missing path is not an error.
+    fn remove_tree(&self, path: &str) -> Result<(), Error>;
+
+    /// Files at or below the path, sorted. Empty if the path doesn't exist, an empty path lists all files.
+    fn list(&self, path: &str) -> Result<Vec<String>, Error>;
+}
+
+/// File below the storage path, or the path itself.
+pub(crate) fn is_below(file: &str, path: &str) -> bool {
+    path.is_empty()
+        || file == path
+        || file
+            .strip_prefix(path)
+            .map(|rest| rest.starts_with('/'))
+            .unwrap_or(false)
+}
+
+/// Files in a local directory, the default storage.
+pub struct FileSystemStorage {
+    root: PathBuf,
+}
+
+impl FileSystemStorage {
+    pub fn new(root: impl Into<PathBuf>) -> FileSystemStorage {
+        FileSystemStorage { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn full_path(&self, path: &str) -> PathBuf {
+        self.root.join(PathBuf::from_slash(path))
+    }
+}
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else {
+            let relative_path = path
+                .strip_prefix(root)
+                .map_err(|e| Error::Filesystem(e.to_string()))?
+                .to_path_buf()
+                .to_slash()
+                .ok_or(Error::Filesystem("to_slash".to_string()))?;
+            files.push(relative_path);
+        }
+    }
+    Ok(())
+}
+
+impl Storage for FileSystemStorage {
+    fn put_file(&self, path: &str, content: &[u8]) -> Result<(), Error> {
+        let full_path = self.full_path(path);
+        let parent_dir = full_path
+            .parent()
+            .ok_or(Error::Filesystem("No parent found".to_string()))?;
+        fs::create_dir_all(parent_dir)?;
+        fs::write(full_path, content)?;
+        Ok(())
+    }
+
+    fn get_file(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let full_path = self.full_path(path);
+        if !full_path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read(full_path)?))
+    }
+
+    fn remove_tree(&self, path: &str) -> Result<(), Error> {
+        let full_path = self.full_path(path);
+        if full_path.is_dir() {
+            remove_dir_all::remove_dir_all(full_path)?;
+        } else if full_path.is_file() {
+            fs::remove_file(full_path)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, Error> {
+        let full_path = self.full_path(path);
+        let mut files = Vec::new();
+        if full_path.is_file() {
+            files.push(path.to_string());
+        } else if full_path.is_dir() {
+            collect_files(&self.root, &full_path, &mut files)?;
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// Files kept in memory, for embedding and tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Copy of all stored files by path.
+    pub fn files(&self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        Ok(self.lock()?.clone())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, BTreeMap<String, Vec<u8>>>, Error> {
+        self.files
+            .lock()
+            .map_err(|_| Error::Filesystem("Storage lock poisoned".to_string()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put_file(&self, path: &str, content: &[u8]) -> Result<(), Error> {
+        self.lock()?.insert(path.to_string(), content.to_vec());
+        Ok(())
+    }
+
+    fn get_file(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.lock()?.get(path).cloned())
+    }
+
+    fn remove_tree(&self, path: &str) -> Result<(), Error> {
+        self.lock()?.retain(|file, _| !is_below(file, path));
+        Ok(())
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .lock()?
+            .keys()
+            .filter(|file| is_below(file, path))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_util;
+    use serde_json::json;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn check_storage(storage: &dyn Storage) {
+        assert_eq!(storage.list("").unwrap(), Vec::<String>::new());
+        assert_eq!(storage.get_file("Pkg/a.txt").unwrap(), None);
+
+        storage.put_file("Pkg/Flow/b.txt", b"b").unwrap();
+        storage.put_file("Pkg/a.txt", b"a").unwrap();
+        storage.put_file("Pkg2/c.txt", b"c").unwrap();
+        storage.put_file("Pkg/a.txt", b"a2").unwrap();
+
+        assert_eq!(storage.get_file("Pkg/a.txt").unwrap(), Some(b"a2".to_vec()));
+        assert_eq!(storage.get_file("Pkg").unwrap(), None);
+        assert_eq!(
+            storage.list("").unwrap(),
+            vec!["Pkg/Flow/b.txt", "Pkg/a.txt", "Pkg2/c.txt"]
+        );
+        assert_eq!(
+            storage.list("Pkg").unwrap(),
+            vec!["Pkg/Flow/b.txt", "Pkg/a.txt"]
+        );
+        assert_eq!(storage.list("Pkg/a.txt").unwrap(), vec!["Pkg/a.txt"]);
+        // a prefix of a folder name is not a parent folder
+        assert_eq!(storage.list("Pk").unwrap(), Vec::<String>::new());
+
+        storage.remove_tree("Pkg/Flow").unwrap();
+        assert_eq!(storage.list("Pkg").unwrap(), vec!["Pkg/a.txt"]);
+        storage.remove_tree("Pkg2/c.txt").unwrap();
+        storage.remove_tree("Missing").unwrap();
+        assert_eq!(storage.list("").unwrap(), vec!["Pkg/a.txt"]);
+    }
+
+    #[test]
+    fn file_system_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSystemStorage::new(dir.path());
+        check_storage(&storage);
+        assert_eq!(
+            fs::read(dir.path().join("Pkg").join("a.txt")).unwrap(),
+            b"a2"
+        );
+    }
+
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::new();
+        check_storage(&storage);
+        assert_eq!(storage.files().unwrap().len(), 1);
+    }
+
+    async fn tenant(flow_status: u16) -> MockServer {
+        let server = test_util::mock_server().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages",
+            json!([
+                { "Id": "Pkg1", "Name": "Package 1" },
+                { "Id": "Pkg2", "Name": "Package 2" }
+            ]),
+        )
+        .await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts",
+            json!([{ "Id": "Flow1", "Name": "Flow 1", "Version": "1.0.0" }]),
+        )
+        .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/api/v1/IntegrationDesigntimeArtifacts(Id='Flow1',Version='Active')/$value",
+            ))
+            .respond_with(
+                ResponseTemplate::new(flow_status).set_body_bytes(test_util::zip(&[(
+                    "META-INF/MANIFEST.MF",
+                    "Bundle-SymbolicName: Flow1",
+                )])),
+            )
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn tenant_config(server: &MockServer) -> Config {
+        test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "packages": {
+                "filter_rules": [{ "type": "single", "id": "Pkg1" }],
+                "artifact_types": ["IntegrationDesigntimeArtifacts"]
+            },
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    #[tokio::test]
+    async fn sync_into_memory_storage() {
+        let server = tenant(200).await;
+        let storage = Arc::new(MemoryStorage::new());
+        crate::sync_with_storage(&tenant_config(&server), "secret", storage.clone(), false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .get_file("Pkg1/IntegrationFlows/Flow1/META-INF/MANIFEST.MF")
+                .unwrap(),
+            Some(b"Bundle-SymbolicName: Flow1".to_vec())
+        );
+        assert!(storage.list("Pkg2").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_download_fails_the_sync() {
+        let server = tenant(500).await;
+        let config = tenant_config(&server);
+
+        let storage = Arc::new(MemoryStorage::new());
+        assert!(
+            crate::sync_with_storage(&config, "secret", storage.clone(), false)
+                .await
+                .is_err()
+        );
+        assert!(storage.list("Pkg1/IntegrationFlows").unwrap().is_empty());
+
+        crate::sync_with_storage(&config, "secret", Arc::new(MemoryStorage::new()), true)
+            .await
+            .unwrap();
+    }
+}
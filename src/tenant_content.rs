@@ -0,0 +1,485 @@
+use crate::auth::Authorization;
+use crate::config::*;
+use crate::errors::Error;
+use crate::runtime::odata_date;
+use crate::storage::Storage;
+use crate::{http, safe_file_name, unexpected_response};
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+pub const TENANT_CONTENT_DIR_NAME: &str = "tenant";
+// public certificates of the keystore entries
+const CERTIFICATE_DIR_NAME: &str = "certificates";
+
+/// API properties written per content type and their local names.
+/// Only metadata is listed, passwords and client secrets are never written.
+fn exported_properties(content_type: TenantContentType) -> &'static [(&'static str, &'static str)] {
+    match content_type {
+        TenantContentType::Keystore => &[
+            ("Alias", "alias"),
+            ("Hexalias", "hexalias"),
+            ("Type", "type"),
+            ("KeyType", "key_type"),
+            ("KeySize", "key_size"),
+            ("SignatureAlgorithm", "signature_algorithm"),
+            ("SerialNumber", "serial_number"),
+            ("SubjectDN", "subject_dn"),
+            ("IssuerDN", "issuer_dn"),
+            ("ValidNotBefore", "valid_not_before"),
+            ("ValidNotAfter", "valid_not_after"),
+            ("Owner", "owner"),
+            ("LastModifiedBy", "last_modified_by"),
+            ("LastModifiedTime", "last_modified_time"),
+        ],
+        TenantContentType::UserCredentials => &[
+            ("Name", "name"),
+            ("Kind", "kind"),
+            ("Description", "description"),
+        ],
+        TenantContentType::OAuth2Credentials => &[
+            ("Name", "name"),
+            ("Description", "description"),
+            ("TokenServiceUrl", "token_service_url"),
+            ("ClientAuthentication", "client_authentication"),
+            ("Scope", "scope"),
+            ("Resource", "resource"),
+            ("Audience", "audience"),
+        ],
+        TenantContentType::NumberRanges => &[
+            ("Name", "name"),
+            ("Description", "description"),
+            ("MinValue", "min_value"),
+            ("MaxValue", "max_value"),
+            ("FieldLength", "field_length"),
+            ("Rotate", "rotate"),
+            ("CurrentValue", "current_value"),
+        ],
+        TenantContentType::JmsQueues => &[
+            ("Name", "name"),
+            ("Type", "type"),
+            ("Usage", "usage"),
+            ("State", "state"),
+            ("Active", "active"),
+            ("AccessType", "access_type"),
+        ],
+        TenantContentType::Variables => &[
+            ("VariableName", "variable_name"),
+            ("IntegrationFlow", "integration_flow"),
+            ("Visibility", "visibility"),
+            ("UpdatedAt", "updated_at"),
+            ("RetainUntil", "retain_until"),
+        ],
+    }
+}
+
+/// Lists the entity set, `None` if it is not available on the tenant.
+async fn list_tenant_content(
+    content_type: TenantContentType,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Option<Vec<Value>>, Error> {
+    let api_content_url = format!(
+        "{api}/{api_name}",
+        api = config.tenant()?.api_url(),
+        api_name = content_type.api_name()
+    );
+    let resp = http::send(
+        config,
+        Some(authorization),
+        client
+            .get(&api_content_url)
+            .header("Accept", "application/json"),
+    )
+    .await?;
+
+    let resp_code = resp.status();
+    let body_text = resp.text().await?;
+
+    //e.g. JMS queues without a message broker
+    if resp_code == reqwest::StatusCode::NOT_FOUND {
+        println!(
+            "Tenant content is not available on the tenant, skipping: {}",
+            content_type.api_name()
+        );
+        return Ok(None);
+    }
+
+    if !resp_code.is_success() {
+        return Err(Error::Api {
+            url: api_content_url,
+            status: resp_code.as_u16(),
+            body: body_text,
+        });
+    }
+
+    let resp_obj: Value =
+        serde_json::from_str(&body_text).map_err(|e| unexpected_response(&api_content_url, e))?;
+    match resp_obj.pointer("/d/results") {
+        Some(Value::Array(results)) => Ok(Some(results.clone())),
+        _ => Err(Error::UnexpectedResponse {
+            url: api_content_url,
+            message: "no d.results".to_string(),
+        }),
+    }
+}
+
+/// Keeps the exported properties only, OData dates as RFC 3339.
+fn select_properties(content_type: TenantContentType, entry: &Value) -> Value {
+    let mut selected = Map::new();
+    for (api_name, local_name) in exported_properties(content_type) {
+        let value = match entry.get(api_name) {
+            Some(Value::String(s)) => Value::String(odata_date(s)),
+            Some(v) => v.clone(),
+            None => continue,
+        };
+        selected.insert(local_name.to_string(), value);
+    }
+    Value::Object(selected)
+}
+
+/// Certificate file name per keystore entry with `Alias` and `Hexalias`. Aliases that map
+/// to the same file name, also ignoring case, get their unique hex alias appended.
+fn certificate_file_names(entries: &[Value]) -> Vec<(String, String)> {
+    let aliases: Vec<(&str, &str)> = entries
+        .iter()
+        .filter_map(|entry| {
+            match (
+                entry.get("Alias").and_then(|v| v.as_str()),
+                entry.get("Hexalias").and_then(|v| v.as_str()),
+            ) {
+                (Some(alias), Some(hexalias)) => Some((alias, hexalias)),
+                _ => None,
+            }
+        })
+        .collect();
+
+    let mut name_count: HashMap<String, usize> = HashMap::new();
+    for (alias, _) in &aliases {
+        *name_count
+            .entry(safe_file_name(alias).to_lowercase())
+            .or_default() += 1;
+    }
+
+    aliases
+        .into_iter()
+        .map(|(alias, hexalias)| {
+            let name = safe_file_name(alias);
+            let file_name = if name_count[&name.to_lowercase()] > 1 {
+                format!("{}_{}.cer", name, safe_file_name(hexalias))
+            } else {
+                format!("{}.cer", name)
+            };
+            (hexalias.to_string(), file_name)
+        })
+        .collect()
+}
+
+/// Writes the public certificate of each keystore entry, entries without one are skipped.
+async fn write_certificates(
+    entries: &[Value],
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &dyn Storage,
+) -> Result<(), Error> {
+    let certificate_dir = format!("{}/{}", TENANT_CONTENT_DIR_NAME, CERTIFICATE_DIR_NAME);
+    storage.remove_tree(&certificate_dir)?;
+
+    for (hexalias, file_name) in certificate_file_names(entries) {
+        let api_certificate_url = format!(
+            "{api}/CertificateResources('{hexalias}')/$value",
+            api = config.tenant()?.api_url(),
+            hexalias = hexalias
+        );
+        let resp = http::send(
+            config,
+            Some(authorization),
+            client.get(&api_certificate_url),
+        )
+        .await?;
+
+        let resp_code = resp.status();
+        //secret keys have no certificate
+        if resp_code == reqwest::StatusCode::NOT_FOUND {
+            continue;
+        }
+        if !resp_code.is_success() {
+            return Err(Error::Api {
+                url: api_certificate_url,
+                status: resp_code.as_u16(),
+                body: resp.text().await?,
+            });
+        }
+        storage.put_file(
+            &format!("{}/{}", certificate_dir, file_name),
+            &resp.bytes().await?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the selected `tenant_content` into the `tenant` folder, one JSON file per
+/// content type with entries sorted by name, and the keystore certificates.
+pub async fn write_tenant_content(
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &dyn Storage,
+) -> Result<(), Error> {
+    for content_type in config.tenant_content.iter().copied() {
+        println!("Fetching tenant content: {}", content_type.api_name());
+        let entries = match list_tenant_content(content_type, config, client, authorization).await?
+        {
+            Some(entries) => entries,
+            None => continue,
+        };
+
+        let mut selected: Vec<Value> = entries
+            .iter()
+            .map(|e| select_properties(content_type, e))
+            .collect();
+        //by name first, the whole entry makes the order deterministic for equal names
+        let name_property = exported_properties(content_type)[0].1;
+        selected.sort_by_cached_key(|e| {
+            (
+                e.get(name_property)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                e.to_string(),
+            )
+        });
+
+        storage.put_file(
+            &format!(
+                "{}/{}.json",
+                TENANT_CONTENT_DIR_NAME,
+                content_type.file_name()
+            ),
+            (serde_json::to_string_pretty(&selected)? + "\n").as_bytes(),
+        )?;
+
+        if content_type == TenantContentType::Keystore {
+            write_certificates(&entries, config, client, authorization, storage).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::test_util;
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const CONTENT_TYPES: [TenantContentType; 6] = [
+        TenantContentType::Keystore,
+        TenantContentType::UserCredentials,
+        TenantContentType::OAuth2Credentials,
+        TenantContentType::NumberRanges,
+        TenantContentType::JmsQueues,
+        TenantContentType::Variables,
+    ];
+
+    #[test]
+    fn only_allowlisted_properties_are_written() {
+        for content_type in CONTENT_TYPES {
+            let mut entry = Map::new();
+            for (api_name, _) in exported_properties(content_type) {
+                entry.insert(api_name.to_string(), json!("value"));
+            }
+            for secret in [
+                "Password",
+                "ClientSecret",
+                "SecurityArtifactDescriptor",
+                "PrivateKey",
+                "Token",
+                "__metadata",
+            ] {
+                entry.insert(secret.to_string(), json!("secret"));
+            }
+
+            let selected = select_properties(content_type, &Value::Object(entry));
+            let selected = selected.as_object().unwrap();
+            let local_names: Vec<&str> = exported_properties(content_type)
+                .iter()
+                .map(|(_, local_name)| *local_name)
+                .collect();
+            assert_eq!(selected.len(), local_names.len(), "{:?}", content_type);
+            for (key, value) in selected {
+                assert!(local_names.contains(&key.as_str()), "{}", key);
+                assert_eq!(value, "value");
+            }
+        }
+    }
+
+    #[test]
+    fn dates_are_rfc_3339() {
+        let selected = select_properties(
+            TenantContentType::Variables,
+            &json!({ "VariableName": "v1", "UpdatedAt": "/Date(1612176741000)/" }),
+        );
+        assert_eq!(
+            selected,
+            json!({ "variable_name": "v1", "updated_at": "2021-02-01T10:52:21+00:00" })
+        );
+    }
+
+    #[test]
+    fn colliding_aliases_get_the_hex_alias_appended() {
+        let entries = json!([
+            { "Alias": "sap:key", "Hexalias": "7361703a6b6579" },
+            { "Alias": "sap/key", "Hexalias": "7361702f6b6579" },
+            { "Alias": "SAP_KEY", "Hexalias": "5341505f4b4559" },
+            { "Alias": "other", "Hexalias": "6f74686572" },
+            { "Alias": "no_hexalias" }
+        ]);
+        let file_names: Vec<String> = certificate_file_names(entries.as_array().unwrap())
+            .into_iter()
+            .map(|(_, file_name)| file_name)
+            .collect();
+        assert_eq!(
+            file_names,
+            [
+                "sap_key_7361703a6b6579.cer",
+                "sap_key_7361702f6b6579.cer",
+                "SAP_KEY_5341505f4b4559.cer",
+                "other.cer"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keystore_and_certificates_are_written() {
+        let server = MockServer::start().await;
+        test_util::mount_results(
+            &server,
+            "/KeystoreEntries",
+            json!([
+                { "Alias": "b", "Hexalias": "62", "Type": "Certificate" },
+                { "Alias": "a:1", "Hexalias": "613a31", "Type": "Certificate" },
+                { "Alias": "a/1", "Hexalias": "612f31", "Type": "Certificate" },
+                { "Alias": "secret", "Hexalias": "736563726574", "Type": "SecretKey",
+                  "Password": "secret" }
+            ]),
+        )
+        .await;
+        for (hexalias, certificate) in [("62", "B"), ("613a31", "A1"), ("612f31", "A2")] {
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "/api/v1/CertificateResources('{}')/$value",
+                    hexalias
+                )))
+                .respond_with(ResponseTemplate::new(200).set_body_string(certificate))
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .and(path("/api/v1/CertificateResources('736563726574')/$value"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        test_util::mount_results(&server, "/Variables", json!([])).await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/Queues"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let config = test_util::config(json!({
+            "tenant": test_util::mock_tenant(&server, test_util::s_user()),
+            "http": { "max_attempts": 1 },
+            "tenant_content": ["keystore", "jms_queues", "variables"]
+        }));
+        let client = http::client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+        let storage = MemoryStorage::new();
+        storage
+            .put_file("tenant/certificates/deleted.cer", b"old")
+            .unwrap();
+
+        write_tenant_content(&config, &client, &authorization, &storage)
+            .await
+            .unwrap();
+
+        let files = storage.files().unwrap();
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                "tenant/certificates/a_1_612f31.cer",
+                "tenant/certificates/a_1_613a31.cer",
+                "tenant/certificates/b.cer",
+                "tenant/keystore.json",
+                "tenant/variables.json"
+            ]
+        );
+        assert_eq!(files["tenant/certificates/a_1_613a31.cer"], b"A1");
+        assert_eq!(files["tenant/certificates/a_1_612f31.cer"], b"A2");
+        let keystore: Value = serde_json::from_slice(&files["tenant/keystore.json"]).unwrap();
+        assert_eq!(
+            keystore,
+            json!([
+                { "alias": "a/1", "hexalias": "612f31", "type": "Certificate" },
+                { "alias": "a:1", "hexalias": "613a31", "type": "Certificate" },
+                { "alias": "b", "hexalias": "62", "type": "Certificate" },
+                { "alias": "secret", "hexalias": "736563726574", "type": "SecretKey" }
+            ])
+        );
+        assert_eq!(files["tenant/variables.json"], b"[]\n");
+    }
+
+    #[tokio::test]
+    async fn failed_list_is_an_api_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/UserCredentials"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+        let config = test_util::config(json!({
+            "tenant": test_util::mock_tenant(&server, test_util::s_user()),
+            "http": { "max_attempts": 1 },
+            "tenant_content": ["user_credentials"]
+        }));
+        let client = http::client_builder(&config).unwrap().build().unwrap();
+        let authorization = Authorization::new(&config, &client, "secret").unwrap();
+
+        let result =
+            write_tenant_content(&config, &client, &authorization, &MemoryStorage::new()).await;
+        assert!(matches!(result, Err(Error::Api { status: 403, .. })));
+    }
+
+    #[tokio::test]
+    async fn malformed_list_is_an_unexpected_response() {
+        for body in [
+            json!({ "d": {} }).to_string(),
+            "<html>Login</html>".to_string(),
+        ] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/api/v1/NumberRanges"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(body))
+                .mount(&server)
+                .await;
+            let config = test_util::config(json!({
+                "tenant": test_util::mock_tenant(&server, test_util::s_user()),
+                "http": { "max_attempts": 1 },
+                "tenant_content": ["number_ranges"]
+            }));
+            let client = http::client_builder(&config).unwrap().build().unwrap();
+            let authorization = Authorization::new(&config, &client, "secret").unwrap();
+
+            let result =
+                write_tenant_content(&config, &client, &authorization, &MemoryStorage::new()).await;
+            assert!(matches!(
+                result,
+                Err(Error::UnexpectedResponse { url, .. }) if url.ends_with("/NumberRanges")
+            ));
+        }
+    }
+}
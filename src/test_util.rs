@@ -0,0 +1,83 @@
+//! Config fixtures and mock tenant responses shared by the unit tests.
+
+use crate::config::{Config, CONFIG_VERSION};
+use serde_json::{json, Value};
+use std::io::Write;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+pub fn s_user() -> Value {
+    json!({ "s_user": { "username": "S1" } })
+}
+
+/// Tenant with an S-user credential at `tenant.example.com`.
+pub fn tenant() -> Value {
+    json!({
+        "management_host": "tenant.example.com",
+        "credential": s_user()
+    })
+}
+
+/// Tenant with the given credential that sends its API requests to the mock server.
+pub fn mock_tenant(server: &MockServer, credential: Value) -> Value {
+    json!({
+        "management_host": "tenant.example.com",
+        "api_base_url": format!("{}/api/v1", server.uri()),
+        "credential": credential
+    })
+}
+
+/// Config with a single tenant and no filter rules. The top-level keys of `overrides`
+/// replace the defaults, a `tenants` map replaces the single `tenant`.
+pub fn config(overrides: Value) -> Config {
+    let mut config = json!({
+        "cpisync": CONFIG_VERSION,
+        "tenant": tenant(),
+        "packages": { "filter_rules": [] }
+    });
+    let base = config.as_object_mut().unwrap();
+    if overrides.get("tenants").is_some() {
+        base.remove("tenant");
+    }
+    for (key, value) in overrides.as_object().unwrap() {
+        base.insert(key.clone(), value.clone());
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+/// Config with the given `packages` section.
+pub fn packages_config(packages: Value) -> Config {
+    config(json!({ "packages": packages }))
+}
+
+/// Mock tenant that accepts the API check of every run.
+pub async fn mock_server() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    server
+}
+
+/// Answers GET requests of the API path, e.g. `/IntegrationPackages`, with an OData collection.
+pub async fn mount_results(server: &MockServer, api_path: &str, results: Value) {
+    Mock::given(method("GET"))
+        .and(path(format!("/api/v1{}", api_path)))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "d": { "results": results } })),
+        )
+        .mount(server)
+        .await;
+}
+
+/// ZIP with the given files, e.g. an artifact download.
+pub fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, content) in files {
+        zip.start_file(*name, Default::default()).unwrap();
+        zip.write_all(content.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
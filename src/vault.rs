@@ -0,0 +1,221 @@
+use crate::errors::Error;
+use crate::write_private_file;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+const VAULT_VERSION: u32 = 1;
+// encrypted with the vault key to detect a wrong passphrase
+const CHECK_NAME: &str = "check";
+const CHECK_VALUE: &str = "cpisync-vault";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KdfParams {
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+// entry names are readable, only the secrets are encrypted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct VaultFile {
+    version: u32,
+    kdf: KdfParams,
+    check: Sealed,
+    entries: BTreeMap<String, Sealed>,
+}
+
+/// Local credential store, secrets are encrypted with a key derived from the master passphrase.
+pub struct Vault {
+    file: VaultFile,
+    cipher: ChaCha20Poly1305,
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    base64::decode(value).map_err(|e| Error::Vault(format!("Invalid vault file: {}", e)))
+}
+
+fn derive_cipher(passphrase: &str, kdf: &KdfParams) -> Result<ChaCha20Poly1305, Error> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| Error::Vault(e.to_string()))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &decode(&kdf.salt)?, &mut key)
+        .map_err(|e| Error::Vault(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// The entry name is authenticated too, so secrets can't be swapped between entries.
+fn seal(cipher: &ChaCha20Poly1305, name: &str, secret: &str) -> Result<Sealed, Error> {
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: secret.as_bytes(),
+                aad: name.as_bytes(),
+            },
+        )
+        .map_err(|_| Error::Vault("Encryption failed".to_string()))?;
+    Ok(Sealed {
+        nonce: base64::encode(nonce),
+        ciphertext: base64::encode(ciphertext),
+    })
+}
+
+fn open(cipher: &ChaCha20Poly1305, name: &str, sealed: &Sealed) -> Result<String, Error> {
+    let nonce = decode(&sealed.nonce)?;
+    if nonce.len() != 12 {
+        return Err(Error::Vault("Invalid vault file: nonce".to_string()));
+    }
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &decode(&sealed.ciphertext)?,
+                aad: name.as_bytes(),
+            },
+        )
+        .map_err(|_| Error::Vault("Wrong vault passphrase".to_string()))?;
+    String::from_utf8(plaintext).map_err(|e| Error::Vault(e.to_string()))
+}
+
+/// Entry names without opening the vault, empty if the vault doesn't exist.
+pub fn entry_names(vault_path: &Path) -> Result<Vec<String>, Error> {
+    if !vault_path.is_file() {
+        return Ok(Vec::new());
+    }
+    let file: VaultFile = serde_json::from_str(&fs::read_to_string(vault_path)?)?;
+    Ok(file.entries.keys().cloned().collect())
+}
+
+impl Vault {
+    /// Opens the vault with the passphrase, or creates an empty one if the file doesn't exist.
+    pub fn open(vault_path: &Path, passphrase: &str) -> Result<Vault, Error> {
+        if !vault_path.is_file() {
+            let mut salt = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut salt);
+            let kdf = KdfParams {
+                salt: base64::encode(salt),
+                m_cost: Params::DEFAULT_M_COST,
+                t_cost: Params::DEFAULT_T_COST,
+                p_cost: Params::DEFAULT_P_COST,
+            };
+            let cipher = derive_cipher(passphrase, &kdf)?;
+            let check = seal(&cipher, CHECK_NAME, CHECK_VALUE)?;
+            return Ok(Vault {
+                file: VaultFile {
+                    version: VAULT_VERSION,
+                    kdf,
+                    check,
+                    entries: BTreeMap::new(),
+                },
+                cipher,
+            });
+        }
+
+        let file: VaultFile = serde_json::from_str(&fs::read_to_string(vault_path)?)?;
+        if file.version != VAULT_VERSION {
+            return Err(Error::Vault(format!(
+                "Unsupported vault version: {}",
+                file.version
+            )));
+        }
+        let cipher = derive_cipher(passphrase, &file.kdf)?;
+        open(&cipher, CHECK_NAME, &file.check)?;
+        Ok(Vault { file, cipher })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<String>, Error> {
+        match self.file.entries.get(name) {
+            Some(sealed) => Ok(Some(open(&self.cipher, name, sealed)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&mut self, name: &str, secret: &str) -> Result<(), Error> {
+        let sealed = seal(&self.cipher, name, secret)?;
+        self.file.entries.insert(name.to_string(), sealed);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.file.entries.remove(name).is_some()
+    }
+
+    pub fn save(&self, vault_path: &Path) -> Result<(), Error> {
+        write_private_file(
+            vault_path,
+            (serde_json::to_string_pretty(&self.file)? + "\n").as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse";
+
+    #[test]
+    fn seal_open_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_path = dir.path().join("vault.json");
+
+        let mut vault = Vault::open(&vault_path, PASSPHRASE).unwrap();
+        vault.insert("DEV", "s3cret").unwrap();
+        vault.save(&vault_path).unwrap();
+
+        let vault = Vault::open(&vault_path, PASSPHRASE).unwrap();
+        assert_eq!(vault.get("DEV").unwrap().as_deref(), Some("s3cret"));
+        assert_eq!(vault.get("PROD").unwrap(), None);
+        assert_eq!(entry_names(&vault_path).unwrap(), vec!["DEV".to_string()]);
+
+        //the secret is not stored in clear text
+        assert!(!fs::read_to_string(&vault_path).unwrap().contains("s3cret"));
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_path = dir.path().join("vault.json");
+
+        let mut vault = Vault::open(&vault_path, PASSPHRASE).unwrap();
+        vault.insert("DEV", "s3cret").unwrap();
+        vault.save(&vault_path).unwrap();
+
+        match Vault::open(&vault_path, "wrong") {
+            Err(Error::Vault(message)) => assert_eq!(message, "Wrong vault passphrase"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn renamed_entry_fails_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault_path = dir.path().join("vault.json");
+
+        let mut vault = Vault::open(&vault_path, PASSPHRASE).unwrap();
+        vault.insert("DEV", "s3cret").unwrap();
+        let sealed = vault.file.entries.remove("DEV").unwrap();
+        vault.file.entries.insert("PROD".to_string(), sealed);
+        vault.save(&vault_path).unwrap();
+
+        let vault = Vault::open(&vault_path, PASSPHRASE).unwrap();
+        assert!(matches!(vault.get("PROD"), Err(Error::Vault(_))));
+    }
+}
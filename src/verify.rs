@@ -0,0 +1,646 @@
+use crate::auth::Authorization;
+use crate::client::{Artifact, Package};
+use crate::config::*;
+use crate::configurations::write_configurations;
+use crate::credentials::CredentialInput;
+use crate::errors::Error;
+use crate::http;
+use crate::package::{package_files, write_package_metadata};
+use crate::report::{ArtifactReport, ArtifactStatus};
+use crate::runtime::write_runtime_status;
+use crate::storage::{FileSystemStorage, MemoryStorage, Storage};
+use crate::{
+    artifact_path, extract_entries, fetch_artifact, get_all_packages, get_authorization,
+    get_data_dir, list_package_artifacts, run_for_tenants, select_packages, try_run_pooled,
+};
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::Cursor,
+    ops::Deref,
+    path::PathBuf,
+};
+
+/// Differences between the local files and the tenant, paths are relative to `local_dir`.
+#[derive(Serialize, Debug, Default)]
+pub struct VerifyReport {
+    pub tenant: Option<String>,
+    pub management_host: String,
+    pub local_dir: PathBuf,
+    pub selected_packages: Vec<String>,
+    pub verified_artifacts: usize,
+    // content differs from the tenant
+    pub mismatched: Vec<String>,
+    // on the tenant, not on disk
+    pub missing: Vec<String>,
+    // on disk, not on the tenant
+    pub extra: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct ArtifactVerification {
+    mismatched: Vec<String>,
+    missing: Vec<String>,
+    extra: Vec<String>,
+    // local files of the artifact, the rest of the package folder is checked for extra files
+    local_files: Vec<String>,
+}
+
+/// Extracted and normalized files of an artifact ZIP.
+fn zip_entries(config: &Config, content: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+    Ok(extract_entries(config, Cursor::new(content))?
+        .into_iter()
+        .collect())
+}
+
+/// Compares a single artifact of the tenant with the local files, same rules as `write_artifact`.
+async fn verify_artifact(
+    package_id: &str,
+    artifact_id: &str,
+    artifact_type: ArtifactType,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    storage: &FileSystemStorage,
+) -> Result<ArtifactVerification, Error> {
+    let artifact_path = artifact_path(package_id, artifact_id, artifact_type, config);
+    let respbytes =
+        fetch_artifact(artifact_id, artifact_type, config, client, authorization).await?;
+
+    let mut verification = ArtifactVerification {
+        local_files: storage.list(&artifact_path)?,
+        ..Default::default()
+    };
+
+    match config.packages.zip_extraction {
+        //zip timestamps change on every download, the extracted files are compared
+        ZipExtraction::Disabled => match storage.get_file(&artifact_path)? {
+            None => verification.missing.push(artifact_path),
+            Some(local_content) => {
+                //an unreadable local ZIP is a mismatch, not an error
+                let tenant_entries = zip_entries(config, respbytes.deref())?;
+                match zip_entries(config, &local_content) {
+                    Ok(local_entries) if local_entries == tenant_entries => {}
+                    _ => verification.mismatched.push(artifact_path),
+                }
+            }
+        },
+        ZipExtraction::Enabled => {
+            let tenant_files: BTreeMap<String, Vec<u8>> = zip_entries(config, respbytes.deref())?
+                .into_iter()
+                .map(|(path, content)| (format!("{}/{}", artifact_path, path), content))
+                .collect();
+            for (path, content) in tenant_files.iter() {
+                match storage.get_file(path)? {
+                    None => verification.missing.push(path.clone()),
+                    Some(local_content) if &local_content != content => {
+                        verification.mismatched.push(path.clone())
+                    }
+                    Some(_) => {}
+                }
+            }
+            for path in verification.local_files.iter() {
+                if !tenant_files.contains_key(path) {
+                    verification.extra.push(path.clone());
+                }
+            }
+        }
+    }
+    Ok(verification)
+}
+
+/// Artifacts of the selected types in the package.
+async fn list_verified_artifacts(
+    package_id: &str,
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+) -> Result<Vec<(String, ArtifactType, Artifact)>, Error> {
+    let mut artifacts = Vec::new();
+    for artifact_type in config.packages.artifact_types.iter() {
+        for artifact in
+            list_package_artifacts(package_id, *artifact_type, config, client, authorization)
+                .await?
+        {
+            artifacts.push((package_id.to_string(), *artifact_type, artifact));
+        }
+    }
+    Ok(artifacts)
+}
+
+/// Renders the files a sync writes next to the artifacts in memory, with the writers of the sync:
+/// `package.json`, `runtime-status.json` and the iFlow configurations, as configured.
+async fn render_package_files(
+    config: &Config,
+    client: &reqwest::Client,
+    authorization: &Authorization,
+    package_list: &[String],
+    api_package_list: &[Package],
+    artifacts: &[(String, ArtifactType, Artifact)],
+) -> Result<MemoryStorage, Error> {
+    let rendered = MemoryStorage::new();
+    let artifact_reports: Vec<ArtifactReport> = artifacts
+        .iter()
+        .map(|(package_id, artifact_type, artifact)| ArtifactReport {
+            package_id: package_id.clone(),
+            artifact_id: artifact.id.clone(),
+            name: artifact.name.clone(),
+            artifact_type: *artifact_type,
+            version: artifact.version.clone(),
+            status: ArtifactStatus::Downloaded,
+            http_status: None,
+            error: None,
+            body_excerpt: None,
+            bytes: None,
+            duration_ms: 0,
+        })
+        .collect();
+
+    write_package_metadata(&rendered, package_list, api_package_list, &artifact_reports)?;
+
+    if matches!(config.packages.runtime_status, RuntimeStatus::Enabled) {
+        write_runtime_status(
+            config,
+            client,
+            authorization,
+            &rendered,
+            package_list,
+            &artifact_reports,
+        )
+        .await?;
+    }
+
+    try_run_pooled(
+        artifacts
+            .iter()
+            .filter(|(_, artifact_type, _)| *artifact_type == ArtifactType::IntegrationFlow)
+            .map(|(package_id, _, artifact)| {
+                write_configurations(
+                    package_id,
+                    &artifact.id,
+                    config,
+                    &rendered,
+                    client,
+                    authorization,
+                    None,
+                )
+            }),
+        config.packages.download_worker_count,
+    )
+    .await?;
+    Ok(rendered)
+}
+
+/// Downloads the artifacts of the selected packages in memory and compares them with
+/// the files under `local_dir`, nothing is written.
+pub async fn verify_with_config_and_password(
+    config: &Config,
+    config_path: &str,
+    password: &str,
+) -> Result<VerifyReport, Error> {
+    if config.output.format != OutputFormat::Directory {
+        return Err(Error::Config(
+            "verify compares the extracted files, `output.format` must be directory".to_string(),
+        ));
+    }
+
+    let client = http::client_builder(config)?.build()?;
+
+    let authorization = get_authorization(config, &client, password).await?;
+
+    let data_dir = get_data_dir(config, config_path, false).await?;
+    let storage = FileSystemStorage::new(&data_dir);
+
+    let api_package_list = get_all_packages(config, &client, &authorization).await?;
+
+    let mut package_list = select_packages(config, &api_package_list)?;
+    package_list.sort();
+
+    println!("Verifying These Packages:");
+    println!("{:?}", &package_list);
+
+    let artifacts: Vec<(String, ArtifactType, Artifact)> = try_run_pooled(
+        package_list
+            .iter()
+            .map(|package_id| list_verified_artifacts(package_id, config, &client, &authorization)),
+        config.packages.download_worker_count,
+    )
+    .await?
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let verifications = try_run_pooled(
+        artifacts
+            .iter()
+            .map(|(package_id, artifact_type, artifact)| {
+                verify_artifact(
+                    package_id,
+                    &artifact.id,
+                    *artifact_type,
+                    config,
+                    &client,
+                    &authorization,
+                    &storage,
+                )
+            }),
+        config.packages.download_worker_count,
+    )
+    .await?;
+
+    let mut report = VerifyReport {
+        management_host: config.tenant()?.management_host.clone(),
+        local_dir: data_dir,
+        verified_artifacts: artifacts.len(),
+        ..Default::default()
+    };
+
+    //files written by a sync next to the artifacts, compared with the rendered tenant state
+    let rendered = render_package_files(
+        config,
+        &client,
+        &authorization,
+        &package_list,
+        &api_package_list,
+        &artifacts,
+    )
+    .await?;
+    let mut known_files = BTreeSet::new();
+    for package_id in package_list.iter() {
+        let integration_flow_ids: Vec<&str> = artifacts
+            .iter()
+            .filter(|(p, artifact_type, _)| {
+                p == package_id && *artifact_type == ArtifactType::IntegrationFlow
+            })
+            .map(|(_, _, artifact)| artifact.id.as_str())
+            .collect();
+        for path in package_files(package_id, &integration_flow_ids, config) {
+            match (rendered.get_file(&path)?, storage.get_file(&path)?) {
+                (_, None) => report.missing.push(path.clone()),
+                (Some(tenant_content), Some(local_content)) if tenant_content != local_content => {
+                    report.mismatched.push(path.clone())
+                }
+                _ => {}
+            }
+            known_files.insert(path);
+        }
+    }
+    for verification in verifications {
+        known_files.extend(verification.local_files);
+        report.mismatched.extend(verification.mismatched);
+        report.missing.extend(verification.missing);
+        report.extra.extend(verification.extra);
+    }
+
+    //artifacts deleted on the tenant, or other files in the package folders
+    for package_id in package_list.iter() {
+        for path in storage.list(package_id)? {
+            if !known_files.contains(&path) {
+                report.extra.push(path);
+            }
+        }
+    }
+
+    report.mismatched.sort();
+    report.missing.sort();
+    report.extra.sort();
+    report.selected_packages = package_list;
+    Ok(report)
+}
+
+fn print_verify_report(report: &VerifyReport) {
+    match &report.tenant {
+        Some(name) => println!(
+            "Verification for Tenant: {} ({})",
+            name, &report.management_host
+        ),
+        None => println!("Verification for Tenant: {}", &report.management_host),
+    }
+    println!("Local directory: {}", report.local_dir.display());
+
+    for path in report.mismatched.iter() {
+        println!("  mismatched {}", path);
+    }
+    for path in report.missing.iter() {
+        println!("  missing    {}", path);
+    }
+    for path in report.extra.iter() {
+        println!("  extra      {}", path);
+    }
+    println!(
+        "Verified artifacts: {}, mismatched files: {}, missing files: {}, extra files: {}",
+        report.verified_artifacts,
+        report.mismatched.len(),
+        report.missing.len(),
+        report.extra.len()
+    );
+}
+
+/// Verifies the selected tenants. If any local file differs from the tenant,
+/// fails with `Error::VerifyFailed` naming the tenants after the report is written.
+pub async fn verify_with_tenants(
+    config: &Config,
+    config_path: &str,
+    credentials: &CredentialInput,
+    selection: &TenantSelection,
+    output: Option<&str>,
+) -> Result<(), Error> {
+    let outputs = run_for_tenants(
+        config,
+        selection,
+        credentials,
+        |tenant_config, password| async move {
+            verify_with_config_and_password(&tenant_config, config_path, &password).await
+        },
+    )
+    .await?;
+
+    let mut reports = Vec::new();
+    for (tenant_name, mut report) in outputs {
+        report.tenant = tenant_name;
+        print_verify_report(&report);
+        reports.push(report);
+    }
+
+    if let Some(output) = output {
+        fs::write(output, serde_json::to_string_pretty(&reports)? + "\n")?;
+        println!("Verification report written to: {}", output);
+    }
+
+    let failed: Vec<String> = reports
+        .iter()
+        .filter(|r| !r.is_clean())
+        .map(|r| {
+            r.tenant
+                .clone()
+                .unwrap_or_else(|| r.management_host.clone())
+        })
+        .collect();
+    if !failed.is_empty() {
+        return Err(Error::VerifyFailed(failed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use serde_json::json;
+    use std::path::Path;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn tenant() -> MockServer {
+        let server = test_util::mock_server().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages",
+            json!([{ "Id": "Pkg1", "Name": "Pkg1" }]),
+        )
+        .await;
+        let flows = [
+            ("Same", "<same/>"),
+            ("Changed", "<new/>"),
+            ("Missing", "<x/>"),
+        ];
+        let flow_list: Vec<serde_json::Value> = flows
+            .iter()
+            .map(|(id, _)| json!({ "Id": id, "Name": id, "Version": "1.0.0" }))
+            .collect();
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts",
+            json!(flow_list),
+        )
+        .await;
+        for (artifact_id, content) in flows {
+            Mock::given(method("GET"))
+                .and(path(format!(
+                    "/api/v1/IntegrationDesigntimeArtifacts(Id='{}',Version='Active')/$value",
+                    artifact_id
+                )))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_bytes(test_util::zip(&[("flow.iflw", content)])),
+                )
+                .mount(&server)
+                .await;
+        }
+        server
+    }
+
+    fn tenant_config(server: &MockServer, data_dir: &Path) -> Config {
+        test_util::config(json!({
+            "tenant": test_util::mock_tenant(server, test_util::s_user()),
+            "packages": {
+                "filter_rules": [{ "type": "single", "id": "Pkg1" }],
+                "local_dir": data_dir.to_string_lossy(),
+                "artifact_types": ["IntegrationDesigntimeArtifacts"]
+            },
+            "http": { "max_attempts": 1 }
+        }))
+    }
+
+    fn write(data_dir: &Path, path: &str, content: &str) {
+        let path = data_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Local files of an earlier sync, changed since on the tenant and on disk.
+    fn local_files(data_dir: &Path) {
+        write(data_dir, "Pkg1/package.json", "{}");
+        write(data_dir, "Pkg1/IntegrationFlows/Same/flow.iflw", "<same/>");
+        write(data_dir, "Pkg1/IntegrationFlows/Same/notes.txt", "local");
+        write(
+            data_dir,
+            "Pkg1/IntegrationFlows/Changed/flow.iflw",
+            "<old/>",
+        );
+        write(data_dir, "Pkg1/IntegrationFlows/Removed/flow.iflw", "<x/>");
+    }
+
+    #[tokio::test]
+    async fn reports_mismatched_missing_and_extra_files() {
+        let server = tenant().await;
+        let dir = tempfile::tempdir().unwrap();
+        local_files(dir.path());
+
+        let config = tenant_config(&server, dir.path());
+        let config_path = dir.path().join("cpi-sync.json");
+        let report =
+            verify_with_config_and_password(&config, &config_path.to_string_lossy(), "secret")
+                .await
+                .unwrap();
+
+        assert_eq!(report.verified_artifacts, 3);
+        assert_eq!(
+            report.mismatched,
+            vec![
+                "Pkg1/IntegrationFlows/Changed/flow.iflw",
+                "Pkg1/package.json"
+            ]
+        );
+        assert_eq!(
+            report.missing,
+            vec!["Pkg1/IntegrationFlows/Missing/flow.iflw"]
+        );
+        assert_eq!(
+            report.extra,
+            vec![
+                "Pkg1/IntegrationFlows/Removed/flow.iflw",
+                "Pkg1/IntegrationFlows/Same/notes.txt"
+            ]
+        );
+        assert!(!report.is_clean());
+    }
+
+    #[tokio::test]
+    async fn differences_fail_with_the_tenant_after_the_report() {
+        let server = tenant().await;
+        let dir = tempfile::tempdir().unwrap();
+        local_files(dir.path());
+        let output = dir.path().join("verify.json");
+
+        let result = verify_with_tenants(
+            &tenant_config(&server, dir.path()),
+            &dir.path().join("cpi-sync.json").to_string_lossy(),
+            &CredentialInput::new(true, Some("secret".to_string()), None),
+            &TenantSelection::Default,
+            Some(&output.to_string_lossy()),
+        )
+        .await;
+        match result {
+            Err(Error::VerifyFailed(tenants)) => assert_eq!(tenants, vec!["tenant.example.com"]),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let reports: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(reports[0]["verified_artifacts"], 3);
+        assert_eq!(
+            reports[0]["missing"][0],
+            "Pkg1/IntegrationFlows/Missing/flow.iflw"
+        );
+    }
+
+    /// Tenant with a single iFlow whose `url` parameter has the given value.
+    async fn configured_tenant(url: &str) -> MockServer {
+        let server = test_util::mock_server().await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages",
+            json!([{ "Id": "Pkg1", "Name": "Pkg1" }]),
+        )
+        .await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationPackages('Pkg1')/IntegrationDesigntimeArtifacts",
+            json!([{ "Id": "Flow1", "Name": "Flow1", "Version": "1.0.0" }]),
+        )
+        .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/api/v1/IntegrationDesigntimeArtifacts(Id='Flow1',Version='Active')/$value",
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_bytes(test_util::zip(&[("flow.iflw", "<x/>")])),
+            )
+            .mount(&server)
+            .await;
+        test_util::mount_results(
+            &server,
+            "/IntegrationDesigntimeArtifacts(Id='Flow1',Version='Active')/Configurations",
+            json!([{ "ParameterKey": "url", "ParameterValue": url, "DataType": "xsd:string" }]),
+        )
+        .await;
+        server
+    }
+
+    fn configured_config(server: &MockServer, data_dir: &Path) -> Config {
+        let mut config = tenant_config(server, data_dir);
+        config.packages.configuration_export = ConfigurationExport::Json;
+        config
+    }
+
+    #[tokio::test]
+    async fn changed_configuration_value_fails_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let synced = configured_tenant("https://a.example.com").await;
+        crate::sync_with_storage(
+            &configured_config(&synced, dir.path()),
+            "secret",
+            std::sync::Arc::new(FileSystemStorage::new(dir.path())),
+            false,
+        )
+        .await
+        .unwrap();
+
+        let config_path = dir
+            .path()
+            .join("cpi-sync.json")
+            .to_string_lossy()
+            .to_string();
+        let report = verify_with_config_and_password(
+            &configured_config(&synced, dir.path()),
+            &config_path,
+            "secret",
+        )
+        .await
+        .unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+
+        let changed = configured_tenant("https://b.example.com").await;
+        let result = verify_with_tenants(
+            &configured_config(&changed, dir.path()),
+            &config_path,
+            &CredentialInput::new(true, Some("secret".to_string()), None),
+            &TenantSelection::Default,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::VerifyFailed(_))));
+
+        let report = verify_with_config_and_password(
+            &configured_config(&changed, dir.path()),
+            &config_path,
+            "secret",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            report.mismatched,
+            vec!["Pkg1/IntegrationFlows/Flow1.configurations.json"]
+        );
+
+        fs::remove_file(dir.path().join("Pkg1/package.json")).unwrap();
+        let report = verify_with_config_and_password(
+            &configured_config(&synced, dir.path()),
+            &config_path,
+            "secret",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.missing, vec!["Pkg1/package.json"]);
+    }
+
+    #[tokio::test]
+    async fn archive_output_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_util::config(json!({}));
+        config.output.format = OutputFormat::Zip;
+        assert!(matches!(
+            verify_with_config_and_password(&config, &dir.path().join("c.json").to_string_lossy(), "secret").await,
+            Err(Error::Config(message)) if message.contains("output.format")
+        ));
+    }
+}